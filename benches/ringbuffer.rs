@@ -0,0 +1,164 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Benchmarks of `RingBuffer` throughput, moving data through the buffer with `put` and
+//! `get` in equal sized chunks, and of `BlockingRingBuffer` throughput with a producer
+//! and a consumer thread contending for it, and writing what it gets.  Run with
+//! `cargo bench`.
+
+#[macro_use] extern crate criterion;
+extern crate pipebuffer;
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{IoSlice, Write};
+use std::sync::Arc;
+use std::thread;
+use criterion::{BenchmarkId, Criterion, Throughput};
+use pipebuffer::blocking::BlockingRingBuffer;
+use pipebuffer::ringbuffer::RingBuffer;
+
+// The number of bytes moved through the buffer by each iteration of the contended benchmark
+const CONTENDED_TOTAL: usize = 64 * 1024 * 1024;
+
+/// Puts `input` into the buffer and gets it back out into `output`, looping as
+/// necessary to move the whole chunk.
+fn round_trip(buffer: &mut RingBuffer<u8>, input: &[u8], output: &mut [u8]) {
+    let mut put = 0;
+    let mut got = 0;
+    while got < output.len() {
+        if put < input.len() { put += buffer.put(&input[put..]); }
+        got += buffer.get(&mut output[got..put]);
+    }
+}
+
+/// Measures throughput for a range of buffer and chunk sizes where the capacity is an
+/// exact multiple of the chunk size, so operations never straddle the wrap.
+fn bench_aligned(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aligned");
+    for &capacity in &[64 * 1024, 1024 * 1024, 16 * 1024 * 1024] {
+        for &chunk in &[512, 4 * 1024, 64 * 1024] {
+            let mut buffer = RingBuffer::new(capacity);
+            let input  = vec![7u8; chunk];
+            let mut output = vec![0u8; chunk];
+            group.throughput(Throughput::Bytes(chunk as u64));
+            group.bench_function(BenchmarkId::new(format!("capacity={}", capacity), chunk), |b| {
+                b.iter(|| round_trip(&mut buffer, black_box(&input), black_box(&mut output)))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Measures throughput where the capacity is one more than a multiple of the chunk
+/// size, so that the chunks drift across the wrap point and regularly straddle it.
+fn bench_wrapping(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wrapping");
+    for &chunk in &[512, 4 * 1024, 64 * 1024] {
+        let mut buffer = RingBuffer::new(chunk * 4 + 1);
+        let input  = vec![7u8; chunk];
+        let mut output = vec![0u8; chunk];
+        group.throughput(Throughput::Bytes(chunk as u64));
+        group.bench_function(BenchmarkId::from_parameter(chunk), |b| {
+            b.iter(|| round_trip(&mut buffer, black_box(&input), black_box(&mut output)))
+        });
+    }
+    group.finish();
+}
+
+/// Moves `total` bytes from a producer thread to a consumer thread through a buffer of
+/// `capacity` bytes, each putting or getting `chunk` bytes at a time.  With `output`
+/// the consumer writes what it gets there, either copying it out of the buffer first,
+/// or if `direct` writing it straight from the buffer with `write_vectored()`.
+fn transfer(capacity: usize, chunk: usize, total: usize, output: Option<&mut File>, direct: bool) {
+    let buffer = Arc::new(BlockingRingBuffer::new(capacity));
+    let producer = {
+        let buffer = buffer.clone();
+        thread::spawn(move || {
+            let input = vec![7u8; chunk];
+            let mut sent = 0;
+            while sent < total {
+                buffer.put_blocking(black_box(&input));
+                sent += chunk;
+            }
+            buffer.close();
+        })
+    };
+
+    let mut staging = vec![0u8; chunk];
+    match output {
+        None                      => while buffer.get_blocking(black_box(&mut staging)) > 0 {},
+        Some(output) if direct    => while buffer.consume_blocking(|first, second| {
+            let first = &first[0..first.len().min(chunk)];
+            let second = &second[0..second.len().min(chunk - first.len())];
+            output.write_vectored(&[IoSlice::new(first), IoSlice::new(second)])
+        }).unwrap() > 0 {},
+        Some(output)              => loop {
+            let n = buffer.get_blocking(&mut staging);
+            if n == 0 { break; }
+            output.write_all(&staging[0..n]).unwrap();
+        }
+    }
+    producer.join().unwrap();
+}
+
+/// Measures throughput with the producer and consumer on their own threads, as when
+/// running, so that it reflects how much they contend for the buffer, for a buffer
+/// that mostly has room and one small enough that they are often waiting on each other.
+fn bench_contended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(CONTENDED_TOTAL as u64));
+    for &capacity in &[64 * 1024, 16 * 1024 * 1024] {
+        for &chunk in &[512, 4 * 1024, 64 * 1024] {
+            if chunk >= capacity { continue; }
+            group.bench_function(BenchmarkId::new(format!("capacity={}", capacity), chunk), |b| {
+                b.iter(|| transfer(capacity, chunk, CONTENDED_TOTAL, None, false))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Measures throughput as `bench_contended()` does, but with the consumer writing to
+/// `/dev/null`, comparing copying the data out of the buffer and then writing it, as
+/// with `--record-size`, with writing it straight from the buffer.  The capacity isn't a
+/// multiple of the chunk size, so that the data regularly wraps, and the vectored writes
+/// have to write from both sides of the wrap.
+fn bench_writing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("writing");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(CONTENDED_TOTAL as u64));
+    let mut output = File::create("/dev/null").unwrap();
+    for &chunk in &[4 * 1024, 64 * 1024] {
+        for &(name, direct) in &[("staged", false), ("direct", true)] {
+            group.bench_function(BenchmarkId::new(name, chunk), |b| {
+                b.iter(|| transfer(chunk * 4 + 1, chunk, CONTENDED_TOTAL, Some(&mut output), direct))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_aligned, bench_wrapping, bench_contended, bench_writing);
+criterion_main!(benches);
@@ -0,0 +1,139 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Adapting the size of the blocks the writer gets from the buffer, for
+//! `--adaptive-block`, to how readily the output accepts them.
+
+use std::cmp;
+use std::time::Duration;
+
+/// The smallest block the size adapts down to, unless the largest allowed is smaller.
+pub const MIN_BLOCK: usize = 4 * 1024;
+
+// How many full writes in a row the output must accept promptly before the block size is doubled
+const GROW_AFTER: usize = 4;
+
+// How many times the average time per byte a write must take to count as a latency spike
+const SPIKE_FACTOR: f64 = 4.0;
+
+// The weight each write is given in the running average of the time per byte
+const SMOOTHING: f64 = 0.2;
+
+/// Tracks how the output copes with the blocks written to it, and so the size of block
+/// to ask the buffer for next.  Starts at `MIN_BLOCK` and doubles, up to the maximum,
+/// whenever the output has accepted `GROW_AFTER` full blocks in a row in single writes
+/// and without a latency spike; halves, down to `MIN_BLOCK`, whenever a write goes
+/// partial or takes `SPIKE_FACTOR` times longer per byte than the running average.
+/// Blocks smaller than the size asked for, because the buffer held less, say nothing
+/// about the output so only feed the average.
+pub struct AdaptiveBlock {
+    size          : usize,
+    min           : usize,
+    max           : usize,
+    prompt_writes : usize,
+    nanos_per_byte: Option<f64>
+}
+
+impl AdaptiveBlock {
+    /// Constructs an `AdaptiveBlock` that adapts between `MIN_BLOCK` and `max` bytes.
+    pub fn new(max: usize) -> AdaptiveBlock {
+        let min = cmp::min(MIN_BLOCK, max);
+        AdaptiveBlock { size: min, min, max, prompt_writes: 0, nanos_per_byte: None }
+    }
+
+    /// Returns the number of bytes to ask the buffer for next.
+    pub fn size(&self) -> usize { self.size }
+
+    /// Records that a block of `n` bytes took `took` to write, and had to be written in
+    /// parts if `partial`, adjusting the size accordingly.
+    pub fn record(&mut self, n: usize, partial: bool, took: Duration) {
+        if n == 0 { return; }
+        let per_byte = took.as_nanos() as f64 / n as f64;
+        let spike = self.nanos_per_byte.is_some_and(|average| per_byte > average * SPIKE_FACTOR);
+        self.nanos_per_byte = Some(match self.nanos_per_byte {
+            None          => per_byte,
+            Some(average) => average + SMOOTHING * (per_byte - average)
+        });
+
+        if partial || spike {
+            self.size = cmp::max(self.size / 2, self.min);
+            self.prompt_writes = 0;
+        }
+        else if n >= self.size {
+            self.prompt_writes += 1;
+            if self.prompt_writes >= GROW_AFTER {
+                self.size = cmp::min(self.size * 2, self.max);
+                self.prompt_writes = 0;
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+const PROMPT: Duration = Duration::from_micros(10);
+
+#[test]
+fn test_grows_while_writes_are_full_and_prompt() {
+    let mut block = AdaptiveBlock::new(64 * 1024);
+    assert!(block.size() == MIN_BLOCK);
+    for _ in 0..GROW_AFTER - 1 { block.record(block.size(), false, PROMPT); }
+    assert!(block.size() == MIN_BLOCK);
+    block.record(block.size(), false, PROMPT / 2);
+    assert!(block.size() == 2 * MIN_BLOCK);
+
+    // Short blocks, with the buffer running low, don't count towards growing
+    for _ in 0..GROW_AFTER { block.record(100, false, Duration::from_nanos(1)); }
+    assert!(block.size() == 2 * MIN_BLOCK);
+
+    // And growth stops at the maximum
+    for _ in 0..100 { block.record(block.size(), false, Duration::from_nanos(1)); }
+    assert!(block.size() == 64 * 1024);
+}
+
+#[test]
+fn test_shrinks_on_partial_writes_and_spikes() {
+    let mut block = AdaptiveBlock::new(64 * 1024);
+    for _ in 0..100 { block.record(block.size(), false, Duration::from_nanos(block.size() as u64)); }
+    assert!(block.size() == 64 * 1024);
+
+    block.record(block.size(), true, Duration::from_nanos(block.size() as u64));
+    assert!(block.size() == 32 * 1024);
+    block.record(block.size(), false, Duration::from_nanos(10 * block.size() as u64));
+    assert!(block.size() == 16 * 1024);
+
+    // But not below the minimum
+    for _ in 0..100 { block.record(block.size(), true, PROMPT); }
+    assert!(block.size() == MIN_BLOCK);
+}
+
+#[test]
+fn test_small_maximum() {
+    let mut block = AdaptiveBlock::new(1000);
+    assert!(block.size() == 1000);
+    block.record(1000, true, PROMPT);
+    assert!(block.size() == 1000);
+}
@@ -0,0 +1,893 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A module that provides a thread-safe, blocking ring buffer for a producer and a
+//! consumer thread.
+
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, TryLockError};
+use std::time::{Duration, Instant};
+
+// How many times a producer must find the buffer full before it is grown
+const GROWTH_THRESHOLD: usize = 4;
+
+// How often a blocked producer or consumer wakes to check whether shutdown was requested
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+/// A ring buffer that can be shared between a producer and a consumer thread, where
+/// the producer blocks while the buffer is full and the consumer while it is empty.
+///
+/// The producer and consumer each have their own lock, so neither waits on the other
+/// while copying items in or out.  The only state both touch on every transfer is the
+/// atomic count of items in the buffer: the producer only writes to slots beyond the
+/// end of the items counted, and the consumer only reads slots within them, so each
+/// hands slots over to the other simply by updating the count.  The two only contend
+/// when one has to sleep on, or wake the other from, a full or empty buffer, which is
+/// done under a third lock held just long enough to check the count.
+///
+/// The buffer may optionally be allowed to grow: if the producer finds the buffer
+/// full `GROWTH_THRESHOLD` times its capacity is doubled, up to a maximum, instead
+/// of waiting on the consumer.  Growing reallocates the slots, so it is done holding
+/// both locks; the lock order is always the producer's, then the consumer's, and the
+/// consumer never sleeps holding its lock, as the producer may be waiting on it to grow.
+///
+/// As with `RingBuffer`, slots are only initialized as items are first put into them,
+/// and items are cloned out by the consumer, leaving each slot initialized until it is
+/// overwritten by a later put, so for types like `u8` every copy is a plain copy.
+///
+/// If the consumer can no longer accept items it may `abort()` the buffer, which
+/// releases a producer blocked on a full buffer rather than leaving it waiting
+/// forever.  Alternatively `request_shutdown()` has the same effect but only sets a
+/// flag, without taking a lock, so may be used where locking is not possible, such
+/// as from a signal handler; blocked threads notice it within `SHUTDOWN_POLL`.
+///
+/// The time each side spends blocked is accumulated, which indicates whether the
+/// producer (time blocked on full) or the consumer (time blocked on empty) is the
+/// slower of the two.  To know when the producer first outran the consumer, a
+/// callback may be set with `on_full()`.
+pub struct BlockingRingBuffer<T: Clone> {
+    storage     : UnsafeCell<Storage<T>>,
+    capacity    : AtomicUsize,
+    max_capacity: usize,
+    len         : AtomicUsize,
+    producer    : Mutex<Producer>,
+    consumer    : Mutex<Consumer>,
+    sleeping    : Mutex<()>,
+    not_empty   : Sleepers,
+    not_full    : Sleepers,
+    closed      : AtomicBool,
+    aborted     : AtomicBool,
+    shutdown    : AtomicBool,
+    full_armed  : AtomicBool,
+    counters    : Counters
+}
+
+// The slots are only ever accessed by one thread at a time, as handed over by `len`
+unsafe impl<T: Clone + Send> Send for BlockingRingBuffer<T> {}
+unsafe impl<T: Clone + Send> Sync for BlockingRingBuffer<T> {}
+
+/// A view of the buffer's counters.  Each is read atomically, without locking, so a
+/// snapshot never holds up the producer or consumer, but while they are running the
+/// figures may be from very slightly different moments.
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    /// The number of items currently in the buffer.
+    pub len      : usize,
+    /// The current capacity of the buffer.
+    pub capacity : usize,
+    /// The most items the buffer has held at once.
+    pub high_water_mark: usize,
+    /// The total number of items put into the buffer.
+    pub total_put: u64,
+    /// The total number of items fetched from the buffer.
+    pub total_got: u64,
+    /// The total time the producer has spent blocked on a full buffer.
+    pub blocked_on_full : Duration,
+    /// The total time the consumer has spent blocked on an empty buffer.
+    pub blocked_on_empty: Duration,
+    /// The number of `put_blocking()` copies into the buffer, and how many of them had
+    /// to be split in two because the free space wrapped around the end of the buffer.
+    pub puts      : u64,
+    pub split_puts: u64,
+    /// The number of `get_blocking()` and `consume_blocking()` calls that got anything,
+    /// and how many of them took items from both sides of the wrap around the end of
+    /// the buffer.
+    pub gets      : u64,
+    pub split_gets: u64
+}
+
+/// The allocation behind the slots, which only changes with both locks held.
+struct Storage<T> {
+    ptr      : *mut T,
+    allocated: usize
+}
+
+/// The producer's state, guarded by its lock.
+struct Producer {
+    write_pos  : usize,
+    initialized: usize,
+    times_full : usize,
+    on_full    : Option<Box<dyn FnMut() + Send>>
+}
+
+/// The consumer's state, guarded by its lock.
+struct Consumer {
+    read_pos: usize
+}
+
+/// The threads sleeping until the buffer is no longer full, or no longer empty, and
+/// how many of them there are, so that there is only any need to wake them if any.
+#[derive(Default)]
+struct Sleepers {
+    condvar: Condvar,
+    count  : AtomicUsize
+}
+
+/// The counters reported by `snapshot()`, with durations in nanoseconds.  Apart from
+/// the times blocked, each is only updated by one side, holding its lock, so with
+/// `bump()` rather than the costlier `fetch_add()`.
+#[derive(Default)]
+struct Counters {
+    high_water_mark : AtomicUsize,
+    total_put       : AtomicU64,
+    total_got       : AtomicU64,
+    blocked_on_full : AtomicU64,
+    blocked_on_empty: AtomicU64,
+    puts            : AtomicU64,
+    split_puts      : AtomicU64,
+    gets            : AtomicU64,
+    split_gets      : AtomicU64
+}
+
+/// Returns `pos` wrapped around to the start of a buffer of `capacity` slots if past its end.
+fn wrap(pos: usize, capacity: usize) -> usize { if pos >= capacity { pos - capacity } else { pos } }
+
+/// Adds `n` to a counter only ever updated by one thread at a time.
+fn bump(counter: &AtomicU64, n: u64) { counter.store(counter.load(Ordering::Relaxed) + n, Ordering::Relaxed); }
+
+/// Adds `elapsed` to the nanoseconds in `counter`.
+fn add_time(counter: &AtomicU64, elapsed: Duration) { counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed); }
+
+impl<T: Clone> BlockingRingBuffer<T> {
+    /// Constructs a new BlockingRingBuffer with a fixed capacity of `size`.
+    ///
+    /// # Panics
+    /// Will panic if `size` is zero.
+    pub fn new(size: usize) -> BlockingRingBuffer<T> {
+        BlockingRingBuffer::with_max_capacity(size, size)
+    }
+
+    /// Constructs a new BlockingRingBuffer with capacity `size` that may grow up to
+    /// `max_capacity` if the producer repeatedly finds it full.
+    ///
+    /// # Panics
+    /// Will panic if `size` is zero.
+    pub fn with_max_capacity(size: usize, max_capacity: usize) -> BlockingRingBuffer<T> {
+        assert!(size > 0, "size must be greater than zero");
+        let mut slots = ManuallyDrop::new(Vec::with_capacity(size));
+        BlockingRingBuffer {
+            storage     : UnsafeCell::new(Storage { ptr: slots.as_mut_ptr(), allocated: slots.capacity() }),
+            capacity    : AtomicUsize::new(size),
+            max_capacity: cmp::max(size, max_capacity),
+            len         : AtomicUsize::new(0),
+            producer    : Mutex::new(Producer { write_pos: 0, initialized: 0, times_full: 0, on_full: None }),
+            consumer    : Mutex::new(Consumer { read_pos: 0 }),
+            sleeping    : Mutex::new(()),
+            not_empty   : Sleepers::default(),
+            not_full    : Sleepers::default(),
+            closed      : AtomicBool::new(false),
+            aborted     : AtomicBool::new(false),
+            shutdown    : AtomicBool::new(false),
+            full_armed  : AtomicBool::new(true),
+            counters    : Counters::default()
+        }
+    }
+
+    /// Puts all the items from `input` into the buffer, blocking whenever the buffer
+    /// is full until the consumer makes space.
+    ///
+    /// # Return
+    /// True if all the items were put, or false if the buffer was aborted, or closed
+    /// by another thread, first.
+    pub fn put_blocking(&self, input: &[T]) -> bool {
+        let mut producer = self.producer.lock().unwrap();
+        let mut start = 0;
+        while start < input.len() {
+            self.check_shutdown();
+            if self.is_full() { producer.times_full += 1; } // once per time it fills, however long it stays full
+            while self.is_full() && !self.is_stopped() {
+                let capacity = self.capacity();
+                if producer.times_full >= GROWTH_THRESHOLD && capacity < self.max_capacity
+                    && self.grow(&mut producer, cmp::min(capacity * 2, self.max_capacity)) {
+                    producer.times_full = 0;
+                }
+                else {
+                    let elapsed = self.sleep(&self.not_full, SHUTDOWN_POLL, || self.is_full());
+                    add_time(&self.counters.blocked_on_full, elapsed);
+                    self.check_shutdown();
+                }
+            }
+
+            if self.is_stopped() { return false; }
+
+            // The free slots run from write_pos, wrapping round to the start of the buffer
+            let capacity = self.capacity();
+            let n = cmp::min(capacity - self.len.load(Ordering::Acquire), input.len() - start);
+            let pos = producer.write_pos;
+            let first = cmp::min(n, capacity - pos);
+            unsafe {
+                self.write_slots(&mut producer.initialized, pos, &input[start..start+first]);
+                self.write_slots(&mut producer.initialized, 0, &input[start+first..start+n]);
+            }
+            producer.write_pos = wrap(pos + n, capacity);
+            let previous = self.len.fetch_add(n, Ordering::SeqCst);
+
+            let counters = &self.counters;
+            if previous + n > counters.high_water_mark.load(Ordering::Relaxed) { counters.high_water_mark.store(previous + n, Ordering::Relaxed); }
+            bump(&counters.total_put, n as u64);
+            bump(&counters.puts, 1);
+            if first < n { bump(&counters.split_puts, 1); }
+            start += n;
+            if previous == 0 { self.wake(&self.not_empty); }
+            if previous + n == capacity && self.full_armed.swap(false, Ordering::AcqRel) {
+                if let Some(ref mut f) = producer.on_full { f(); }
+            }
+        }
+        true
+    }
+
+    /// Gets items from the buffer into `output`, blocking while the buffer is empty
+    /// until the producer supplies more.  If the readable items wrap around the end
+    /// of the ring, items from both sides of the wrap are fetched.
+    ///
+    /// # Return
+    /// The number of items fetched, which is zero only once the buffer has been
+    /// closed and fully drained, or has been aborted.
+    pub fn get_blocking(&self, output: &mut [T]) -> usize {
+        let got: Result<usize, ()> = self.consume_blocking(|first, second| {
+            let n = cmp::min(first.len() + second.len(), output.len());
+            let split = cmp::min(first.len(), n);
+            output[0..split].clone_from_slice(&first[0..split]);
+            output[split..n].clone_from_slice(&second[0..n-split]);
+            Ok(n)
+        });
+        got.unwrap_or(0)
+    }
+
+    /// Blocks while the buffer is empty, as `get_blocking()` does, then passes the items
+    /// in the buffer to `f` in place, as the slices either side of the wrap, the second
+    /// empty unless they wrap, and removes as many as `f` returns having consumed, from
+    /// the start of the first.  This lets the consumer write straight from the buffer,
+    /// e.g. with `write_vectored()`, rather than copy items out first.  `f` runs holding
+    /// only the consumer's lock, so the producer carries on putting meanwhile, though it
+    /// won't grow the buffer until `f` returns, and the items stay in the buffer, taking
+    /// up room, until then.
+    ///
+    /// # Return
+    /// The number of items consumed, which is zero without calling `f` only once the
+    /// buffer has been closed and fully drained, or has been aborted, or the error from `f`.
+    ///
+    /// # Panics
+    /// Will panic if `f` returns more items than it was given.
+    pub fn consume_blocking<F, E>(&self, f: F) -> Result<usize, E> where F: FnOnce(&[T], &[T]) -> Result<usize, E> {
+        // Sleep without the consumer's lock, which the producer needs in order to grow
+        let mut consumer = loop {
+            self.check_shutdown();
+            while self.is_empty() && !self.is_stopped() {
+                let elapsed = self.sleep(&self.not_empty, SHUTDOWN_POLL, || self.is_empty());
+                add_time(&self.counters.blocked_on_empty, elapsed);
+                self.check_shutdown();
+            }
+            let consumer = self.consumer.lock().unwrap();
+            if !self.is_empty() || self.is_stopped() { break consumer; }
+        };
+        let len = self.len.load(Ordering::Acquire);
+        if self.aborted.load(Ordering::Acquire) || len == 0 {
+            if len == 0 { self.full_armed.store(true, Ordering::Release); }
+            return Ok(0);
+        }
+
+        // The items run from read_pos, wrapping round to the start of the buffer
+        let capacity = self.capacity();
+        let pos = consumer.read_pos;
+        let first = cmp::min(len, capacity - pos);
+        let n = unsafe { f(self.read_slots(pos, first), self.read_slots(0, len - first))? };
+        assert!(n <= len, "Cannot consume more items than are in the buffer.");
+        consumer.read_pos = wrap(pos + n, capacity);
+        let previous = self.len.fetch_sub(n, Ordering::SeqCst);
+
+        bump(&self.counters.total_got, n as u64);
+        if n > 0 { bump(&self.counters.gets, 1); }
+        if n > first { bump(&self.counters.split_gets, 1); }
+        if previous == capacity && n > 0 { self.wake(&self.not_full); }
+        if previous == n { self.full_armed.store(true, Ordering::Release); }
+        Ok(n)
+    }
+
+    /// Waits up to `timeout` for there to be items to get, without getting any, e.g. so
+    /// that a consumer can tell that the producer has gone quiet.
+    ///
+    /// # Return
+    /// False if the timeout passed with the buffer still empty and open, and true if
+    /// there are items to get, or it has been closed or aborted, so `get_blocking()`
+    /// wouldn't block.
+    pub fn wait_for_items(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.check_shutdown();
+        while self.is_empty() && !self.is_stopped() {
+            let now = Instant::now();
+            if now >= deadline { return false; }
+            let elapsed = self.sleep(&self.not_empty, cmp::min(deadline - now, SHUTDOWN_POLL), || self.is_empty());
+            add_time(&self.counters.blocked_on_empty, elapsed);
+            self.check_shutdown();
+        }
+        true
+    }
+
+    /// Commits the memory behind the buffer up front by filling it with `value`; see
+    /// `RingBuffer::prefault()`.
+    pub fn prefault(&self, value: T) {
+        let mut producer = self.producer.lock().unwrap();
+        let _consumer = self.consumer.lock().unwrap();
+        let capacity = self.capacity();
+        let ptr = unsafe { (*self.storage.get()).ptr };
+        for i in producer.initialized..capacity { unsafe { ptr::write(ptr.add(i), value.clone()); } }
+        producer.initialized = capacity;
+    }
+
+    /// Sets `f` to be called the first time the buffer becomes full, which is when the
+    /// producer starts to wait on the consumer, and again each time it refills after
+    /// having drained empty.  It is called on the producer's thread while the producer
+    /// holds its lock, so it should be quick and must not use the buffer itself.
+    pub fn on_full<F>(&self, f: F) where F: FnMut() + Send + 'static {
+        self.producer.lock().unwrap().on_full = Some(Box::new(f));
+    }
+
+    /// Discards everything in the buffer, waking a producer blocked on it being full.
+    /// What is discarded doesn't count towards `total_got`.
+    ///
+    /// # Return
+    /// The number of items discarded.
+    pub fn discard(&self) -> usize {
+        let mut consumer = self.consumer.lock().unwrap();
+        let n = self.len.load(Ordering::Acquire);
+        consumer.read_pos = wrap(consumer.read_pos + n, self.capacity());
+        if self.len.fetch_sub(n, Ordering::SeqCst) == n { self.full_armed.store(true, Ordering::Release); }
+        self.wake_all();
+        n
+    }
+
+    /// Closes the buffer, waking the consumer so that it can drain what remains, and
+    /// any producer blocked on a full buffer so that it can give up.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wake_all();
+    }
+
+    /// Aborts the buffer, waking both sides: any blocked or future `put_blocking()`
+    /// returns false and any `get_blocking()` returns zero.  Used by the consumer to
+    /// stop the producer when it can no longer accept items.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        self.wake_all();
+    }
+
+    /// Requests that the buffer be shut down, which once noticed has the same effect as
+    /// `abort()`.  Only sets a flag, so is safe to call from a signal handler.
+    pub fn request_shutdown(&self) { self.shutdown.store(true, Ordering::SeqCst); }
+
+    /// Returns true if shutdown has been requested, and false otherwise.
+    pub fn is_shutdown_requested(&self) -> bool { self.shutdown.load(Ordering::SeqCst) }
+
+    /// Aborts the buffer if shutdown has been requested.
+    fn check_shutdown(&self) {
+        if self.is_shutdown_requested() { self.aborted.store(true, Ordering::Release); }
+    }
+
+    /// Returns true if the buffer has been aborted, or shutdown requested, and false otherwise.
+    pub fn is_aborted(&self) -> bool { self.aborted.load(Ordering::Acquire) || self.is_shutdown_requested() }
+
+    /// Returns true if the buffer is closed, and false otherwise.
+    pub fn is_closed(&self) -> bool { self.closed.load(Ordering::Acquire) }
+
+    /// Returns the total time the producer has spent blocked on a full buffer.
+    pub fn blocked_on_full(&self) -> Duration { Duration::from_nanos(self.counters.blocked_on_full.load(Ordering::Relaxed)) }
+
+    /// Returns the total time the consumer has spent blocked on an empty buffer.
+    pub fn blocked_on_empty(&self) -> Duration { Duration::from_nanos(self.counters.blocked_on_empty.load(Ordering::Relaxed)) }
+
+    /// Returns a snapshot of the buffer's fill level and transfer counters.
+    pub fn snapshot(&self) -> Snapshot {
+        let counters = &self.counters;
+        Snapshot {
+            len      : self.len.load(Ordering::Acquire),
+            capacity : self.capacity.load(Ordering::Acquire),
+            high_water_mark: counters.high_water_mark.load(Ordering::Relaxed),
+            total_put: counters.total_put.load(Ordering::Relaxed),
+            total_got: counters.total_got.load(Ordering::Relaxed),
+            blocked_on_full : self.blocked_on_full(),
+            blocked_on_empty: self.blocked_on_empty(),
+            puts      : counters.puts.load(Ordering::Relaxed),
+            split_puts: counters.split_puts.load(Ordering::Relaxed),
+            gets      : counters.gets.load(Ordering::Relaxed),
+            split_gets: counters.split_gets.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Returns the current capacity, which only changes with both locks held, so is
+    /// stable while either is.
+    fn capacity(&self) -> usize { self.capacity.load(Ordering::Acquire) }
+
+    fn is_empty(&self) -> bool { self.len.load(Ordering::SeqCst) == 0 }
+
+    fn is_full(&self) -> bool { self.len.load(Ordering::SeqCst) == self.capacity() }
+
+    /// True if neither side should wait any longer: the buffer is closed or aborted, or
+    /// shutdown has been requested.
+    fn is_stopped(&self) -> bool { self.is_closed() || self.is_aborted() }
+
+    /// Sleeps among `sleepers` for up to `timeout`, unless `blocked` no longer holds or
+    /// the buffer has been stopped.  The sleeper is counted before `blocked` is checked,
+    /// and `wake()` checks the count after changing `len`, all sequentially consistent,
+    /// so either the sleeper sees the change or the waker sees the sleeper.  And both
+    /// check under the sleeping lock, so a wakeup can't be missed before the wait.
+    ///
+    /// # Return
+    /// How long was spent sleeping.
+    fn sleep<F: Fn() -> bool>(&self, sleepers: &Sleepers, timeout: Duration, blocked: F) -> Duration {
+        let started = Instant::now();
+        let guard = self.sleeping.lock().unwrap();
+        sleepers.count.fetch_add(1, Ordering::SeqCst);
+        if blocked() && !self.is_stopped() { drop(sleepers.condvar.wait_timeout(guard, timeout).unwrap()); }
+        sleepers.count.fetch_sub(1, Ordering::SeqCst);
+        started.elapsed()
+    }
+
+    /// Wakes a thread among `sleepers`, if there are any.
+    fn wake(&self, sleepers: &Sleepers) {
+        if sleepers.count.load(Ordering::SeqCst) > 0 {
+            drop(self.sleeping.lock().unwrap());
+            sleepers.condvar.notify_one();
+        }
+    }
+
+    /// Wakes every thread sleeping on either side of the buffer.
+    fn wake_all(&self) {
+        drop(self.sleeping.lock().unwrap());
+        self.not_empty.condvar.notify_all();
+        self.not_full.condvar.notify_all();
+    }
+
+    /// Grows the buffer to `new_capacity` slots, holding the producer's lock as
+    /// `producer` and taking the consumer's.  The items are cloned, in the order they
+    /// will be read, to the start of a new allocation, so the buffer no longer wraps.
+    /// If the consumer is in the middle of `consume_blocking()`, still reading items in
+    /// place, the buffer is left as it is, rather than have the producer wait on it.
+    ///
+    /// # Return
+    /// True if the buffer was grown, and false if the consumer was busy.
+    fn grow(&self, producer: &mut Producer, new_capacity: usize) -> bool {
+        let mut consumer = match self.consumer.try_lock() {
+            Ok(consumer) => consumer,
+            Err(TryLockError::WouldBlock) => return false,
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e)
+        };
+        let capacity = self.capacity();
+        let len = self.len.load(Ordering::Acquire);
+        let first = cmp::min(len, capacity - consumer.read_pos);
+        let mut slots = ManuallyDrop::new(Vec::with_capacity(new_capacity));
+        unsafe {
+            slots.extend_from_slice(self.read_slots(consumer.read_pos, first));
+            slots.extend_from_slice(self.read_slots(0, len - first));
+            let storage = &mut *self.storage.get();
+            drop(Vec::from_raw_parts(storage.ptr, producer.initialized, storage.allocated));
+            *storage = Storage { ptr: slots.as_mut_ptr(), allocated: slots.capacity() };
+        }
+        producer.initialized = len;
+        producer.write_pos   = len;
+        consumer.read_pos    = 0;
+        self.capacity.store(new_capacity, Ordering::Release);
+        true
+    }
+
+    /// Clones `items` into the slots from `pos`, overwriting those that already hold an
+    /// item and initializing the rest, of which there are `initialized` from the start.
+    ///
+    /// # Safety
+    /// Must be called by the producer, holding its lock, with the slots free and within
+    /// the buffer.  Slots are initialized in order, so `pos` can't be past `initialized`.
+    unsafe fn write_slots(&self, initialized: &mut usize, pos: usize, items: &[T]) {
+        debug_assert!(pos <= *initialized);
+        let ptr = (*self.storage.get()).ptr;
+        let overwrite = cmp::min(items.len(), *initialized - pos);
+        slice::from_raw_parts_mut(ptr.add(pos), overwrite).clone_from_slice(&items[0..overwrite]);
+        for (i, item) in items[overwrite..].iter().enumerate() {
+            ptr::write(ptr.add(pos + overwrite + i), item.clone());
+        }
+        *initialized = cmp::max(*initialized, pos + items.len());
+    }
+
+    /// Returns the `n` items in the slots from `pos`.
+    ///
+    /// # Safety
+    /// Must be called by the consumer, or the producer when growing, holding its lock,
+    /// with the slots holding items put but not yet got.
+    unsafe fn read_slots(&self, pos: usize, n: usize) -> &[T] {
+        slice::from_raw_parts((*self.storage.get()).ptr.add(pos), n)
+    }
+}
+
+impl<T: Clone> Drop for BlockingRingBuffer<T> {
+    fn drop(&mut self) {
+        let initialized = self.producer.get_mut().unwrap_or_else(|e| e.into_inner()).initialized;
+        let storage = self.storage.get_mut();
+        unsafe { drop(Vec::from_raw_parts(storage.ptr, initialized, storage.allocated)); }
+    }
+}
+
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::thread;
+
+#[test]
+fn test_blocking_hand_off() {
+    let buffer: Arc<BlockingRingBuffer<u32>> = Arc::new(BlockingRingBuffer::new(7));
+    let producer = {
+        let buffer = buffer.clone();
+        thread::spawn(move || {
+            let xs: Vec<u32> = (0..10000).collect();
+            for chunk in xs.chunks(13) { buffer.put_blocking(chunk); }
+            buffer.close();
+        })
+    };
+
+    let mut received = Vec::new();
+    let mut ys = [0u32; 5];
+    loop {
+        let n = buffer.get_blocking(&mut ys);
+        if n == 0 { break; }
+        received.extend_from_slice(&ys[0..n]);
+    }
+    producer.join().unwrap();
+
+    assert!(received.len() == 10000);
+    for (i, y) in received.iter().enumerate() { assert!(*y as usize == i); }
+}
+
+/// A xorshift64 generator, so that the stress test's payload and chunk sizes are the same
+/// on every run.
+#[cfg(test)]
+struct Rng(u64);
+
+#[cfg(test)]
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn between_one_and(&mut self, max: usize) -> usize { 1 + (self.next() % max as u64) as usize }
+}
+
+#[test]
+fn test_concurrent_transfer_stress() {
+    // Many times the buffer's size, in puts and gets of random sizes up to twice its size,
+    // so that it fills and empties, and wraps at every offset, over and over
+    const SEED: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut rng = Rng(SEED);
+    let payload: Vec<u8> = (0..4 * 1024 * 1024).map(|_| rng.next() as u8).collect();
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::new(61));
+
+    let producer = {
+        let buffer = buffer.clone();
+        let payload = payload.clone();
+        thread::spawn(move || {
+            let mut rng = Rng(SEED ^ 1);
+            let mut start = 0;
+            while start < payload.len() {
+                let end = cmp::min(start + rng.between_one_and(122), payload.len());
+                assert!(buffer.put_blocking(&payload[start..end]));
+                start = end;
+            }
+            buffer.close();
+        })
+    };
+
+    let mut rng = Rng(SEED ^ 2);
+    let mut received = Vec::with_capacity(payload.len());
+    let mut chunk = [0u8; 122];
+    loop {
+        let n = buffer.get_blocking(&mut chunk[0..rng.between_one_and(122)]);
+        if n == 0 { break; }
+        received.extend_from_slice(&chunk[0..n]);
+    }
+    producer.join().unwrap();
+
+    let mismatch = received.iter().zip(payload.iter()).position(|(a, b)| a != b);
+    assert!(mismatch.is_none() && received.len() == payload.len(),
+            "with seed {:x}: received {} of {} bytes, first difference at {:?}", SEED, received.len(), payload.len(), mismatch);
+    let snapshot = buffer.snapshot();
+    assert!(snapshot.split_puts > 0 && snapshot.split_gets > 0);
+}
+
+#[test]
+fn test_concurrent_growth_with_owned_items() {
+    // Strings own heap memory, so any slot cloned from, or dropped, at the wrong time shows
+    let buffer: Arc<BlockingRingBuffer<String>> = Arc::new(BlockingRingBuffer::with_max_capacity(3, 200));
+    let producer = {
+        let buffer = buffer.clone();
+        thread::spawn(move || {
+            let mut rng = Rng(7);
+            let xs: Vec<String> = (0..20000).map(|i| i.to_string()).collect();
+            let mut start = 0;
+            while start < xs.len() {
+                let end = cmp::min(start + rng.between_one_and(9), xs.len());
+                assert!(buffer.put_blocking(&xs[start..end]));
+                start = end;
+            }
+            buffer.close();
+        })
+    };
+
+    let mut rng = Rng(11);
+    let mut received = Vec::new();
+    let mut ys = vec![String::new(); 9];
+    loop {
+        let n = buffer.get_blocking(&mut ys[0..rng.between_one_and(9)]);
+        if n == 0 { break; }
+        received.extend_from_slice(&ys[0..n]);
+        if received.len() % 1000 == 0 { thread::sleep(Duration::from_millis(1)); }
+    }
+    producer.join().unwrap();
+
+    assert!(received.len() == 20000);
+    for (i, y) in received.iter().enumerate() { assert!(*y == i.to_string()); }
+}
+
+#[test]
+fn test_items_dropped_once() {
+    let item = Arc::new(0u8);
+    {
+        let buffer: BlockingRingBuffer<Arc<u8>> = BlockingRingBuffer::with_max_capacity(4, 16);
+        buffer.put_blocking(&vec![item.clone(); 3]);
+        let mut out = vec![item.clone(); 2];
+        assert!(buffer.get_blocking(&mut out) == 2);
+        drop(out);
+        buffer.put_blocking(&vec![item.clone(); 2]); // wraps, overwriting a slot
+        assert!(Arc::strong_count(&item) == 5);      // one per slot ever filled, plus ours
+        buffer.discard();
+        buffer.prefault(item.clone());
+    }
+    assert!(Arc::strong_count(&item) == 1);
+}
+
+#[test]
+fn test_consume_blocking() {
+    let buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::new(6);
+    buffer.put_blocking(&[1, 2, 3, 4, 5]);
+    assert!(buffer.get_blocking(&mut [0; 3]) == 3);
+    buffer.put_blocking(&[6, 7, 8]);
+
+    // Both sides of the wrap, in place, consuming only some of them
+    let consumed: Result<usize, ()> = buffer.consume_blocking(|first, second| {
+        assert!(first == [4, 5, 6] && second == [7, 8]);
+        Ok(4)
+    });
+    assert!(consumed == Ok(4));
+    let consumed: Result<usize, ()> = buffer.consume_blocking(|first, second| {
+        assert!(first == [8] && second.is_empty());
+        Err(())
+    });
+    assert!(consumed == Err(()) && buffer.snapshot().len == 1); // nothing consumed on an error
+
+    let snapshot = buffer.snapshot();
+    assert!(snapshot.total_got == 7 && snapshot.gets == 2 && snapshot.split_gets == 1);
+
+    // Once closed and drained, f isn't called
+    buffer.close();
+    assert!(buffer.consume_blocking(|_, _| Ok::<usize, ()>(1)) == Ok(1));
+    assert!(buffer.consume_blocking(|_, _| -> Result<usize, ()> { panic!("called on an empty buffer") }) == Ok(0));
+}
+
+#[test]
+fn test_get_blocking_after_close() {
+    let buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::new(10);
+    let mut ys = [0u8; 10];
+    buffer.put_blocking(&[1, 2, 3]);
+    buffer.close();
+    assert!(buffer.is_closed());
+    assert!(buffer.snapshot().len == 3);
+    assert!(buffer.get_blocking(&mut ys) == 3);
+    assert!(buffer.snapshot().total_put == 3 && buffer.snapshot().total_got == 3);
+    assert!(buffer.get_blocking(&mut ys) == 0);
+}
+
+#[test]
+fn test_abort_releases_blocked_producer() {
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::new(4));
+    let producer = {
+        let buffer = buffer.clone();
+        thread::spawn(move || buffer.put_blocking(&[1; 10]))
+    };
+    thread::sleep(Duration::from_millis(20));
+    buffer.abort();
+    assert!(!producer.join().unwrap());
+    assert!(buffer.is_aborted());
+
+    let mut ys = [0u8; 4];
+    assert!(buffer.get_blocking(&mut ys) == 0);
+}
+
+#[test]
+fn test_put_blocking_grows() {
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::with_max_capacity(4, 64));
+    let consumer = {
+        let buffer = buffer.clone();
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut ys = [0u8; 1];
+            while buffer.get_blocking(&mut ys) > 0 {
+                received.push(ys[0]);
+                thread::sleep(Duration::from_millis(1));
+            }
+            received
+        })
+    };
+
+    let xs: Vec<u8> = (0..200).collect();
+    buffer.put_blocking(&xs);
+    buffer.close();
+    assert!(consumer.join().unwrap() == xs);
+    assert!(buffer.snapshot().capacity > 4);
+}
+
+#[test]
+fn test_long_stall_does_not_grow() {
+    // The producer finds the buffer full just once, however many times it wakes while waiting
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::with_max_capacity(4, 64));
+    buffer.put_blocking(&[1, 2, 3, 4]);
+    let producer = { let buffer = buffer.clone(); thread::spawn(move || buffer.put_blocking(&[5])) };
+    thread::sleep(SHUTDOWN_POLL * (GROWTH_THRESHOLD as u32 * 2));
+
+    let mut ys = [0u8; 4];
+    assert!(buffer.get_blocking(&mut ys) == 4);
+    assert!(producer.join().unwrap());
+    assert!(buffer.snapshot().capacity == 4);
+}
+
+#[test]
+fn test_shutdown_releases_blocked_threads() {
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::new(4));
+    buffer.put_blocking(&[1, 2, 3, 4]);
+
+    // A producer blocked on a buffer that is never drained, and a consumer of another that is never filled
+    let empty: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::new(4));
+    let producer = { let buffer = buffer.clone(); thread::spawn(move || buffer.put_blocking(&[5])) };
+    let consumer = { let empty  = empty.clone();  thread::spawn(move || empty.get_blocking(&mut [0u8; 4])) };
+    thread::sleep(Duration::from_millis(100));
+
+    buffer.request_shutdown();
+    empty.request_shutdown();
+    assert!(!producer.join().unwrap());
+    assert!(consumer.join().unwrap() == 0);
+    assert!(buffer.is_aborted());
+}
+
+#[test]
+fn test_close_releases_blocked_producer() {
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::new(4));
+    let producer = { let buffer = buffer.clone(); thread::spawn(move || buffer.put_blocking(&[1, 2, 3, 4, 5, 6])) };
+    thread::sleep(Duration::from_millis(20));
+    buffer.close();
+    assert!(!producer.join().unwrap());
+
+    // What was put before the close can still be drained
+    let mut ys = [0u8; 8];
+    assert!(buffer.get_blocking(&mut ys) == 4);
+    assert!(buffer.get_blocking(&mut ys) == 0);
+}
+
+#[test]
+fn test_on_full_fires_once_until_drained() {
+    let buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::new(10);
+    let fired = Arc::new(AtomicUsize::new(0));
+    {
+        let fired = fired.clone();
+        buffer.on_full(move || { fired.fetch_add(1, Ordering::SeqCst); });
+    }
+    let mut out = [0u8; 10];
+
+    buffer.put_blocking(&[1; 6]);
+    assert!(fired.load(Ordering::SeqCst) == 0);
+    buffer.put_blocking(&[1; 4]);
+    assert!(fired.load(Ordering::SeqCst) == 1);
+
+    // Refilling without having drained empty doesn't fire again
+    assert!(buffer.get_blocking(&mut out[0..5]) == 5);
+    buffer.put_blocking(&[1; 5]);
+    assert!(fired.load(Ordering::SeqCst) == 1);
+
+    // But refilling after draining empty does
+    assert!(buffer.get_blocking(&mut out) == 10);
+    buffer.put_blocking(&[1; 10]);
+    assert!(fired.load(Ordering::SeqCst) == 2);
+}
+
+#[test]
+fn test_discard() {
+    let buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::new(10);
+    buffer.put_blocking(&[1, 2, 3, 4, 5, 6]);
+    buffer.close();
+    assert!(buffer.discard() == 6);
+    assert!(buffer.snapshot().total_got == 0);
+    assert!(buffer.get_blocking(&mut [0u8; 10]) == 0);
+}
+
+#[test]
+fn test_wait_for_items() {
+    let buffer: Arc<BlockingRingBuffer<u8>> = Arc::new(BlockingRingBuffer::new(8));
+    assert!(!buffer.wait_for_items(Duration::from_millis(20)));
+    assert!(buffer.blocked_on_empty() >= Duration::from_millis(20));
+
+    let producer = {
+        let buffer = buffer.clone();
+        thread::spawn(move || { thread::sleep(Duration::from_millis(20)); buffer.put_blocking(&[1, 2]); })
+    };
+    assert!(buffer.wait_for_items(Duration::from_secs(10)));
+    assert!(buffer.snapshot().len == 2); // nothing was got
+    producer.join().unwrap();
+
+    buffer.get_blocking(&mut [0; 2]);
+    buffer.close();
+    assert!(buffer.wait_for_items(Duration::from_secs(10)));
+}
+
+#[test]
+fn test_split_counts() {
+    let buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::new(10);
+    let mut output = [0u8; 10];
+    buffer.put_blocking(&[0; 7]);
+    assert!(buffer.get_blocking(&mut output[0..7]) == 7);
+
+    // Positions at 7, so six items go in, and come out, either side of the wrap
+    buffer.put_blocking(&[1; 6]);
+    assert!(buffer.get_blocking(&mut output[0..2]) == 2);
+    assert!(buffer.get_blocking(&mut output) == 4);
+    let snapshot = buffer.snapshot();
+    assert!(snapshot.puts == 2 && snapshot.split_puts == 1);
+    assert!(snapshot.gets == 3 && snapshot.split_gets == 1);
+}
+
+#[test]
+#[should_panic]
+fn test_zero_capacity() {
+    let _buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::with_max_capacity(0, 16);
+}
@@ -0,0 +1,103 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Recording how much of the output has been written, for `--checkpoint`, so that an
+//! interrupted transfer can be picked up again with `--resume`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// The least time between saves of the checkpoint, other than the final one
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps a count of the bytes of the output written and flushed in a file, as a decimal
+/// number, counting from `base`, the bytes already written before a resumed transfer.
+/// The file is replaced by renaming a new one over it, so it always holds a complete
+/// count, and never one larger than what had been flushed when it was saved.
+pub struct Checkpoint {
+    path    : PathBuf,
+    base    : u64,
+    saved_at: Option<Instant>
+}
+
+impl Checkpoint {
+    /// Creates a checkpoint, saved to `path`, of a transfer that starts `base` bytes in.
+    /// Nothing is saved until `flushed()` or `save()` is called.
+    pub fn new(path: PathBuf, base: u64) -> Checkpoint {
+        Checkpoint { path, base, saved_at: None }
+    }
+
+    /// Notes that `written` bytes have now been written and flushed in this transfer,
+    /// saving the count if it hasn't been saved for `SAVE_INTERVAL`.
+    pub fn flushed(&mut self, written: u64, now: Instant) -> io::Result<()> {
+        if self.saved_at.is_some_and(|saved_at| now - saved_at < SAVE_INTERVAL) { return Ok(()); }
+        self.saved_at = Some(now);
+        self.save(written)
+    }
+
+    /// Saves the count of `written` bytes, plus the base, straight away.
+    pub fn save(&self, written: u64) -> io::Result<()> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, format!("{}\n", self.base + written))?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+/// Reads the count of bytes saved at `path`, or zero if there is no such file, as for
+/// a transfer that was interrupted before its first checkpoint.
+pub fn load(path: &Path) -> io::Result<u64> {
+    match fs::read_to_string(path) {
+        Ok(text) => text.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not a count of bytes", text.trim()))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_save_and_load() {
+    let path = ::std::env::temp_dir().join(format!("pipebuffer-test-{}-checkpoint", ::std::process::id()));
+    assert!(load(&path).unwrap() == 0);
+
+    // Saves are spaced out, bar the first and any made with save()
+    let start = Instant::now();
+    let mut checkpoint = Checkpoint::new(path.clone(), 100);
+    checkpoint.flushed(10, start).unwrap();
+    assert!(load(&path).unwrap() == 110);
+    checkpoint.flushed(20, start + SAVE_INTERVAL / 2).unwrap();
+    assert!(load(&path).unwrap() == 110);
+    checkpoint.flushed(30, start + SAVE_INTERVAL).unwrap();
+    assert!(load(&path).unwrap() == 130);
+    checkpoint.save(40).unwrap();
+    assert!(load(&path).unwrap() == 140);
+
+    fs::write(&path, "lots").unwrap();
+    assert!(load(&path).unwrap_err().kind() == io::ErrorKind::InvalidData);
+    fs::remove_file(&path).unwrap();
+}
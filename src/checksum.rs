@@ -0,0 +1,93 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A module that provides a CRC-32 checksum, for verifying that data passed through
+//! a buffer arrives intact.
+
+// The CRC-32 (IEEE 802.3) polynomial, in its reflected form
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+// The remainder for each possible byte, computed at compile time
+const TABLE: [u32; 256] = make_table();
+
+/// Builds the table of remainders used to process a byte at a time.
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A running CRC-32 checksum, as used by zlib, gzip and PNG, that can be updated with
+/// successive slices of data.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32
+}
+
+impl Crc32 {
+    /// Constructs a new checksum of no data.
+    pub fn new() -> Crc32 { Crc32 { state: 0xFFFF_FFFF } }
+
+    /// Updates the checksum with the bytes in `data`.
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.state;
+        for b in data { crc = TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8); }
+        self.state = crc;
+    }
+
+    /// Returns the checksum of all the data so far.
+    pub fn value(&self) -> u32 { !self.state }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 { Crc32::new() }
+}
+
+#[test]
+fn test_crc32_check_value() {
+    let mut crc = Crc32::new();
+    assert!(crc.value() == 0);
+    crc.update(b"123456789");
+    assert!(crc.value() == 0xCBF4_3926);
+}
+
+#[test]
+fn test_crc32_incremental() {
+    let mut whole = Crc32::new();
+    whole.update(b"The quick brown fox jumps over the lazy dog");
+    let mut parts = Crc32::new();
+    parts.update(b"The quick brown ");
+    parts.update(b"");
+    parts.update(b"fox jumps over the lazy dog");
+    assert!(whole.value() == parts.value() && whole.value() == 0x414F_A339);
+}
@@ -0,0 +1,137 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A reader that gathers a source's small reads into larger ones, for `--min-read`.
+
+use std::cmp;
+use std::io;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// Reads from a source until at least `min` bytes, or as many as asked for if fewer,
+/// have been gathered, the source reaches EOF, or `max_wait` has passed since the first
+/// of them arrived, so a source that dribbles out a few bytes at a time is passed on in
+/// larger pieces.  The wait is checked as each read of the source returns, so a source
+/// that stops altogether holds back what was gathered until its next read returns.
+///
+/// An error after some bytes have been gathered is held back until the next `read()`,
+/// so those bytes are returned first.
+pub struct Coalescing<R: Read> {
+    inner   : R,
+    min     : usize,
+    max_wait: Duration,
+    error   : Option<io::Error>
+}
+
+impl<R: Read> Coalescing<R> {
+    /// Wraps `inner` so that each read gathers at least `min` bytes, waiting at most
+    /// `max_wait` after the first of them.
+    pub fn new(inner: R, min: usize, max_wait: Duration) -> Coalescing<R> {
+        Coalescing { inner, min, max_wait, error: None }
+    }
+}
+
+impl<R: Read> Read for Coalescing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = self.error.take() { return Err(e); }
+        let wanted = cmp::min(self.min, buf.len());
+        let mut filled = 0;
+        let mut first: Option<Instant> = None;
+        while filled < wanted {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted && filled > 0 => continue,
+                Err(e) => {
+                    if filled == 0 { return Err(e); }
+                    self.error = Some(e);
+                    break;
+                }
+            }
+            if first.get_or_insert_with(Instant::now).elapsed() >= self.max_wait { break; }
+        }
+        Ok(filled)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+/// A source that gives up to `size` bytes of `data` per read, sleeping `pause` first.
+#[cfg(test)]
+pub struct Trickle { pub data: io::Cursor<Vec<u8>>, pub size: usize, pub pause: Duration }
+
+#[cfg(test)]
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        ::std::thread::sleep(self.pause);
+        let n = cmp::min(self.size, buf.len());
+        self.data.read(&mut buf[0..n])
+    }
+}
+
+#[test]
+fn test_coalesces_small_reads() {
+    let data: Vec<u8> = (0..100).collect();
+    let trickle = Trickle { data: io::Cursor::new(data.clone()), size: 3, pause: Duration::new(0, 0) };
+    let mut reader = Coalescing::new(trickle, 10, Duration::from_secs(60));
+    let mut buf = [0u8; 16];
+    let mut sizes = Vec::new();
+    let mut received = Vec::new();
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            n => { sizes.push(n); received.extend_from_slice(&buf[0..n]); }
+        }
+    }
+    // Four reads of three make each twelve, bar the last at EOF
+    assert!(received == data);
+    assert!(sizes == vec![12, 12, 12, 12, 12, 12, 12, 12, 4]);
+}
+
+#[test]
+fn test_coalescing_gives_up_waiting() {
+    let trickle = Trickle { data: io::Cursor::new(vec![7; 100]), size: 1, pause: Duration::from_millis(20) };
+    let mut reader = Coalescing::new(trickle, 100, Duration::from_millis(30));
+    let mut buf = [0u8; 100];
+    let n = reader.read(&mut buf).unwrap();
+    assert!((2..10).contains(&n));
+}
+
+#[test]
+fn test_coalescing_returns_data_before_error() {
+    let failing = io::Cursor::new(vec![1, 2, 3]).chain(FailingRead);
+    let mut reader = Coalescing::new(failing, 10, Duration::from_secs(60));
+    let mut buf = [0u8; 10];
+    assert!(reader.read(&mut buf).unwrap() == 3);
+    assert!(reader.read(&mut buf).unwrap_err().kind() == io::ErrorKind::InvalidData);
+}
+
+#[cfg(test)]
+struct FailingRead;
+
+#[cfg(test)]
+impl Read for FailingRead {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> { Err(io::Error::new(io::ErrorKind::InvalidData, "bad sector")) }
+}
@@ -0,0 +1,106 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A reader that presents a list of files as a single continuous stream, in the
+//! manner of `cat`.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Reads each of a list of files fully, in order, opening each only once the one
+/// before it has reached EOF.  EOF is reported once the last file is exhausted.
+pub struct ConcatReader {
+    paths  : Vec<PathBuf>,
+    next   : usize,
+    current: Option<File>
+}
+
+impl ConcatReader {
+    /// Creates a reader over the given files, none of which are opened until needed.
+    pub fn new(paths: Vec<PathBuf>) -> ConcatReader {
+        ConcatReader { paths, next: 0, current: None }
+    }
+
+    /// Opens the next file in the list, if there is one, reporting which file failed,
+    /// and where it is in the list, if it cannot be opened.
+    fn open_next(&mut self) -> io::Result<bool> {
+        if self.next == self.paths.len() { return Ok(false); }
+        let path = &self.paths[self.next];
+        let file = File::open(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("could not open input {} of {} ({}): {}",
+                                             self.next + 1, self.paths.len(), path.display(), e))
+        })?;
+        self.current = Some(file);
+        self.next += 1;
+        Ok(true)
+    }
+}
+
+impl Read for ConcatReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        loop {
+            if let Some(ref mut file) = self.current {
+                let n = file.read(buf)?;
+                if n > 0 { return Ok(n); }
+            }
+            // The current file, if any, is exhausted so move on to the next
+            self.current = None;
+            if !self.open_next()? { return Ok(0); }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+use super::temp_file;
+
+#[test]
+fn test_concatenates_in_order() {
+    let a = temp_file("concat-a", b"hello ");
+    let b = temp_file("concat-b", b"");
+    let c = temp_file("concat-c", b"world");
+    let mut reader = ConcatReader::new(vec![a.clone(), b.clone(), c.clone()]);
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).unwrap();
+    assert!(received == b"hello world");
+    for path in [a, b, c].iter() { ::std::fs::remove_file(path).unwrap(); }
+}
+
+#[test]
+fn test_reports_missing_file() {
+    let a = temp_file("missing-a", b"first");
+    let missing = ::std::env::temp_dir().join("pipebuffer-test-does-not-exist");
+    let mut reader = ConcatReader::new(vec![a.clone(), missing.clone()]);
+    let mut received = Vec::new();
+    let err = reader.read_to_end(&mut received).unwrap_err();
+    assert!(received == b"first");
+    assert!(err.kind() == io::ErrorKind::NotFound);
+    assert!(err.to_string().starts_with(&format!("could not open input 2 of 2 ({})", missing.display())));
+    ::std::fs::remove_file(a).unwrap();
+}
@@ -0,0 +1,145 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A module that reads a config file of default option values.  The file uses a small
+//! subset of TOML: one `key = value` per line, where the key is the long name of a
+//! command line option and the value is a quoted string, a whole number, `true` or
+//! `false`, or a single-line array of quoted strings; blank lines and `#` comments are
+//! ignored.  For example:
+//!
+//! ```toml
+//! # Buffer generously and report what happened
+//! size  = "1g"
+//! stats = true
+//! input = ["part1.dat", "part2.dat"]
+//! ```
+
+/// A value given for an option in a config file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+    List(Vec<String>)
+}
+
+impl Value {
+    /// Converts the value for the option `key` into command line arguments: `--key=value`
+    /// for a single value, one such per element of a list, `--key` for `true`, and
+    /// nothing at all for `false`.
+    pub fn to_args(&self, key: &str) -> Vec<String> {
+        match *self {
+            Value::Str(ref s)     => vec![format!("--{}={}", key, s)],
+            Value::Int(n)         => vec![format!("--{}={}", key, n)],
+            Value::Bool(true)     => vec![format!("--{}", key)],
+            Value::Bool(false)    => vec![],
+            Value::List(ref list) => list.iter().map(|s| format!("--{}={}", key, s)).collect()
+        }
+    }
+}
+
+/// Parses the text of a config file into its keys and values, in the order given.
+/// Returns a message naming the offending line if the text is malformed or a key is
+/// given more than once.
+pub fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = strip_comment(raw).trim();
+        if line.is_empty() { continue; }
+
+        let error = |msg: &str| format!("line {}: {}: {}", i + 1, msg, raw.trim());
+        let eq = line.find('=').ok_or_else(|| error("expected key = value"))?;
+        let key = line[..eq].trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(error("invalid key"));
+        }
+        if entries.iter().any(|(k, _)| k == key) { return Err(error("duplicate key")); }
+        let value = parse_value(line[eq+1..].trim()).ok_or_else(|| error("invalid value"))?;
+        entries.push((key.to_string(), value));
+    }
+    Ok(entries)
+}
+
+/// Removes a trailing `#` comment from a line, ignoring any `#` within a quoted string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"'             => quoted = !quoted,
+            '#' if !quoted  => return &line[..i],
+            _               => {}
+        }
+    }
+    line
+}
+
+/// Parses a single value: a quoted string, whole number, boolean or array of strings.
+fn parse_value(s: &str) -> Option<Value> {
+    if s == "true"  { return Some(Value::Bool(true)); }
+    if s == "false" { return Some(Value::Bool(false)); }
+    if let Ok(n) = s.parse::<u64>() { return Some(Value::Int(n)); }
+    if s.starts_with('[') && s.ends_with(']') {
+        let inner = s[1..s.len()-1].trim();
+        if inner.is_empty() { return Some(Value::List(Vec::new())); }
+        return inner.split(',').map(|item| parse_string(item.trim())).collect::<Option<Vec<_>>>().map(Value::List);
+    }
+    parse_string(s).map(Value::Str)
+}
+
+/// Parses a double-quoted string, which may not contain quotes or escapes.
+fn parse_string(s: &str) -> Option<String> {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') && !s[1..s.len()-1].contains('"') && !s.contains('\\') {
+        Some(s[1..s.len()-1].to_string())
+    }
+    else {
+        None
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_parse_config() {
+    let text = "# defaults\n\nsize = \"1g\"   # generous\nblock-size=4096\nstats = true\nquiet = false\ninput = [\"a#1\", \"b\"]\n";
+    let entries = parse(text).unwrap();
+    assert!(entries == vec![
+        ("size".to_string(),       Value::Str("1g".to_string())),
+        ("block-size".to_string(), Value::Int(4096)),
+        ("stats".to_string(),      Value::Bool(true)),
+        ("quiet".to_string(),      Value::Bool(false)),
+        ("input".to_string(),      Value::List(vec!["a#1".to_string(), "b".to_string()]))
+    ]);
+    assert!(entries[4].1.to_args("input") == vec!["--input=a#1", "--input=b"]);
+    assert!(entries[3].1.to_args("quiet").is_empty());
+}
+
+#[test]
+fn test_parse_config_errors() {
+    assert!(parse("size 1g").unwrap_err().starts_with("line 1: expected key = value"));
+    assert!(parse("size = 1g").unwrap_err().starts_with("line 1: invalid value"));
+    assert!(parse("size = \"1g").unwrap_err().starts_with("line 1: invalid value"));
+    assert!(parse("\nsize = \"1g\"\nsize = \"2g\"").unwrap_err().starts_with("line 3: duplicate key"));
+    assert!(parse("= \"1g\"").unwrap_err().starts_with("line 1: invalid key"));
+}
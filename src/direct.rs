@@ -0,0 +1,175 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! Writing the output file with `O_DIRECT`, bypassing the page cache, for `--direct`.
+//! Direct IO requires the memory written from, the length of each write and the file
+//! offset to all be aligned to the filesystem's block size, so data is staged in an
+//! aligned buffer and written a whole number of blocks at a time, with only the final
+//! partial block, if any, written through the page cache once the output is finished.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use pipe::Output;
+
+// The alignment used if the filesystem's block size is unknown or implausible
+const DEFAULT_ALIGNMENT: usize = 4096;
+
+/// An output file opened with `O_DIRECT` where supported.  Where it isn't, it warns,
+/// unless `quiet`, and behaves as an ordinary file, as it also does once `finish()`
+/// has written out the final partial block.
+pub struct DirectFile {
+    file   : File,
+    direct : bool,
+    staging: Vec<u8>,
+    start  : usize,
+    len    : usize,
+    chunk  : usize
+}
+
+impl DirectFile {
+    /// Creates, or truncates, the file at `path` for writing with `O_DIRECT`, staging
+    /// writes of at least `block_size` bytes.
+    pub fn create(path: &Path, block_size: usize, quiet: bool) -> io::Result<DirectFile> {
+        let (file, direct) = match open_direct(path) {
+            Ok(file) => (file, true),
+            Err(e)   => {
+                if !quiet { writeln!(&mut io::stderr(), "Warning: could not open {} for direct IO, so writing it through the page cache: {}", path.display(), e).unwrap(); }
+                (File::create(path)?, false)
+            }
+        };
+
+        let blksize   = file.metadata()?.blksize() as usize;
+        let alignment = if blksize.is_power_of_two() && blksize >= 512 { blksize } else { DEFAULT_ALIGNMENT };
+        let chunk     = cmp::max(block_size, alignment).div_ceil(alignment) * alignment;
+
+        // Over-allocate so that an aligned region of chunk bytes can be found within
+        let staging = vec![0u8; chunk + alignment];
+        let start   = staging.as_ptr().align_offset(alignment);
+        Ok(DirectFile { file, direct, staging, start, len: 0, chunk })
+    }
+
+    /// Writes out the staged data, which must be a whole chunk while writing directly.
+    fn write_staged(&mut self) -> io::Result<()> {
+        let result = self.file.write_all(&self.staging[self.start..self.start + self.len]);
+        self.len = 0;
+        result
+    }
+
+    /// Writes out the final partial chunk, if any, which can't be written directly, so
+    /// `O_DIRECT` is first turned off for the file.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.direct {
+            set_direct(&self.file, false)?;
+            self.direct = false;
+        }
+        if self.len > 0 { self.write_staged()?; }
+        Ok(())
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.direct { return self.file.write(buf); }
+
+        let n = cmp::min(buf.len(), self.chunk - self.len);
+        let at = self.start + self.len;
+        self.staging[at..at + n].copy_from_slice(&buf[0..n]);
+        self.len += n;
+        if self.len == self.chunk { self.write_staged()?; }
+        Ok(n)
+    }
+
+    /// Flushing can't write out a partial chunk while writing directly, so only does
+    /// anything once the output is finished.
+    fn flush(&mut self) -> io::Result<()> { self.file.flush() }
+}
+
+impl Output for DirectFile {
+    fn finish(&mut self) -> io::Result<()> { DirectFile::finish(self) }
+    fn sync(&mut self) -> io::Result<()> { self.file.sync_all() }
+}
+
+impl Drop for DirectFile {
+    /// Writes out any partial chunk, on a best-effort basis, should the output not have
+    /// been finished, e.g. because the transfer failed, so as not to lose data.
+    fn drop(&mut self) { let _ = self.finish(); }
+}
+
+/// Opens `path` with `O_DIRECT`.
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    ::std::fs::OpenOptions::new().write(true).create(true).truncate(true).custom_flags(libc::O_DIRECT).open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "O_DIRECT is only supported on Linux"))
+}
+
+/// Turns `O_DIRECT` on or off for an open file.
+#[cfg(target_os = "linux")]
+fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 { return Err(io::Error::last_os_error()); }
+    let flags = if direct { flags | libc::O_DIRECT } else { flags & !libc::O_DIRECT };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 { return Err(io::Error::last_os_error()); }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_file: &File, _direct: bool) -> io::Result<()> { Ok(()) }
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_direct_write() {
+    // The target directory rather than the temp dir, which may be a tmpfs, where O_DIRECT fails
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join(format!("pipebuffer-test-{}-direct", ::std::process::id()));
+    let data = super::test_data(100000);
+    {
+        let mut file = DirectFile::create(&path, 1000, true).unwrap();
+        assert!(file.direct);
+        for block in data.chunks(777) { file.write_all(block).unwrap(); file.flush().unwrap(); }
+        Output::finish(&mut file).unwrap();
+    }
+    assert!(::std::fs::read(&path).unwrap() == data);
+
+    // Dropping the file without finishing it still writes the final partial chunk
+    {
+        let mut file = DirectFile::create(&path, 1000, true).unwrap();
+        file.write_all(&data[0..5000]).unwrap();
+    }
+    assert!(::std::fs::read(&path).unwrap() == data[0..5000]);
+    ::std::fs::remove_file(&path).unwrap();
+}
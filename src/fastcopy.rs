@@ -0,0 +1,104 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! A kernel-side alternative to the ring buffer for copying one regular file to another,
+//! using Linux's `copy_file_range(2)`, or `sendfile(2)` where that isn't supported, so the
+//! data never passes through userspace and, on filesystems that support it, may not be
+//! copied at all but shared or copied on the storage itself.
+
+use std::io;
+use std::os::fd::RawFd;
+use std::ptr;
+
+// The most bytes asked of each system call, which the kernel may copy less of
+const CHUNK: usize = 128 * 1024 * 1024;
+
+/// True if `fd` refers to a regular file.
+pub fn is_regular_file(fd: RawFd) -> bool {
+    let mut stat: libc::stat = unsafe { ::std::mem::zeroed() };
+    unsafe { libc::fstat(fd, &mut stat) == 0 && (stat.st_mode & libc::S_IFMT) == libc::S_IFREG }
+}
+
+/// Copies from `input` to `output`, from and at their current offsets, until `input`
+/// reaches EOF, with `copy_file_range()` or failing that `sendfile()`.
+///
+/// # Return
+/// The number of bytes copied, or `None` if neither system call can copy between these
+/// files, e.g. on an old kernel or with the output opened for appending, in which case
+/// nothing has been copied and the caller should copy them some other way.
+pub fn transfer(input: RawFd, output: RawFd) -> io::Result<Option<u64>> {
+    let copy_file_range = |len| unsafe { libc::copy_file_range(input, ptr::null_mut(), output, ptr::null_mut(), len, 0) as isize };
+    let sendfile = |len| unsafe { libc::sendfile(output, input, ptr::null_mut(), len) as isize };
+    match copy_all(&copy_file_range)? {
+        Some(n) => Ok(Some(n)),
+        None    => copy_all(&sendfile)
+    }
+}
+
+/// Copies `CHUNK` bytes at a time with `copy` until it reports EOF.  If the very first
+/// call fails because the system call isn't supported for these files, returns `None`.
+fn copy_all(copy: &dyn Fn(usize) -> isize) -> io::Result<Option<u64>> {
+    let mut total = 0u64;
+    loop {
+        let n = copy(CHUNK);
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { continue; }
+            let unsupported = [libc::ENOSYS, libc::EXDEV, libc::EINVAL, libc::EBADF, libc::EOPNOTSUPP].contains(&e.raw_os_error().unwrap_or(0));
+            if total == 0 && unsupported { return Ok(None); }
+            return Err(e);
+        }
+        if n == 0 { return Ok(Some(total)); }
+        total += n as u64;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_fast_copy_transfer() {
+    use std::fs;
+    use std::os::fd::AsRawFd;
+    let data = super::test_data(3000001);
+    let from = super::temp_file("fastcopy-in", &data);
+    let to   = ::std::env::temp_dir().join(format!("pipebuffer-test-{}-fastcopy-out", ::std::process::id()));
+
+    let input  = fs::File::open(&from).unwrap();
+    let output = fs::File::create(&to).unwrap();
+    assert!(is_regular_file(input.as_raw_fd()) && is_regular_file(output.as_raw_fd()));
+    assert!(transfer(input.as_raw_fd(), output.as_raw_fd()).unwrap() == Some(data.len() as u64));
+    assert!(fs::read(&to).unwrap() == data);
+
+    // Neither will append, so that is left to the caller, with nothing copied
+    let output = fs::OpenOptions::new().append(true).open(&to).unwrap();
+    let input  = fs::File::open(&from).unwrap();
+    assert!(transfer(input.as_raw_fd(), output.as_raw_fd()).unwrap().is_none());
+    assert!(fs::read(&to).unwrap() == data);
+
+    assert!(!is_regular_file(fs::File::open("/dev/null").unwrap().as_raw_fd()));
+    fs::remove_file(&from).unwrap();
+    fs::remove_file(&to).unwrap();
+}
@@ -0,0 +1,204 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! A module implementing `--fuzz`, which hammers a small buffer with puts and gets of
+//! random sizes, at random intervals, from two threads, and checks that every byte comes
+//! out in the order it went in.
+//!
+//! The seed determines the capacity of the buffer, the size of every put and get, the
+//! pauses between them and the data itself, so a failure can be replayed with the same
+//! seed; only the interleaving of the two threads is left to the scheduler.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use pipebuffer::blocking::BlockingRingBuffer;
+use pipebuffer::checksum::Crc32;
+use pipebuffer::progress::format_bytes;
+use selftest::XorShift;
+
+/// The number of puts made if no other limit is given.
+pub const DEFAULT_ITERATIONS: u64 = 1_000_000;
+
+// The largest initial capacity of the buffer; kept small so that the ends of the ring
+// are crossed constantly
+const MAX_CAPACITY: usize = 1024;
+
+// One in this many puts or gets is preceded by a pause, of up to MAX_PAUSE
+const PAUSE_ODDS: u64 = 256;
+const MAX_PAUSE : u64 = 50;
+
+// Constants mixed into the seed so that each stream of random numbers is independent
+const PRODUCER_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+const CONSUMER_SEED: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const BUFFER_SEED  : u64 = 0x1656_67B1_9E37_79F9;
+
+/// A stream of pseudo-random bytes that is the same however it is divided up, so the
+/// consumer can regenerate exactly what the producer put, whatever the chunk sizes.
+struct ByteStream { rng: XorShift, word: [u8; 8], used: usize }
+
+impl ByteStream {
+    fn new(seed: u64) -> ByteStream {
+        ByteStream { rng: XorShift::new(seed), word: [0; 8], used: 8 }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.used == 8 {
+            self.word = self.rng.next_u64().to_le_bytes();
+            self.used = 0;
+        }
+        self.used += 1;
+        self.word[self.used - 1]
+    }
+}
+
+/// Pauses the calling thread now and then, for a random number of microseconds, so that
+/// each side in turn finds the buffer full or empty and has to block.
+fn maybe_pause(rng: &mut XorShift) {
+    if rng.next_u64().is_multiple_of(PAUSE_ODDS) {
+        thread::sleep(Duration::from_micros(rng.next_u64() % (MAX_PAUSE + 1)));
+    }
+}
+
+/// The first byte the consumer got that was not the one expected.
+struct Violation { offset: u64, expected: u8, received: u8, get: u64, len: usize }
+
+/// Runs the fuzz loop for `seed`: a producer thread makes up to `iterations` puts of
+/// between 1 and twice the buffer's capacity bytes, while the calling thread gets chunks
+/// of random size and compares them against the expected stream, until `iterations` is
+/// reached, `time_limit` elapses or a byte is out of place.  Reports the outcome on
+/// stdout, and on failure the seed and state of the buffer needed to reproduce it.
+///
+/// # Return
+/// True if every byte put was got, intact and in order, false otherwise.
+pub fn fuzz(seed: u64, iterations: u64, time_limit: Option<Duration>) -> io::Result<bool> {
+    let mut rng = XorShift::new(seed ^ BUFFER_SEED);
+    let capacity = rng.between_one_and(MAX_CAPACITY);
+    let max_capacity = if rng.next_u64().is_multiple_of(2) { capacity } else { capacity * rng.between_one_and(4) };
+    let buffer = Arc::new(BlockingRingBuffer::with_max_capacity(capacity, max_capacity));
+    let max_chunk = 2 * max_capacity;
+    let stop = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+
+    let producer = {
+        let buffer = buffer.clone();
+        let stop = stop.clone();
+        thread::Builder::new().name("producer".to_string()).spawn(move || {
+            let mut rng = XorShift::new(seed ^ PRODUCER_SEED);
+            let mut data = ByteStream::new(seed);
+            let mut crc = Crc32::new();
+            let mut chunk = vec![0u8; max_chunk];
+            let (mut puts, mut bytes) = (0u64, 0u64);
+            while puts < iterations && !stop.load(Ordering::Relaxed) {
+                if time_limit.is_some_and(|limit| started.elapsed() >= limit) { break; }
+                maybe_pause(&mut rng);
+                let n = rng.between_one_and(max_chunk);
+                for b in chunk[0..n].iter_mut() { *b = data.next_byte(); }
+                if !buffer.put_blocking(&chunk[0..n]) { break; }
+                crc.update(&chunk[0..n]);
+                puts += 1;
+                bytes += n as u64;
+            }
+            buffer.close();
+            (puts, bytes, crc)
+        })?
+    };
+
+    let mut rng = XorShift::new(seed ^ CONSUMER_SEED);
+    let mut expected = ByteStream::new(seed);
+    let mut crc = Crc32::new();
+    let mut chunk = vec![0u8; max_chunk];
+    let (mut gets, mut verified) = (0u64, 0u64);
+    let mut violation = None;
+    loop {
+        maybe_pause(&mut rng);
+        let want = rng.between_one_and(max_chunk);
+        let n = buffer.get_blocking(&mut chunk[0..want]);
+        if n == 0 { break; }
+        gets += 1;
+        for (i, b) in chunk[0..n].iter().enumerate() {
+            let e = expected.next_byte();
+            if *b != e {
+                violation = Some(Violation { offset: verified + i as u64, expected: e, received: *b, get: gets, len: n });
+                break;
+            }
+        }
+        if violation.is_some() { break; }
+        crc.update(&chunk[0..n]);
+        verified += n as u64;
+    }
+
+    // Take the state of the buffer before releasing the producer, which may change it
+    let snapshot = buffer.snapshot();
+    if violation.is_some() {
+        stop.store(true, Ordering::Relaxed);
+        buffer.abort();
+    }
+    let (puts, bytes, sent) = producer.join().unwrap();
+    let elapsed = started.elapsed();
+
+    let passed = violation.is_none() && verified == bytes && crc.value() == sent.value();
+    println!("Fuzz {} for seed {}: {} puts and {} gets, {} in {:.3}s ({}/s) through a {} byte buffer growing to at most {}",
+             if passed { "passed" } else { "FAILED" }, seed, puts, gets, format_bytes(verified), elapsed.as_secs_f64(),
+             format_bytes((verified as f64 / elapsed.as_secs_f64().max(1e-9)) as u64), capacity, max_capacity);
+    println!("Checksum: sent {:08x}, received {:08x} over {} of {} bytes", sent.value(), crc.value(), verified, bytes);
+    if !passed {
+        if let Some(ref v) = violation {
+            println!("Byte {} was {:02x} rather than {:02x}, in get {} of {} bytes", v.offset, v.received, v.expected, v.get, v.len);
+        }
+        println!("Buffer state: {:?}", snapshot);
+        println!("To reproduce: --fuzz {} --fuzz-iterations {}", seed, iterations);
+    }
+    Ok(passed)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_byte_stream_is_independent_of_chunking() {
+    let mut a = ByteStream::new(42);
+    let mut b = XorShift::new(42);
+    let mut words = vec![0u8; 24];
+    b.fill(&mut words);
+    let bytes: Vec<u8> = (0..24).map(|_| a.next_byte()).collect();
+    assert!(bytes == words);
+}
+
+#[test]
+fn test_fuzz_passes() {
+    for seed in 1..5 {
+        assert!(fuzz(seed, 2000, None).unwrap());
+    }
+}
+
+#[test]
+fn test_fuzz_time_limit() {
+    let started = Instant::now();
+    assert!(fuzz(7, u64::MAX, Some(Duration::from_millis(100))).unwrap());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,137 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A hexdump of the input as it is read, for `--inspect`, in the canonical format of
+//! `hexdump -C`: an offset, sixteen bytes in hex and the same bytes as ASCII per line.
+
+use std::cmp;
+use std::io::Write;
+
+// The number of bytes shown per line
+const WIDTH: usize = 16;
+
+/// Writes a hexdump of the bytes fed to it to `out`, up to `limit` bytes, beyond which
+/// they are only counted.  Output is written a whole line at a time, so a line that is
+/// split across feeds is held back until it is complete; the final, partial, line and a
+/// count of any bytes not shown are written when the `Hexdump` is dropped.  Errors
+/// writing to `out` are ignored, since the dump is only a diagnostic.
+pub struct Hexdump<W: Write> {
+    out  : W,
+    limit: u64,
+    shown: u64,
+    total: u64,
+    // The bytes of the line being filled, and the offset of its first
+    line  : Vec<u8>,
+    offset: u64
+}
+
+impl<W: Write> Hexdump<W> {
+    /// Constructs a `Hexdump` that writes to `out` and shows the first `limit` bytes.
+    pub fn new(out: W, limit: u64) -> Hexdump<W> {
+        Hexdump { out, limit, shown: 0, total: 0, line: Vec::with_capacity(WIDTH), offset: 0 }
+    }
+
+    /// Dumps `bytes`, or as many of them as fit within the limit.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let n = cmp::min(bytes.len() as u64, self.limit - self.shown) as usize;
+        for byte in bytes[0..n].iter() {
+            self.line.push(*byte);
+            if self.line.len() == WIDTH { self.write_line(); }
+        }
+        self.shown += n as u64;
+        self.total += bytes.len() as u64;
+    }
+
+    /// Writes out the line held, and starts a new one.
+    fn write_line(&mut self) {
+        let _ = writeln!(&mut self.out, "{}", format_line(self.offset, &self.line));
+        self.offset += self.line.len() as u64;
+        self.line.clear();
+    }
+}
+
+impl<W: Write> Drop for Hexdump<W> {
+    fn drop(&mut self) {
+        if !self.line.is_empty() { self.write_line(); }
+        if self.total > self.shown {
+            let _ = writeln!(&mut self.out, "... {} more bytes not shown", self.total - self.shown);
+        }
+    }
+}
+
+/// Formats up to `WIDTH` bytes found at `offset` as a line of the dump.
+pub fn format_line(offset: u64, bytes: &[u8]) -> String {
+    let mut line = format!("{:08x} ", offset);
+    for i in 0..WIDTH {
+        if i % 8 == 0 { line.push(' '); }
+        match bytes.get(i) {
+            Some(byte) => line.push_str(&format!("{:02x} ", byte)),
+            None       => line.push_str("   ")
+        }
+    }
+    line.push_str(" |");
+    line.extend(bytes.iter().map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' }));
+    line.push('|');
+    line
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_format_line() {
+    assert!(format_line(0, b"Hello, world!\nHi") == "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 48 69  |Hello, world!.Hi|");
+    assert!(format_line(0x1230, b"hello world\n") == "00001230  68 65 6c 6c 6f 20 77 6f  72 6c 64 0a              |hello world.|");
+}
+
+#[test]
+fn test_lines_split_across_feeds() {
+    let mut out = Vec::new();
+    {
+        let mut dump = Hexdump::new(&mut out, 1000);
+        dump.feed(b"0123456789");
+        dump.feed(b"abcdefghij");
+        dump.feed(b"ABCDE");
+    }
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.len() == 2);
+    assert!(lines[0] == format_line(0, b"0123456789abcdef"));
+    assert!(lines[1] == format_line(16, b"ghijABCDE"));
+}
+
+#[test]
+fn test_limit() {
+    let mut out = Vec::new();
+    {
+        let mut dump = Hexdump::new(&mut out, 20);
+        dump.feed(&[0u8; 15]);
+        dump.feed(&[1u8; 100]);
+    }
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.len() == 3);
+    assert!(lines[1].starts_with("00000010  01 01 01 01  "));
+    assert!(lines[2] == "... 95 more bytes not shown");
+}
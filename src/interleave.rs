@@ -0,0 +1,102 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A reader that merges several inputs into one stream by taking a read's worth from
+//! each in turn.
+
+use std::io;
+use std::io::Read;
+
+/// Reads from each of a list of sources in turn, round-robin, so that each `read()`
+/// returns whatever a single read of the next source in the rotation gave.  A source
+/// that reaches EOF drops out of the rotation and the rest carry on; EOF is reported
+/// once every source is exhausted.
+pub struct InterleaveReader {
+    sources: Vec<Box<dyn Read + Send>>,
+    next   : usize
+}
+
+impl InterleaveReader {
+    /// Creates a reader that takes from `sources` in the order given, starting with the first.
+    pub fn new(sources: Vec<Box<dyn Read + Send>>) -> InterleaveReader {
+        InterleaveReader { sources, next: 0 }
+    }
+}
+
+impl Read for InterleaveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        while !self.sources.is_empty() {
+            // An error, including Interrupted, leaves the source next in line to be retried
+            let n = self.sources[self.next].read(buf)?;
+            if n > 0 {
+                self.next = (self.next + 1) % self.sources.len();
+                return Ok(n);
+            }
+            self.sources.remove(self.next);
+            if self.next == self.sources.len() { self.next = 0; }
+        }
+        Ok(0)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+use super::temp_file;
+
+/// Reads `reader` to EOF in reads of up to `size` bytes, returning what each gave.
+#[cfg(test)]
+fn chunks(reader: &mut InterleaveReader, size: usize) -> Vec<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut buf = vec![0u8; size];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => return chunks,
+            n => chunks.push(buf[0..n].to_vec())
+        }
+    }
+}
+
+#[test]
+fn test_interleaves_files() {
+    let a = temp_file("interleave-a", b"aaaaaaaa");
+    let b = temp_file("interleave-b", b"bbbb");
+    let sources: Vec<Box<dyn Read + Send>> = vec![Box::new(::std::fs::File::open(&a).unwrap()), Box::new(::std::fs::File::open(&b).unwrap())];
+    let mut reader = InterleaveReader::new(sources);
+
+    // Once the shorter file runs out the longer is drained on its own
+    let expected: Vec<&[u8]> = vec![b"aaa", b"bbb", b"aaa", b"b", b"aa"];
+    assert!(chunks(&mut reader, 3) == expected);
+    for path in [a, b].iter() { ::std::fs::remove_file(path).unwrap(); }
+}
+
+#[test]
+fn test_interleave_empty_sources() {
+    let sources: Vec<Box<dyn Read + Send>> = vec![Box::new(io::empty()), Box::new(&b"xy"[..]), Box::new(io::empty())];
+    let expected: Vec<&[u8]> = vec![b"x", b"y"];
+    assert!(chunks(&mut InterleaveReader::new(sources), 1) == expected);
+    assert!(chunks(&mut InterleaveReader::new(Vec::new()), 1).is_empty());
+}
@@ -0,0 +1,110 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//!
+//! Library portion of `pipebuffer`, providing the buffer that the command line
+//! program uses to sit between its input and output, and the pipeline built around
+//! it, which the program runs through `pipe::PipeBuffer`.  It is exposed as a library
+//! so that the buffer can be benchmarked and both reused outside of the program.
+//!
+
+#[cfg(feature = "async")]
+extern crate tokio;
+extern crate libc;
+
+/// Writes a trace message to stderr if the `verbosity` of the given `Options` is at
+/// least `level`, formatting the message only if so.
+macro_rules! trace {
+    ($opts:expr, $level:expr, $($arg:tt)*) => {
+        if $opts.verbosity >= $level { writeln!(&mut io::stderr(), $($arg)*).unwrap(); }
+    }
+}
+
+pub mod ringbuffer;
+pub mod blocking;
+pub mod checksum;
+pub mod pipe;
+pub mod checkpoint;
+pub mod progress;
+pub mod signals;
+pub mod tee;
+pub mod token;
+pub mod watchdog;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+
+mod adaptive;
+mod coalesce;
+mod concat;
+mod direct;
+#[cfg(target_os = "linux")]
+mod fastcopy;
+mod hexdump;
+mod interleave;
+#[cfg(target_os = "linux")]
+mod splice;
+mod split;
+
+#[cfg(test)]
+use std::{env, fs, io};
+#[cfg(test)]
+use std::io::Write;
+#[cfg(test)]
+use std::path::PathBuf;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Writes `contents` to a file in the temporary directory with a name made unique to
+/// this process and `name`, returning its path.
+#[cfg(test)]
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = env::temp_dir().join(format!("pipebuffer-test-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Returns `len` bytes of test data that repeat only every 251 bytes, a prime, so that
+/// data lost, repeated or reordered in blocks of any usual size shows up.
+#[cfg(test)]
+fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// A sink that records what is written to it where the test can see it, or fails
+/// every write once `fail` is set.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedSink { written: Arc<Mutex<Vec<u8>>>, fail: bool }
+
+#[cfg(test)]
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fail { return Err(io::Error::other("disk full")); }
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+impl pipe::Output for SharedSink {}
@@ -27,133 +27,956 @@
 //! pipe in order to smooth out any "lumpiness" in the flow of data.
 //! 
 
-mod ringbuffer;
+mod config;
+mod fuzz;
+mod pidfile;
+mod selftest;
 
 #[macro_use] extern crate clap;
-extern crate regex;
+extern crate libc;
+extern crate pipebuffer;
 
+use std::cmp;
+use std::env;
+use std::ffi::OsString;
 use std::io;
-use std::io::{Read,Write};
-use std::sync::{Arc, Mutex, Condvar};
-use std::thread;
-use clap::{Arg, App};
-use ringbuffer::RingBuffer;
-use regex::Regex;
+use std::io::{IsTerminal,Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::{Arg, App, ArgMatches};
+use pidfile::Pidfile;
+use pipebuffer::pipe::{fit_block_to_buffer, writes_to_stdout, FlushInterval, NoInput, Options, PipeBuffer};
+use pipebuffer::{checkpoint, progress, signals, tee, token};
 
-// How big should the thread-local buffers for the reader and writer threads be
-const THREAD_BUFFER_SIZE: usize = 1024 * 64;
+// The largest percentage of the memory available the buffer may take without --force
+const MEMORY_PCT: u64 = 80;
+
+// The number of bytes of the input --inspect shows if not told otherwise
+const INSPECT_BYTES: usize = 1024;
+
+// The environment variable consulted for the buffer size when --size isn't given
+const SIZE_VAR: &str = "PIPEBUFFER_SIZE";
+
+// The environment variable consulted for the block size when --block-size isn't given
+const BLOCK_SIZE_VAR: &str = "PIPEBUFFER_BLOCK_SIZE";
+
+// The environment variable giving the largest buffer that may be used, however it is asked for
+const MAX_SIZE_VAR: &str = "PIPEBUFFER_MAX_SIZE";
+
+// The environment variable that, when set to anything non-empty, disables colour (see no-color.org)
+const NO_COLOR_VAR: &str = "NO_COLOR";
+
+// The exit status when --timeout cuts the transfer short, as used by coreutils' timeout
+const TIMEOUT_STATUS: i32 = 124;
+
+// The exit status when --input-timeout passes with nothing read: EX_NOINPUT, input missing
+const INPUT_TIMEOUT_STATUS: i32 = 66;
+
+// The exit status with --sigpipe-status signal when the output is closed: 128 plus SIGPIPE,
+// as a shell reports for a process killed by the signal
+const SIGPIPE_STATUS: i32 = 128 + 13;
+
+// Groups of options that are alternatives to one another, so that giving any one on the command
+// line overrides all of them in a config file
+const CONFIG_ALTERNATIVES: &[&[&str]] = &[
+    &["size", "blocks"],
+    &["input", "interleave", "listen", "unix-listen"],
+    &["output", "connect", "unix-connect", "output-prefix", "discard"],
+    &["flush-interval", "no-flush"]
+];
+
+// The fewest blocks a buffer is divided into when the block size isn't given explicitly
+const MIN_BLOCKS_PER_BUFFER: usize = 4;
+
+// The buffer and block sizes implied by --low-latency
+const LOW_LATENCY_SIZE: usize = 1024 * 1024;
+const LOW_LATENCY_BLOCK_SIZE: usize = 4 * 1024;
+
+/// Builds the definition of the command line arguments.
+fn app() -> App<'static, 'static> {
+    App::new("pipebuffer")
+        .version(crate_version!())
+        .about("A tool to rapidly buffer and re-emit data in unix pipelines.")
+        .arg(Arg::with_name("config")
+                 .long("config").takes_value(true).value_name("FILE")
+                 .help("Read default values for options from this file; options given on the command \
+                        line take precedence over it, and it over the built-in defaults."))
+        .arg(Arg::with_name("size")
+                 .short("s").long("size")
+                 .help("The size, in bytes or with k[b]/m[b]/g[b] suffix. \
+                        If not given, PIPEBUFFER_SIZE is used if set. May not exceed PIPEBUFFER_MAX_SIZE, \
+                        if that is set.")
+                 .default_value("256m"))
+        .arg(Arg::with_name("blocks")
+                 .long("blocks").takes_value(true).value_name("N")
+                 .conflicts_with("size")
+                 .help("The size as a number of blocks of --block-size bytes, instead of --size."))
+        .arg(Arg::with_name("block-size")
+                 .long("block-size").takes_value(true).value_name("SIZE")
+                 .help("The size of the chunks read from the input and written to the output. If not \
+                        given, PIPEBUFFER_BLOCK_SIZE is used if set. Unless given, reduced as needed \
+                        so the buffer holds at least four blocks. If given \
+                        larger than the buffer, it is reduced to the buffer size with a warning, \
+                        or the buffer is bypassed if nothing needs it.")
+                 .default_value("64k"))
+        .arg(Arg::with_name("low-latency")
+                 .long("low-latency")
+                 .help("Favour latency over throughput for interactive use. Implies --size 1m and \
+                        --block-size 4k, flushing after every write; options given explicitly \
+                        take precedence."))
+        .arg(Arg::with_name("min-size")
+                 .long("min-size").takes_value(true).value_name("SIZE")
+                 .help("Start the buffer at this size and grow it as needed, up to --max-size. \
+                        Defaults to --size, or to --max-size if that is smaller."))
+        .arg(Arg::with_name("max-size")
+                 .long("max-size").takes_value(true).value_name("SIZE")
+                 .help("The largest size the buffer may grow to. Defaults to --size."))
+        .arg(Arg::with_name("prefault")
+                 .long("prefault")
+                 .help("Touch every page of the buffer before starting, so the first fill doesn't \
+                        stall on page faults. Costs startup time in proportion to --size, e.g. \
+                        around half a second for 1g, and commits all the memory up front."))
+        .arg(Arg::with_name("force")
+                 .long("force")
+                 .help("Allocate the buffer even if it is larger than 80% of the memory available, \
+                        which would otherwise be refused; a warning is given instead."))
+        .arg(Arg::with_name("pace")
+                 .long("pace").takes_value(true).value_name("MILLIS")
+                 .help("Space successive writes at least this many milliseconds apart."))
+        .arg(Arg::with_name("delay")
+                 .long("delay").takes_value(true).value_name("DURATION")
+                 .help("Hold back the output for this long, e.g. 500ms or 2s, after the first data \
+                        arrives, while the input continues to fill the buffer."))
+        .arg(Arg::with_name("flush-interval")
+                 .long("flush-interval").takes_value(true).value_name("SIZE|TIME")
+                 .help("Flush the output only after this many bytes (e.g. 4mb) or this much time \
+                        (e.g. 500ms, 2s, 1h) rather than after every write. A bare m is refused \
+                        as ambiguous: give megabytes as mb. Ignored when writing to stdout and it \
+                        is a terminal."))
+        .arg(Arg::with_name("no-flush")
+                 .long("no-flush").alias("raw")
+                 .conflicts_with("flush-interval")
+                 .help("Flush the output only once, at EOF, for the most throughput when the reader \
+                        buffers its input anyway. Not for interactive use, since output may be held \
+                        back indefinitely. Ignored when writing to stdout and it is a terminal."))
+        .arg(Arg::with_name("flush-on-idle")
+                 .long("flush-on-idle").takes_value(true).value_name("DURATION").conflicts_with("prefetch")
+                 .help("With --flush-interval or --no-flush, flush what has been written anyway once \
+                        the buffer has been empty for this long, e.g. 200ms, so that a burst of input \
+                        isn't held back waiting for more that doesn't come."))
+        .arg(Arg::with_name("input")
+                 .short("i").long("input").takes_value(true).value_name("FILE")
+                 .multiple(true).number_of_values(1)
+                 .conflicts_with_all(&["listen", "unix-listen"])
+                 .help("Read from this file instead of stdin. May be repeated to read several files \
+                        in order, as if concatenated."))
+        .arg(Arg::with_name("interleave")
+                 .long("interleave").takes_value(true).number_of_values(2).value_names(&["FILE_A", "FILE_B"])
+                 .conflicts_with_all(&["input", "listen", "unix-listen"])
+                 .help("Read from these two files instead of stdin, alternating a block from one with a \
+                        block from the other. Once either ends the other is read on to its end."))
+        .arg(Arg::with_name("output")
+                 .short("o").long("output").takes_value(true).value_name("FILE")
+                 .conflicts_with_all(&["connect", "unix-connect"])
+                 .help("Write to this file instead of stdout, creating or truncating it."))
+        .arg(Arg::with_name("output-prefix")
+                 .long("output-prefix").takes_value(true).value_name("PREFIX")
+                 .requires("split").conflicts_with_all(&["output", "connect", "unix-connect"])
+                 .help("Write to a series of files named PREFIX000, PREFIX001 and so on instead of \
+                        stdout, each of the --split size bar the last."))
+        .arg(Arg::with_name("split")
+                 .long("split").takes_value(true).value_name("SIZE")
+                 .requires("output-prefix")
+                 .help("The size of each file written with --output-prefix, e.g. 1g."))
+        .arg(Arg::with_name("append")
+                 .long("append")
+                 .help("Append to the --output file rather than truncating it."))
+        .arg(Arg::with_name("fsync")
+                 .long("fsync")
+                 .help("Sync the --output file to disk once everything has been written, so that it \
+                        survives a crash as soon as pipebuffer has exited."))
+        .arg(Arg::with_name("direct")
+                 .long("direct").conflicts_with("append")
+                 .help("Write the --output file with O_DIRECT, bypassing the page cache, e.g. when \
+                        archiving streams too large to be worth caching. Falls back to ordinary \
+                        writes, with a warning, where O_DIRECT is not supported."))
+        .arg(Arg::with_name("checkpoint")
+                 .long("checkpoint").takes_value(true).value_name("FILE").conflicts_with("direct")
+                 .help("Keep the count of bytes written and flushed to the --output file in this file, \
+                        updated at most once a second and at the end, so that an interrupted transfer \
+                        can be continued with --resume."))
+        .arg(Arg::with_name("resume")
+                 .long("resume").requires("checkpoint").conflicts_with("append")
+                 .help("Continue the transfer recorded in the --checkpoint file: skip the bytes it \
+                        counts in the input, and cut the --output file back to them and write on from \
+                        there. Starts from the beginning if there is no checkpoint file yet."))
+        .arg(Arg::with_name("tee")
+                 .long("tee").takes_value(true).value_name("FILE")
+                 .multiple(true).number_of_values(1)
+                 .help("Also copy everything written to the output to this file. May be given \
+                        more than once."))
+        .arg(Arg::with_name("tee-policy")
+                 .long("tee-policy").takes_value(true).value_name("POLICY")
+                 .possible_values(&["abort", "ignore"]).default_value("abort")
+                 .help("What to do when writing to a --tee file fails: 'abort' fails the run, as a \
+                        failure on the output itself always does, 'ignore' warns and stops copying \
+                        to that file."))
+        .arg(Arg::with_name("tee-buffer")
+                 .long("tee-buffer").takes_value(true).value_name("SIZE").requires("tee")
+                 .help("Give each --tee file a buffer of this size, e.g. 64m, and a thread of its own \
+                        to write it out, so that a slow file holds back the output only once its \
+                        buffer is full, or with --tee-overflow fail not at all. Costs SIZE of memory \
+                        per file, allocated up front."))
+        .arg(Arg::with_name("tee-overflow")
+                 .long("tee-overflow").takes_value(true).value_name("POLICY")
+                 .possible_values(&["wait", "fail"]).default_value("wait")
+                 .help("What to do when a --tee file's own buffer is full: 'wait' for the file to \
+                        catch up, holding back the output, or 'fail' as if writing to the file had \
+                        failed, so that --tee-policy decides whether the run fails or the file is \
+                        dropped."))
+        .arg(Arg::with_name("listen")
+                 .long("listen").takes_value(true).value_name("HOST:PORT")
+                 .help("Read from a single TCP connection accepted on this address instead of stdin."))
+        .arg(Arg::with_name("connect")
+                 .long("connect").takes_value(true).value_name("HOST:PORT")
+                 .help("Write to a TCP connection to this address instead of stdout."))
+        .arg(Arg::with_name("unix-listen")
+                 .long("unix-listen").takes_value(true).value_name("PATH")
+                 .conflicts_with("listen")
+                 .help("Read from a single connection accepted on this Unix domain socket instead of stdin."))
+        .arg(Arg::with_name("unix-connect")
+                 .long("unix-connect").takes_value(true).value_name("PATH")
+                 .conflicts_with("connect")
+                 .help("Write to a connection to this Unix domain socket instead of stdout."))
+        .arg(Arg::with_name("discard")
+                 .long("discard").conflicts_with_all(&["output", "output-prefix", "connect", "unix-connect"])
+                 .help("Throw the data away once it has passed through the buffer instead of writing it \
+                        to stdout, without making any write calls, e.g. to measure with --stats how \
+                        fast the input can be read and buffered."))
+        .arg(Arg::with_name("prefetch")
+                 .long("prefetch")
+                 .help("Get the next block from the buffer on a separate thread while writing the \
+                        previous one. Can improve throughput to fast outputs given a spare CPU core."))
+        .arg(Arg::with_name("no-drain-on-close")
+                 .long("no-drain-on-close").conflicts_with("prefetch")
+                 .help("Fail fast: once the input ends, stop writing and discard whatever is still \
+                        buffered instead of writing it out. The output is then missing the end of \
+                        the input, by design; --stats reports how much was discarded."))
+        .arg(Arg::with_name("adaptive-block")
+                 .long("adaptive-block").conflicts_with("prefetch")
+                 .help("Start writing in small blocks and double the size while the output keeps \
+                        accepting whole blocks promptly, halving it again if writes go partial or \
+                        slow down. --block-size is then the largest block written."))
+        .arg(Arg::with_name("splice")
+                 .long("splice")
+                 .help("On Linux, when stdin and stdout are both pipes, move data between them with \
+                        splice(2) through a pipe of --size, never copying it into pipebuffer. Falls \
+                        back to the usual buffer if the kernel won't make a pipe that large, or \
+                        other options need to see the data."))
+        .arg(Arg::with_name("fast-copy")
+                 .long("fast-copy")
+                 .help("On Linux, when the input and output are both regular files, have the kernel \
+                        copy one to the other with copy_file_range(2), or sendfile(2), bypassing the \
+                        buffer. Falls back to the usual buffer for anything else, if neither call \
+                        works for the files, or other options need to see the data."))
+        .arg(Arg::with_name("stack-size")
+                 .long("stack-size").takes_value(true).value_name("SIZE")
+                 .help("The stack size for the writer thread."))
+        .arg(Arg::with_name("affinity")
+                 .long("affinity").takes_value(true).value_name("READER_CPU,WRITER_CPU")
+                 .help("Pin the reader and writer threads to these CPUs, if supported. \
+                        Best-effort: the transfer continues unpinned if pinning fails."))
+        .arg(Arg::with_name("progress")
+                 .long("progress").takes_value(true).min_values(0).value_name("FORMAT")
+                 .help("Report progress on stderr during the transfer, as a bar (the default), with \
+                        --progress=plain as one line of text per update even on a terminal, or with \
+                        --progress=json as one JSON object per line."))
+        .arg(Arg::with_name("progress-interval")
+                 .long("progress-interval").takes_value(true).value_name("DURATION")
+                 .default_value("1s")
+                 .help("How often to report progress with --progress, e.g. 250ms or 10s; at least 100ms. \
+                        A final report is always made at the end of the transfer."))
+        .arg(Arg::with_name("color")
+                 .long("color").takes_value(true).value_name("WHEN")
+                 .possible_values(&["auto", "always", "never"]).default_value("auto")
+                 .help("When to colour styled output such as the --progress bar: 'auto' only on a \
+                        terminal and only if NO_COLOR is not set, 'always' regardless, or 'never'."))
+        .arg(Arg::with_name("size-expected")
+                 .long("size-expected").takes_value(true).value_name("SIZE")
+                 .help("The number of bytes expected, used to show percent complete and ETA with --progress. \
+                        Defaults to the total size of the --input files, if given."))
+        .arg(Arg::with_name("sigpipe-status")
+                 .long("sigpipe-status").takes_value(true).value_name("exit|signal")
+                 .possible_values(&["exit", "signal"]).default_value("exit")
+                 .help("How to exit when the output is closed by its reader: 'exit' exits quietly with \
+                        status 0, 'signal' with status 141 as if killed by SIGPIPE, which \
+                        'set -o pipefail' treats as a failure."))
+        .arg(Arg::with_name("timeout")
+                 .long("timeout").takes_value(true).value_name("DURATION")
+                 .help("Stop reading the input after this long, e.g. 30s, 5m or 1h, write out what is \
+                        already buffered and exit with status 124."))
+        .arg(Arg::with_name("input-timeout")
+                 .long("input-timeout").takes_value(true).value_name("DURATION")
+                 .help("Give up, exiting with status 66, if no input has arrived this long, e.g. 30s, \
+                        after the input was opened. Once the first byte is read it no longer applies."))
+        .arg(Arg::with_name("watchdog")
+                 .long("watchdog").takes_value(true).value_name("DURATION")
+                 .help("Exit with status 70, after describing the state of the buffer and both \
+                        threads on stderr, if no data moves for this long, e.g. 1m, while neither \
+                        the reader nor the writer is waiting on the input or output. A safety net \
+                        against hangs; slow inputs and outputs never trigger it."))
+        .arg(Arg::with_name("dry-run")
+                 .long("dry-run")
+                 .help("Print the fully resolved settings, one per line, and exit without reading \
+                        or writing anything. Sizes are in bytes and durations in milliseconds."))
+        .arg(Arg::with_name("selftest")
+                 .long("selftest")
+                 .help("Pass 64 MiB of pseudo-random data through the buffer, check it arrives intact \
+                        and report the result, exiting non-zero if not."))
+        .arg(Arg::with_name("fuzz")
+                 .long("fuzz").takes_value(true).value_name("SEED")
+                 .help("Stress the buffer with puts and gets of random sizes at random intervals from \
+                        two threads, checking every byte comes out in order, and report the result, \
+                        exiting non-zero on any violation. The seed fixes the buffer size, chunk \
+                        sizes, pauses and data, so a failure can be rerun with the same seed."))
+        .arg(Arg::with_name("fuzz-iterations")
+                 .long("fuzz-iterations").takes_value(true).value_name("COUNT").requires("fuzz")
+                 .help("Stop --fuzz after this many puts; default 1000000."))
+        .arg(Arg::with_name("fuzz-time")
+                 .long("fuzz-time").takes_value(true).value_name("DURATION").requires("fuzz")
+                 .help("Stop --fuzz after this long, e.g. 30s, even if --fuzz-iterations has not been reached."))
+        .arg(Arg::with_name("stats")
+                 .long("stats")
+                 .help("Report statistics about buffer usage on stderr, or --metrics-file, at exit."))
+        .arg(Arg::with_name("count-lines")
+                 .long("count-lines")
+                 .help("Count the lines passing through and report the count on stderr, or \
+                        --metrics-file, at exit. A final line without a newline is counted."))
+        .arg(Arg::with_name("warn-on-full")
+                 .long("warn-on-full")
+                 .help("Print a timestamped line on stderr the first time the buffer fills up, i.e. \
+                        when the input starts to outrun the output, and again if it refills after \
+                        having drained."))
+        .arg(Arg::with_name("inspect")
+                 .long("inspect").takes_value(true).min_values(0).value_name("SIZE")
+                 .help("Write a hexdump of the input to stderr as it is read, in the format of \
+                        hexdump -C, without altering the output. Only the first SIZE bytes, by \
+                        default 1k, are shown."))
+        .arg(Arg::with_name("max-write")
+                 .long("max-write").takes_value(true).value_name("SIZE")
+                 .help("Pass at most this many bytes to each write to the output, however large the \
+                        block, e.g. to stay within the atomic write size of a pipe."))
+        .arg(Arg::with_name("record-size")
+                 .long("record-size").takes_value(true).value_name("SIZE")
+                 .help("Treat the stream as records of this many bytes and only ever write whole \
+                        records, so the output is never flushed part way through one. A partial \
+                        record at the end of the input is still written."))
+        .arg(Arg::with_name("min-read")
+                 .long("min-read").takes_value(true).value_name("SIZE")
+                 .help("Gather at least this many bytes of the input, up to the block size, before \
+                        putting them into the buffer, e.g. 16k, to cut the overhead of a source that \
+                        writes a little at a time. Bytes are held back no longer than 100ms for more."))
+        .arg(Arg::with_name("max-bytes")
+                 .long("max-bytes").takes_value(true).value_name("SIZE")
+                 .help("Stop reading after this many bytes of the input, e.g. 10g. Reads are sized so \
+                        that nothing beyond them is taken from the input, which can then be read on \
+                        by something else."))
+        .arg(Arg::with_name("start-after")
+                 .long("start-after").takes_value(true).value_name("TOKEN")
+                 .help("Discard the input up to and including the first occurrence of this token, \
+                        given as a string or as hex prefixed with 0x, e.g. 0x0d0a. Nothing is output \
+                        if the token never appears."))
+        .arg(Arg::with_name("include-start-token")
+                 .long("include-start-token").requires("start-after")
+                 .help("Keep the --start-after token itself, so the output starts with it."))
+        .arg(Arg::with_name("stop-before")
+                 .long("stop-before").takes_value(true).value_name("TOKEN")
+                 .help("Stop reading at the first occurrence of this token, given as for --start-after, \
+                        and exit once everything before it has been written. With --start-after, \
+                        the token is looked for only after the start token."))
+        .arg(Arg::with_name("header")
+                 .long("header").takes_value(true).value_name("BYTES").conflicts_with("checkpoint")
+                 .help("Write these bytes, given as for --start-after, to the output before anything \
+                        else, e.g. a magic number or 0xefbbbf for a UTF-8 BOM. Written once even if \
+                        the input is empty."))
+        .arg(Arg::with_name("trailer")
+                 .long("trailer").takes_value(true).value_name("BYTES").conflicts_with("checkpoint")
+                 .help("Write these bytes, given as for --start-after, to the output after the input \
+                        has ended and everything before it has been written."))
+        .arg(Arg::with_name("checksum-verify")
+                 .long("checksum-verify").takes_value(true).value_name("CRC32")
+                 .help("Compute the CRC-32 (as used by gzip and zip) of the output and, at EOF, fail if \
+                        it isn't this one, given in hex. The data is passed through unchanged either way."))
+        .arg(Arg::with_name("on-drain")
+                 .long("on-drain").takes_value(true).value_name("COMMAND")
+                 .help("Run this shell command once the input has reached EOF and the last buffered \
+                        byte has been written and flushed. Fails if the command does."))
+        .arg(Arg::with_name("pidfile")
+                 .long("pidfile").takes_value(true).value_name("FILE")
+                 .help("Write the process ID and the buffer size in bytes, on a line each, to this \
+                        file while running, so other tools can find and signal the process, and \
+                        remove it at exit, including after SIGTERM or SIGINT. Refused if the file \
+                        names a process that is still running."))
+        .arg(Arg::with_name("verbose")
+                 .short("v").long("verbose").multiple(true)
+                 .help("Trace the transfer on stderr; give twice to trace every read and write."))
+        .arg(Arg::with_name("quiet")
+                 .short("q").long("quiet")
+                 .help("Write nothing to stderr except fatal errors. Overrides --progress and --stats."))
+        .arg(Arg::with_name("metrics-file")
+                 .long("metrics-file").takes_value(true).value_name("PATH")
+                 .help("Write --progress and --stats output to this file or FIFO instead of stderr. \
+                        Writes are best-effort and never hold up the transfer."))
+        .arg(Arg::with_name("buffer-stats-interval")
+                 .long("buffer-stats-interval").takes_value(true).value_name("DURATION")
+                 .requires("metrics-file")
+                 .help("Append a JSON line to --metrics-file every DURATION, e.g. 10s, giving the \
+                        minimum, maximum and average fill of the buffer over the interval and the \
+                        percentage of it the reader and writer spent blocked; at least 100ms."))
+}
 
 /// Main function that coordinates argument parsing and then delegates to the
 /// `run()` function to do the actual work.
 pub fn main() {
-    let matches =
-        App::new("pipebuffer")
-            .version(crate_version!())
-            .about("A tool to rapidly buffer and re-emit data in unix pipelines.")
-            .arg(Arg::with_name("size")
-                     .short("s").long("size")
-                     .help("The size, in bytes or with k[b]/m[b]/g[b] suffix.")
-                     .default_value("256m"))
-            .get_matches();
-
-    let buffer_size = match parse_memory(matches.value_of("size").unwrap()) {
-        Some(size) => size,
-        None       => {
-            println!("{}", matches.usage());
-            println!("Error: Argument {} is not a valid size.", matches.value_of("size").unwrap());
-            std::process::exit(1)
+    let matches = apply_config(app().get_matches(), env::args_os().collect());
+
+    let opts = options(&matches);
+    if matches.is_present("dry-run") {
+        write!(&mut io::stdout(), "{}", describe(&opts)).unwrap();
+        std::process::exit(0);
+    }
+    if matches.is_present("selftest") {
+        match selftest::selftest(&opts, selftest::SELFTEST_BYTES) {
+            Ok(true)  => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e)    => { writeln!(&mut io::stderr(), "Error: {}", e).unwrap(); std::process::exit(1); }
         }
+    }
+    if let Some(seed) = matches.value_of("fuzz") {
+        let seed = seed.parse::<u64>().unwrap_or_else(|_| fail(&matches, &format!("Argument {} is not a valid seed.", seed)));
+        let iterations = matches.value_of("fuzz-iterations").map_or(fuzz::DEFAULT_ITERATIONS, |s|
+            s.parse::<u64>().unwrap_or_else(|_| fail(&matches, &format!("Argument {} is not a valid count.", s))));
+        let time_limit = matches.value_of("fuzz-time").map(|s|
+            parse_duration(s).unwrap_or_else(|| fail(&matches, &format!("Argument {} is not a valid duration.", s))));
+        match fuzz::fuzz(seed, iterations, time_limit) {
+            Ok(true)  => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e)    => { writeln!(&mut io::stderr(), "Error: {}", e).unwrap(); std::process::exit(1); }
+        }
+    }
+
+    let pidfile = opts.pidfile.as_ref().map(|path| Pidfile::create(path, opts.max_size).unwrap_or_else(|e| {
+        writeln!(&mut io::stderr(), "Error: could not create the pidfile {}: {}", path.display(), e).unwrap();
+        std::process::exit(1);
+    }));
+    let sigpipe_as_signal = matches.value_of("sigpipe-status") == Some("signal");
+    let result = PipeBuffer::with_options(opts).handle_signals(true).run();
+    drop(pidfile); // removed however the run ended, since exiting skips destructors
+    match result {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            // Downstream stopped reading, which is how pipelines normally end early, so isn't reported
+            std::process::exit(if sigpipe_as_signal { SIGPIPE_STATUS } else { 0 });
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted && signals::received() == Some(libc::SIGTERM) && !signals::forced() => {
+            // Stopped as asked, having written out all that was read, which a service manager should see as a clean stop
+            std::process::exit(0);
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted && signals::received().is_some() => {
+            // Interrupted, or the drain cut short, so exit as a process killed by the signal would be reported
+            std::process::exit(128 + signals::received().unwrap());
+        },
+        Err(e) => {
+            writeln!(&mut io::stderr(), "Error: {}", e).unwrap();
+            let status = match e.kind() {
+                io::ErrorKind::TimedOut if e.get_ref().is_some_and(|inner| inner.is::<NoInput>()) => INPUT_TIMEOUT_STATUS,
+                io::ErrorKind::TimedOut => TIMEOUT_STATUS,
+                _                       => 1
+            };
+            std::process::exit(status);
+        }
+    }
+}
+
+/// Resolves the `Options` from the parsed command line, exiting with an error message
+/// if any are invalid or can't be used together.
+fn options(matches: &ArgMatches) -> Options {
+    let block_size = block_size(matches);
+    if block_size == 0 { fail(matches, "--block-size must be greater than zero."); }
+    let buffer_size = buffer_size(matches, block_size);
+    let block_size  = fit_block_size(matches, block_size, buffer_size);
+    let (initial_size, max_size) = size_bounds(matches, buffer_size);
+    if let Some(problem) = size_var(matches, MAX_SIZE_VAR).and_then(|cap| size_cap_problem(max_size, cap)) {
+        fail(matches, &format!("{}.", problem));
+    }
+    if let Some(problem) = available_memory().and_then(|available| memory_problem(max_size, available)) {
+        if !matches.is_present("force") { fail(matches, &format!("{}; use --force to allocate it anyway.", problem)); }
+        if !matches.is_present("quiet") { writeln!(&mut io::stderr(), "Warning: {}.", problem).unwrap(); }
+    }
+
+    let pace = matches.value_of("pace").map(|s| match s.parse::<u64>() {
+        Ok(millis) => Duration::from_millis(millis),
+        Err(_)     => fail(matches, &format!("Argument {} is not a valid number of milliseconds.", s))
+    });
+
+    if matches.is_present("append") && !matches.is_present("output") {
+        fail(matches, "--append can only be used when writing to a file with --output.");
+    }
+    if matches.is_present("fsync") && !matches.is_present("output") {
+        fail(matches, "--fsync can only be used when writing to a file with --output.");
+    }
+    if matches.is_present("direct") && !matches.is_present("output") {
+        fail(matches, "--direct can only be used when writing to a file with --output.");
+    }
+    if matches.is_present("checkpoint") && !matches.is_present("output") {
+        fail(matches, "--checkpoint can only be used when writing to a file with --output.");
+    }
+
+    let flush_interval = match matches.value_of("flush-interval") {
+        None if matches.is_present("no-flush") => Some(FlushInterval::Never),
+        Some(s) if split_number(&s.to_lowercase()).1 == "m" =>
+            fail(matches, &format!("Argument {} is ambiguous as a flush interval; give megabytes as mb, or a time in s.", s)),
+        Some(s) => Some(parse_flush_interval(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid flush interval.", s)))),
+        None    => None
+    };
+
+    let quiet = matches.is_present("quiet");
+    let mut opts = Options {
+        block_size, initial_size, max_size, pace, flush_interval,
+        flush_on_idle: matches.value_of("flush-on-idle").map(|s| parse_duration(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid duration.", s)))),
+        prefault: matches.is_present("prefault"),
+        delay  : matches.value_of("delay").map(|s| parse_duration(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid duration.", s)))),
+        inputs : matches.values_of("input").map(|vs| vs.map(PathBuf::from).collect()).unwrap_or_default(),
+        interleave: matches.values_of("interleave").map(|vs| vs.map(PathBuf::from).collect()).unwrap_or_default(),
+        listen : matches.value_of("listen").map(|s| s.to_string()),
+        output : matches.value_of("output").map(PathBuf::from),
+        output_prefix: matches.value_of("output-prefix").map(|s| s.to_string()),
+        split  : match size_arg(matches, "split") {
+            Some(0) => fail(matches, "--split must be greater than zero."),
+            size    => size
+        },
+        append : matches.is_present("append"),
+        fsync  : matches.is_present("fsync"),
+        direct : matches.is_present("direct"),
+        checkpoint : matches.value_of("checkpoint").map(PathBuf::from),
+        resume_from: match matches.value_of("checkpoint") {
+            Some(path) if matches.is_present("resume") => Some(checkpoint::load(Path::new(path))
+                .unwrap_or_else(|e| fail(matches, &format!("Could not read the checkpoint {}: {}", path, e)))),
+            _ => None
+        },
+        tees   : matches.values_of("tee").map(|vs| vs.map(PathBuf::from).collect()).unwrap_or_default(),
+        tee_policy: tee::Policy::parse(matches.value_of("tee-policy").unwrap()).unwrap(),
+        tee_buffer: match size_arg(matches, "tee-buffer") {
+            Some(0) => fail(matches, "--tee-buffer must be greater than zero."),
+            size    => size
+        },
+        tee_overflow: tee::Overflow::parse(matches.value_of("tee-overflow").unwrap()).unwrap(),
+        connect: matches.value_of("connect").map(|s| s.to_string()),
+        unix_listen : matches.value_of("unix-listen").map(PathBuf::from),
+        unix_connect: matches.value_of("unix-connect").map(PathBuf::from),
+        discard      : matches.is_present("discard"),
+        prefetch     : matches.is_present("prefetch"),
+        adaptive_block: matches.is_present("adaptive-block"),
+        no_drain_on_close: matches.is_present("no-drain-on-close"),
+        splice       : matches.is_present("splice"),
+        fast_copy    : matches.is_present("fast-copy"),
+        stack_size   : size_arg(matches, "stack-size"),
+        affinity     : matches.value_of("affinity").map(|s| parse_affinity(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid pair of CPUs.", s)))),
+        progress     : if matches.is_present("progress") && !quiet {
+                           let s = matches.value_of("progress");
+                           Some(progress::Format::parse(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid progress format.", s.unwrap()))))
+                       } else { None },
+        progress_interval: {
+            let s = matches.value_of("progress-interval").unwrap();
+            match parse_duration(s) {
+                Some(interval) if interval >= progress::MIN_INTERVAL => interval,
+                Some(_) => fail(matches, &format!("Argument {} is shorter than the minimum progress interval of {:?}.", s, progress::MIN_INTERVAL)),
+                None    => fail(matches, &format!("Argument {} is not a valid duration.", s))
+            }
+        },
+        color        : progress::ColorChoice::parse(matches.value_of("color").unwrap()).unwrap()
+                           .with_no_color(env::var_os(NO_COLOR_VAR).is_some_and(|v| !v.is_empty())),
+        expected_size: size_arg(matches, "size-expected").map(|n| n as u64).or_else(|| inputs_size(matches)),
+        timeout: matches.value_of("timeout").map(|s| parse_duration(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid duration.", s)))),
+        input_timeout: matches.value_of("input-timeout").map(|s| parse_duration(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid duration.", s)))),
+        watchdog: matches.value_of("watchdog").map(|s| match parse_duration(s) {
+            Some(interval) if interval > Duration::new(0, 0) => interval,
+            _ => fail(matches, &format!("Argument {} is not a valid duration.", s))
+        }),
+        stats  : matches.is_present("stats") && !quiet,
+        count_lines: matches.is_present("count-lines") && !quiet,
+        warn_on_full: matches.is_present("warn-on-full") && !quiet,
+        inspect: if matches.is_present("inspect") && !quiet { size_arg(matches, "inspect").or(Some(INSPECT_BYTES)) } else { None },
+        max_write: match size_arg(matches, "max-write") {
+            Some(0) => fail(matches, "--max-write must be greater than zero."),
+            size    => size
+        },
+        record_size: match size_arg(matches, "record-size") {
+            Some(0) => fail(matches, "--record-size must be greater than zero."),
+            size    => size
+        },
+        min_read: match size_arg(matches, "min-read") {
+            Some(0) => fail(matches, "--min-read must be greater than zero."),
+            size    => size
+        },
+        max_bytes: match size_arg(matches, "max-bytes") {
+            Some(0) => fail(matches, "--max-bytes must be greater than zero."),
+            size    => size.map(|n| n as u64)
+        },
+        start_after: matches.value_of("start-after").map(|s| token::parse_token(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid token.", s)))),
+        include_start_token: matches.is_present("include-start-token"),
+        stop_before: matches.value_of("stop-before").map(|s| token::parse_token(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid token.", s)))),
+        header : matches.value_of("header").map(|s| token::parse_token(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not valid bytes.", s)))),
+        trailer: matches.value_of("trailer").map(|s| token::parse_token(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not valid bytes.", s)))),
+        checksum_verify: matches.value_of("checksum-verify").map(|s| parse_checksum(s).unwrap_or_else(|| fail(matches, &format!("Argument {} is not a valid CRC-32.", s)))),
+        quiet,
+        verbosity: if quiet { 0 } else { matches.occurrences_of("verbose") },
+        metrics_file: matches.value_of("metrics-file").map(PathBuf::from),
+        buffer_stats_interval: matches.value_of("buffer-stats-interval").map(|s| match parse_duration(s) {
+            Some(interval) if interval >= progress::MIN_INTERVAL => interval,
+            Some(_) => fail(matches, &format!("Argument {} is shorter than the minimum interval of {:?}.", s, progress::MIN_INTERVAL)),
+            None    => fail(matches, &format!("Argument {} is not a valid duration.", s))
+        }),
+        on_drain    : matches.value_of("on-drain").map(|s| s.to_string()),
+        pidfile     : matches.value_of("pidfile").map(PathBuf::from)
+    };
+    opts.flush_interval = terminal_flush_interval(&opts, io::stdout().is_terminal());
+    if let Some(warning) = fit_block_to_buffer(&mut opts) {
+        if !quiet { writeln!(&mut io::stderr(), "Warning: {}", warning).unwrap(); }
+    }
+    opts
+}
+
+/// Describes the resolved `opts`, one `name: value` line per setting, named after the
+/// option that sets it.  Sizes are given in bytes and durations in milliseconds, so the
+/// output is stable and easily grepped.
+fn describe(opts: &Options) -> String {
+    fn or_none<T, F>(value: &Option<T>, f: F) -> String where F: Fn(&T) -> String {
+        value.as_ref().map(f).unwrap_or_else(|| "none".to_string())
+    }
+    fn millis(d: &Duration) -> String { format!("{}ms", d.as_millis()) }
+    fn token(t: &[u8]) -> String { format!("{:?}", String::from_utf8_lossy(t)) }
+
+    let input = if !opts.inputs.is_empty() {
+        opts.inputs.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+    }
+    else if !opts.interleave.is_empty() {
+        format!("interleave {}", opts.interleave.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", "))
+    }
+    else if let Some(ref address) = opts.listen { format!("tcp:{}", address) }
+    else if let Some(ref path) = opts.unix_listen { format!("unix:{}", path.display()) }
+    else { "stdin".to_string() };
+    let output = if let Some(ref path) = opts.output { path.display().to_string() }
+    else if let (Some(ref prefix), Some(size)) = (&opts.output_prefix, opts.split) { format!("{}000... split every {} bytes", prefix, size) }
+    else if let Some(ref address) = opts.connect { format!("tcp:{}", address) }
+    else if let Some(ref path) = opts.unix_connect { format!("unix:{}", path.display()) }
+    else if opts.discard { "discard".to_string() }
+    else { "stdout".to_string() };
+
+    let settings = vec![
+        ("block-size", opts.block_size.to_string()),
+        ("min-size", opts.initial_size.to_string()),
+        ("max-size", opts.max_size.to_string()),
+        ("prefault", opts.prefault.to_string()),
+        ("pace", or_none(&opts.pace, millis)),
+        ("delay", or_none(&opts.delay, millis)),
+        ("flush-interval", match opts.flush_interval {
+            None                              => "every write".to_string(),
+            Some(FlushInterval::Bytes(bytes)) => format!("{} bytes", bytes),
+            Some(FlushInterval::Time(time))   => millis(&time),
+            Some(FlushInterval::Never)        => "never".to_string()
+        }),
+        ("flush-on-idle", or_none(&opts.flush_on_idle, millis)),
+        ("input", input),
+        ("output", output),
+        ("append", opts.append.to_string()),
+        ("fsync", opts.fsync.to_string()),
+        ("direct", opts.direct.to_string()),
+        ("checkpoint", or_none(&opts.checkpoint, |p| p.display().to_string())),
+        ("resume", or_none(&opts.resume_from, |n| n.to_string())),
+        ("tee", if opts.tees.is_empty() { "none".to_string() } else {
+            opts.tees.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+        }),
+        ("tee-policy", format!("{:?}", opts.tee_policy).to_lowercase()),
+        ("tee-buffer", or_none(&opts.tee_buffer, |n| n.to_string())),
+        ("tee-overflow", format!("{:?}", opts.tee_overflow).to_lowercase()),
+        ("prefetch", opts.prefetch.to_string()),
+        ("adaptive-block", opts.adaptive_block.to_string()),
+        ("no-drain-on-close", opts.no_drain_on_close.to_string()),
+        ("splice", opts.splice.to_string()),
+        ("fast-copy", opts.fast_copy.to_string()),
+        ("stack-size", or_none(&opts.stack_size, |n| n.to_string())),
+        ("affinity", or_none(&opts.affinity, |&(reader, writer)| format!("{},{}", reader, writer))),
+        ("progress", or_none(&opts.progress, |f| match *f {
+            progress::Format::Human => "bar".to_string(),
+            progress::Format::Plain => "plain".to_string(),
+            progress::Format::Json  => "json".to_string()
+        })),
+        ("progress-interval", millis(&opts.progress_interval)),
+        ("color", format!("{:?}", opts.color).to_lowercase()),
+        ("size-expected", or_none(&opts.expected_size, |n| n.to_string())),
+        ("timeout", or_none(&opts.timeout, millis)),
+        ("input-timeout", or_none(&opts.input_timeout, millis)),
+        ("watchdog", or_none(&opts.watchdog, millis)),
+        ("stats", opts.stats.to_string()),
+        ("count-lines", opts.count_lines.to_string()),
+        ("warn-on-full", opts.warn_on_full.to_string()),
+        ("inspect", or_none(&opts.inspect, |n| n.to_string())),
+        ("max-write", or_none(&opts.max_write, |n| n.to_string())),
+        ("record-size", or_none(&opts.record_size, |n| n.to_string())),
+        ("min-read", or_none(&opts.min_read, |n| n.to_string())),
+        ("max-bytes", or_none(&opts.max_bytes, |n| n.to_string())),
+        ("start-after", or_none(&opts.start_after, |t| token(t))),
+        ("include-start-token", opts.include_start_token.to_string()),
+        ("stop-before", or_none(&opts.stop_before, |t| token(t))),
+        ("header", or_none(&opts.header, |t| token(t))),
+        ("trailer", or_none(&opts.trailer, |t| token(t))),
+        ("checksum-verify", or_none(&opts.checksum_verify, |c| format!("{:08x}", c))),
+        ("quiet", opts.quiet.to_string()),
+        ("verbose", opts.verbosity.to_string()),
+        ("metrics-file", or_none(&opts.metrics_file, |p| p.display().to_string())),
+        ("buffer-stats-interval", or_none(&opts.buffer_stats_interval, millis)),
+        ("on-drain", or_none(&opts.on_drain, |c| c.clone())),
+        ("pidfile", or_none(&opts.pidfile, |p| p.display().to_string()))
+    ];
+    settings.iter().map(|&(name, ref value)| format!("{}: {}\n", name, value)).collect()
+}
+
+/// Layers the options in the `--config` file, if one was given, under those given on
+/// the command line in `args`, re-parsing them together.  An option in the file is
+/// ignored if it, or one it is an alternative to (e.g. `--blocks` for `--size`), was
+/// given on the command line.  The buffer and block sizes in `PIPEBUFFER_SIZE` and
+/// `PIPEBUFFER_BLOCK_SIZE` also take precedence over those in the file, so overall the
+/// order of precedence is: command line, environment, config file, then built-in
+/// defaults.  Exits with an error message if the file can't be read or is malformed.
+fn apply_config(matches: ArgMatches<'static>, mut args: Vec<OsString>) -> ArgMatches<'static> {
+    let path = match matches.value_of("config") {
+        Some(path) => path.to_string(),
+        None       => return matches
     };
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| fail(&matches, &format!("Could not read config file {}: {}", path, e)));
+    let entries = config::parse(&text).unwrap_or_else(|e| fail(&matches, &format!("Invalid config file {}: {}", path, e)));
+
+    for (key, value) in entries {
+        if key == "config" { fail(&matches, &format!("Invalid config file {}: config files cannot be nested.", path)); }
+        let alternatives = CONFIG_ALTERNATIVES.iter().find(|group| group.contains(&key.as_str())).cloned().unwrap_or(&[]);
+        let given = matches.occurrences_of(&key) > 0 || alternatives.iter().any(|alt| matches.occurrences_of(alt) > 0);
+        let from_env = ((key == "size" || key == "blocks") && env::var_os(SIZE_VAR).is_some()) ||
+                       (key == "block-size" && env::var_os(BLOCK_SIZE_VAR).is_some());
+        if !given && !from_env { args.extend(value.to_args(&key).into_iter().map(OsString::from)); }
+    }
+
+    app().get_matches_from_safe(args).unwrap_or_else(|e| {
+        let message = e.message.lines().next().unwrap_or("").trim_start_matches("error: ").to_string();
+        fail(&matches, &format!("Invalid config file {}: {}", path, message))
+    })
+}
+
+/// Resolves the block size from the `--block-size` argument if given explicitly, then
+/// the `--low-latency` preset, the `PIPEBUFFER_BLOCK_SIZE` environment variable, and
+/// finally the default value of `--block-size`.
+fn block_size(matches: &ArgMatches) -> usize {
+    if matches.occurrences_of("block-size") == 0 {
+        if matches.is_present("low-latency") { return LOW_LATENCY_BLOCK_SIZE; }
+        if let Some(size) = size_var(matches, BLOCK_SIZE_VAR) { return size; }
+    }
+    size_arg(matches, "block-size").unwrap()
+}
+
+/// Shrinks the block size, unless it was given explicitly or the buffer size was given
+/// in blocks, so that the buffer holds at least `MIN_BLOCKS_PER_BUFFER` blocks; a
+/// buffer that holds only one block can't be filled by the reader while the writer
+/// empties it.
+fn fit_block_size(matches: &ArgMatches, block_size: usize, buffer_size: usize) -> usize {
+    if matches.occurrences_of("block-size") > 0 || matches.is_present("blocks") { return block_size; }
+    cmp::max(1, cmp::min(block_size, buffer_size / MIN_BLOCKS_PER_BUFFER))
+}
+
+/// Returns the memory available to be allocated without swapping, from `MemAvailable`
+/// in `/proc/meminfo`, or `None` if it can't be determined.
+fn available_memory() -> Option<u64> {
+    fs::read_to_string("/proc/meminfo").ok().and_then(|meminfo| parse_mem_available(&meminfo))
+}
+
+/// Parses the `MemAvailable` line, given in kB, out of the contents of `/proc/meminfo`.
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    line.split_whitespace().nth(1).and_then(|kb| kb.parse::<u64>().ok()).map(|kb| kb * 1024)
+}
+
+/// Checks whether a buffer of `size` bytes is too large to be backed by the `available`
+/// memory, i.e. larger than `MEMORY_PCT` percent of it, since allocating it then risks
+/// thrashing, or the process being killed for want of memory, part way through.
+///
+/// # Return
+/// A description of the problem, or `None` if the buffer fits comfortably.
+fn memory_problem(size: usize, available: u64) -> Option<String> {
+    if (size as u64).saturating_mul(100) <= available.saturating_mul(MEMORY_PCT) { return None; }
+    Some(format!("the buffer of {} is more than {}% of the {} of memory available",
+                 progress::format_bytes(size as u64), MEMORY_PCT, progress::format_bytes(available)))
+}
+
+/// Resolves the initial and maximum buffer sizes from `--min-size` and `--max-size`,
+/// each defaulting to `buffer_size`, except that with only `--max-size` the buffer
+/// starts at whichever of the two is smaller.  Exits with an error message, naming
+/// only the flags actually given, if the sizes are not valid.
+fn size_bounds(matches: &ArgMatches, buffer_size: usize) -> (usize, usize) {
+    let min_size = size_arg(matches, "min-size");
+    let max_size = size_arg(matches, "max-size");
+    let initial_size = min_size.unwrap_or_else(|| cmp::min(buffer_size, max_size.unwrap_or(buffer_size)));
+    let max_size = max_size.unwrap_or(buffer_size);
+    if initial_size == 0 {
+        if min_size.is_some() { fail(matches, "--min-size must be greater than zero."); }
+        if matches.is_present("max-size") { fail(matches, "--max-size must be greater than zero."); }
+        fail(matches, "The buffer size must be greater than zero.");
+    }
+    if initial_size > max_size {
+        if matches.is_present("max-size") { fail(matches, "--min-size may not be larger than --max-size."); }
+        fail(matches, &format!("--min-size may not be larger than the buffer size of {} bytes.", buffer_size));
+    }
+    (initial_size, max_size)
+}
+
+/// Resolves the buffer size from, in order of precedence: the `--blocks` argument
+/// (which clap ensures is not given along with `--size`) multiplied by the block size,
+/// the `--size` argument if given explicitly, the `--low-latency` preset, the
+/// `PIPEBUFFER_SIZE` environment variable, and the default value of `--size`.  Exits
+/// with an error message if the value used is not valid.
+fn buffer_size(matches: &ArgMatches, block_size: usize) -> usize {
+    if let Some(s) = matches.value_of("blocks") {
+        return match s.parse::<usize>().ok().and_then(|n| n.checked_mul(block_size)) {
+            Some(size) if size > 0 => size,
+            _ => fail(matches, &format!("Argument {} is not a valid number of blocks.", s))
+        };
+    }
+    if matches.occurrences_of("size") == 0 {
+        if matches.is_present("low-latency") { return LOW_LATENCY_SIZE; }
+        if let Some(size) = size_var(matches, SIZE_VAR) { return size; }
+    }
+    size_arg(matches, "size").unwrap()
+}
+
+/// Parses the size in the environment variable `name`, if it is set, exiting with an
+/// error message if it is not a valid size.
+fn size_var(matches: &ArgMatches, name: &str) -> Option<usize> {
+    env::var(name).ok().map(|s| parse_memory(&s).unwrap_or_else(|| fail(matches, &format!("{} value {} is not a valid size.", name, s))))
+}
+
+/// Checks the buffer `size`, however it was arrived at, against the administrative `cap`
+/// in `PIPEBUFFER_MAX_SIZE`, which unlike the check on available memory can't be forced.
+///
+/// # Return
+/// A description of the problem, or `None` if the buffer is within the cap.
+fn size_cap_problem(size: usize, cap: usize) -> Option<String> {
+    if size <= cap { return None; }
+    Some(format!("The buffer size of {} bytes exceeds the limit of {} bytes set by {}", size, cap, MAX_SIZE_VAR))
+}
+
+/// Fetches the named argument, if present, and parses it with `parse_memory()`. Exits
+/// with an error message if the value is present but not a valid size.
+fn size_arg(matches: &ArgMatches, name: &str) -> Option<usize> {
+    matches.value_of(name).map(|s| match parse_memory(s) {
+        Some(size) => size,
+        None       => fail(matches, &format!("Argument {} is not a valid size.", s))
+    })
+}
+
+/// Totals the sizes of the `--input` or `--interleave` files, if any are given and all
+/// are regular files whose size can be read, for use as the expected size of the
+/// transfer.
+fn inputs_size(matches: &ArgMatches) -> Option<u64> {
+    let mut total = 0;
+    for path in matches.values_of("input").or_else(|| matches.values_of("interleave"))? {
+        match fs::metadata(path) {
+            Ok(ref meta) if meta.is_file() => total += meta.len(),
+            _ => return None
+        }
+    }
+    Some(total)
+}
 
-    run(buffer_size);
+/// Prints the usage and the given error message, then exits the program.
+fn fail(matches: &ArgMatches, message: &str) -> ! {
+    println!("{}", matches.usage());
+    println!("Error: {}", message);
+    std::process::exit(1)
 }
 
 /// Parses memory unit values from strings. Specifically accepts any value
 /// that is an integer number followed optionally by `k/kb/m/mb/g/gb/p/pb` in
 /// either upper or lower case. If the value can be parsed returns a 
-/// `Some(bytes)`, otherwise returns a None.
+/// `Some(bytes)`, otherwise returns a None, including for a size too large to hold.
 fn parse_memory(s: &str) -> Option<usize> {
-    match Regex::new("^([0-9]+)([kmgp])?b?$").unwrap().captures(&s.to_lowercase()) {
-        None => None,
-        Some(groups) => {
-            let num : Option<usize> = groups.at(1).unwrap().parse().ok();
-            let exp = match groups.at(2) {
-                Some("k") => 1,
-                Some("m") => 2,
-                Some("g") => 3,
-                Some("p") => 4,
-                _         => 0
-            };
-            num.map(|n| n * (1024 as usize).pow(exp))
-        }
+    let lower = s.to_lowercase();
+    let (digits, suffix) = split_number(&lower);
+    let exp = match suffix {
+        ""  | "b"  => 0,
+        "k" | "kb" => 1,
+        "m" | "mb" => 2,
+        "g" | "gb" => 3,
+        "p" | "pb" => 4,
+        _          => return None
+    };
+    digits.parse::<usize>().ok().and_then(|n| n.checked_mul(1024_usize.pow(exp)))
+}
+
+/// Parses a duration from a whole number with an optional `ms`, `s`, `m` or `h` suffix,
+/// in either upper or lower case, taken to be seconds if there is no suffix.  If the
+/// value can be parsed returns a `Some(duration)`, otherwise returns a None.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let lower = s.to_lowercase();
+    let (digits, suffix) = split_number(&lower);
+    let num : Option<u64> = digits.parse().ok();
+    match suffix {
+        ""  | "s" => num.map(Duration::from_secs),
+        "ms"      => num.map(Duration::from_millis),
+        "m"       => num.and_then(|n| n.checked_mul(60)).map(Duration::from_secs),
+        "h"       => num.and_then(|n| n.checked_mul(60 * 60)).map(Duration::from_secs),
+        _         => None
     }
 }
 
-/// Funtion that uses a pair of threads to move data from Stdin to Stdout
-/// with a RungBuffer in the middle.
-fn run(buffer_size: usize) {
-    // The shared ring buffer and the thread handles
-    let ring = Arc::new(Mutex::new(RingBuffer::new(buffer_size)));
-    let cond = Arc::new(Condvar::new());
-
-    // Setup the writer thread
-    let writer_handle = {
-        let ring = ring.clone();
-        let cond = cond.clone();
-        thread::spawn(move || {
-            let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
-            let mut output = io::stdout();
-            'main_loop : loop {
-                let n = {
-                    // Lock the buffer, but wait on it if it's empty
-                    let mut buffer = ring.lock().unwrap();
-                    while buffer.is_empty() {
-                        if buffer.is_closed() { break 'main_loop; }
-                        else { buffer = cond.wait(buffer).unwrap(); }
-                    }
-
-                    // Fetch from the buffer, and notify writers if we went from full to not full
-                    let was_full = buffer.is_full();
-                    let n = buffer.get(&mut bytes);
-                    if was_full && n > 0 { cond.notify_one(); }
-                    n
-                }; // lock released here
-
-                // Write the data, if any, to stdout
-                let mut start = 0;
-                while start < n { start += output.write(&bytes[start..n]).unwrap(); }
-                output.flush().unwrap();
-            }
-        })
-    };
+/// Splits `s` after the decimal digits it starts with, if any, e.g. `64k` into `64`
+/// and `k`.
+fn split_number(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
 
-    // Setup this thread as the reader thread
-    let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
-    let mut input = io::stdin();
-    loop {
-        let n = input.read(&mut bytes).unwrap();
-        let mut buffer = ring.lock().unwrap();
-        
-        if n == 0 { // input stream is closed
-            buffer.close();
-            cond.notify_one();
-            break; 
-        }
-        else {
-            let mut start = 0;
-            while start < n {
-                while buffer.is_full() {
-                    buffer = cond.wait(buffer).unwrap();
-                }
-                let was_empty = buffer.is_empty();
-                start += buffer.put(&bytes[start..n]);
-                if was_empty { cond.notify_one(); }
-             }
-        }
+/// Parses a flush interval, which is either a time with an `ms`, `s` or `h` suffix as
+/// accepted by `parse_duration()`, or otherwise a number of bytes as accepted by
+/// `parse_memory()`.  A bare `m` could be either minutes or megabytes, so is refused;
+/// megabytes must be given as `mb`.
+fn parse_flush_interval(s: &str) -> Option<FlushInterval> {
+    let lower = s.to_lowercase();
+    match split_number(&lower).1 {
+        "m"              => None,
+        "ms" | "s" | "h" => parse_duration(s).map(FlushInterval::Time),
+        _                => parse_memory(s).map(FlushInterval::Bytes)
     }
-    
-    writeln!(&mut io::stderr(), "Attempting to join on the writer.").unwrap();
-    writer_handle.join().unwrap();
+}
+
+/// Parses a pair of CPU numbers separated by a comma, e.g. `0,1`.
+fn parse_affinity(s: &str) -> Option<(usize, usize)> {
+    let mut cpus = s.split(',').map(|cpu| cpu.trim().parse::<usize>());
+    match (cpus.next(), cpus.next(), cpus.next()) {
+        (Some(Ok(reader)), Some(Ok(writer)), None) => Some((reader, writer)),
+        _                                          => None
+    }
+}
+
+/// Parses a CRC-32 given as up to eight hex digits, optionally prefixed with `0x`.
+fn parse_checksum(s: &str) -> Option<u32> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    if digits.is_empty() || digits.len() > 8 || !digits.chars().all(|c| c.is_ascii_hexdigit()) { return None; }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Returns the flush interval to use, which is none, flushing every write so that it's
+/// seen straight away, when writing to stdout and it is a terminal, and otherwise the
+/// interval given.
+fn terminal_flush_interval(opts: &Options, stdout_is_terminal: bool) -> Option<FlushInterval> {
+    if stdout_is_terminal && writes_to_stdout(opts) { None } else { opts.flush_interval }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -161,15 +984,15 @@ fn run(buffer_size: usize) {
 ////////////////////////////////////////////////////////////////////////////////
 
 #[test]
-fn test_parse_mem_bytes() -> () {
+fn test_parse_mem_bytes() {
     assert!(parse_memory("1") == Some::<usize>(1));
     assert!(parse_memory("1024") == Some::<usize>(1024));
     assert!(parse_memory("1000000000") == Some::<usize>(1000000000));
-    assert!(parse_memory("10000000000000000000000000000") == None);
+    assert!(parse_memory("10000000000000000000000000000").is_none());
 }
 
 #[test]
-fn test_parse_mem_suffixed() -> () {
+fn test_parse_mem_suffixed() {
     assert!(parse_memory("1k")      == Some::<usize>(1024));
     assert!(parse_memory("99k")     == Some::<usize>(99 * 1024));
     assert!(parse_memory("99kb")    == Some::<usize>(99 * 1024));
@@ -188,15 +1011,246 @@ fn test_parse_mem_suffixed() -> () {
 }
 
 #[test]
-fn test_parse_mem_fails() -> () {
-    assert!(parse_memory("") == None);
-    assert!(parse_memory("k") == None);
-    assert!(parse_memory("kb") == None);
-    assert!(parse_memory("foo") == None);
-    assert!(parse_memory("not1024m") == None);
-    assert!(parse_memory("-12g") == None);
-    assert!(parse_memory("12x") == None);
-    assert!(parse_memory("7y") == None);
-    assert!(parse_memory("1024x1024") == None);
-    assert!(parse_memory("1024mi") == None);
+fn test_parse_mem_fails() {
+    assert!(parse_memory("").is_none());
+    assert!(parse_memory("k").is_none());
+    assert!(parse_memory("kb").is_none());
+    assert!(parse_memory("foo").is_none());
+    assert!(parse_memory("not1024m").is_none());
+    assert!(parse_memory("-12g").is_none());
+    assert!(parse_memory("12x").is_none());
+    assert!(parse_memory("7y").is_none());
+    assert!(parse_memory("1024x1024").is_none());
+    assert!(parse_memory("1024mi").is_none());
+    assert!(parse_memory("+12").is_none());
+    assert!(parse_memory("12 k").is_none());
+    assert!(parse_memory("99999999999999p").is_none());
+}
+
+// Serializes the tests that modify the environment
+#[cfg(test)]
+static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[test]
+fn test_buffer_size_precedence() {
+    let _env = ENV_LOCK.lock().unwrap();
+    env::remove_var(SIZE_VAR);
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer"]), 1024) == 256 * 1024 * 1024);
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer", "--size", "2m"]), 1024) == 2 * 1024 * 1024);
+
+    env::set_var(SIZE_VAR, "16k");
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer"]), 1024) == 16 * 1024);
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer", "--size", "2m"]), 1024) == 2 * 1024 * 1024);
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer", "--blocks", "8"]), 1024) == 8 * 1024);
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer", "--low-latency"]), 1024) == LOW_LATENCY_SIZE);
+    assert!(buffer_size(&app().get_matches_from(vec!["pipebuffer", "--low-latency", "-s", "2m"]), 1024) == 2 * 1024 * 1024);
+    env::remove_var(SIZE_VAR);
+}
+
+#[test]
+fn test_block_size_precedence() {
+    let _env = ENV_LOCK.lock().unwrap();
+    env::remove_var(BLOCK_SIZE_VAR);
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer"])) == 64 * 1024);
+
+    env::set_var(BLOCK_SIZE_VAR, "1m");
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer"])) == 1024 * 1024);
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer", "--block-size", "4k"])) == 4 * 1024);
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer", "--low-latency"])) == LOW_LATENCY_BLOCK_SIZE);
+
+    // Like the default, shrunk to fit a small buffer, where one given explicitly isn't
+    let opts = options(&app().get_matches_from(vec!["pipebuffer", "--size", "1m"]));
+    assert!(opts.block_size == 256 * 1024);
+    env::remove_var(BLOCK_SIZE_VAR);
+}
+
+#[test]
+fn test_size_cap() {
+    let _env = ENV_LOCK.lock().unwrap();
+    env::remove_var(SIZE_VAR);
+    assert!(size_cap_problem(1024 * 1024, 1024 * 1024).is_none());
+    assert!(size_cap_problem(1024 * 1024 + 1, 1024 * 1024).unwrap() ==
+            "The buffer size of 1048577 bytes exceeds the limit of 1048576 bytes set by PIPEBUFFER_MAX_SIZE");
+
+    // Sizes within the cap are allowed from any source
+    env::set_var(MAX_SIZE_VAR, "4m");
+    assert!(options(&app().get_matches_from(vec!["pipebuffer", "--size", "4m"])).max_size == 4 * 1024 * 1024);
+    assert!(options(&app().get_matches_from(vec!["pipebuffer", "--blocks", "2", "--block-size", "1m"])).max_size == 2 * 1024 * 1024);
+    env::remove_var(MAX_SIZE_VAR);
+}
+
+#[test]
+fn test_apply_config() {
+    let path = env::temp_dir().join(format!("pipebuffer-test-{}.toml", std::process::id()));
+    fs::write(&path, "size = \"2m\"\nblock-size = \"1k\"\nstats = true\n").unwrap();
+    let parse = |args: Vec<&str>| {
+        let mut args: Vec<OsString> = args.into_iter().map(OsString::from).collect();
+        args.insert(1, OsString::from("--config"));
+        args.insert(2, path.clone().into_os_string());
+        apply_config(app().get_matches_from(args.clone()), args)
+    };
+    let _env = ENV_LOCK.lock().unwrap();
+    env::remove_var(SIZE_VAR);
+
+    // Values are taken from the file
+    let matches = parse(vec!["pipebuffer"]);
+    assert!(buffer_size(&matches, 1024) == 2 * 1024 * 1024);
+    assert!(block_size(&matches) == 1024);
+    assert!(matches.is_present("stats"));
+
+    // But overridden by the command line, including by alternatives to them
+    let matches = parse(vec!["pipebuffer", "--size", "4m", "--block-size", "2k"]);
+    assert!(buffer_size(&matches, 2048) == 4 * 1024 * 1024);
+    assert!(block_size(&matches) == 2048);
+    assert!(buffer_size(&parse(vec!["pipebuffer", "--blocks", "3"]), 1024) == 3 * 1024);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_size_bounds() {
+    let _env = ENV_LOCK.lock().unwrap();
+    let bounds = |args: Vec<&str>| size_bounds(&app().get_matches_from(args), 4 * 1024 * 1024);
+    assert!(bounds(vec!["pipebuffer"]) == (4 * 1024 * 1024, 4 * 1024 * 1024));
+    assert!(bounds(vec!["pipebuffer", "--max-size", "1m"]) == (1024 * 1024, 1024 * 1024));
+    assert!(bounds(vec!["pipebuffer", "--max-size", "8m"]) == (4 * 1024 * 1024, 8 * 1024 * 1024));
+    assert!(bounds(vec!["pipebuffer", "--min-size", "1m"]) == (1024 * 1024, 4 * 1024 * 1024));
+    assert!(bounds(vec!["pipebuffer", "--min-size", "1m", "--max-size", "2m"]) == (1024 * 1024, 2 * 1024 * 1024));
+}
+
+#[test]
+fn test_low_latency_block_size() {
+    let _env = ENV_LOCK.lock().unwrap();
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer"])) == 64 * 1024);
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer", "--low-latency"])) == LOW_LATENCY_BLOCK_SIZE);
+    assert!(block_size(&app().get_matches_from(vec!["pipebuffer", "--low-latency", "--block-size", "1k"])) == 1024);
+}
+
+#[test]
+fn test_fit_block_size() {
+    let _env = ENV_LOCK.lock().unwrap();
+    let fit = |args: Vec<&str>, buffer_size| {
+        let matches = app().get_matches_from(args);
+        fit_block_size(&matches, block_size(&matches), buffer_size)
+    };
+    assert!(fit(vec!["pipebuffer"], 256 * 1024 * 1024) == 64 * 1024);
+    assert!(fit(vec!["pipebuffer", "-s", "64k"], 64 * 1024) == 16 * 1024);
+    assert!(fit(vec!["pipebuffer", "-s", "2"], 2) == 1);
+    assert!(fit(vec!["pipebuffer", "-s", "64k", "--block-size", "64k"], 64 * 1024) == 64 * 1024);
+    assert!(fit(vec!["pipebuffer", "--blocks", "2"], 128 * 1024) == 64 * 1024);
+}
+
+#[test]
+fn test_blocks_conflicts_with_size() {
+    assert!(app().get_matches_from_safe(vec!["pipebuffer", "--blocks", "8"]).is_ok());
+    assert!(app().get_matches_from_safe(vec!["pipebuffer", "--blocks", "8", "--size", "1m"]).is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert!(parse_duration("30")    == Some(Duration::from_secs(30)));
+    assert!(parse_duration("30s")   == Some(Duration::from_secs(30)));
+    assert!(parse_duration("250ms") == Some(Duration::from_millis(250)));
+    assert!(parse_duration("5m")    == Some(Duration::from_secs(5 * 60)));
+    assert!(parse_duration("2H")    == Some(Duration::from_secs(2 * 60 * 60)));
+    assert!(parse_duration("").is_none());
+    assert!(parse_duration("s").is_none());
+    assert!(parse_duration("1.5s").is_none());
+    assert!(parse_duration("-1s").is_none());
+    assert!(parse_duration("3d").is_none());
+    assert!(parse_duration("5mh").is_none());
+    assert!(parse_duration("99999999999999999h").is_none());
+}
+
+#[test]
+fn test_parse_flush_interval() {
+    assert!(parse_flush_interval("500ms") == Some(FlushInterval::Time(Duration::from_millis(500))));
+    assert!(parse_flush_interval("2s")    == Some(FlushInterval::Time(Duration::from_secs(2))));
+    assert!(parse_flush_interval("2S")    == Some(FlushInterval::Time(Duration::from_secs(2))));
+    assert!(parse_flush_interval("4096")  == Some(FlushInterval::Bytes(4096)));
+    assert!(parse_flush_interval("1h")    == Some(FlushInterval::Time(Duration::from_secs(60 * 60))));
+    assert!(parse_flush_interval("4mb")   == Some(FlushInterval::Bytes(4 * 1024 * 1024)));
+    assert!(parse_flush_interval("4MB")   == Some(FlushInterval::Bytes(4 * 1024 * 1024)));
+    assert!(parse_flush_interval("4k")    == Some(FlushInterval::Bytes(4 * 1024)));
+    assert!(parse_flush_interval("4m").is_none());
+    assert!(parse_flush_interval("ms").is_none());
+    assert!(parse_flush_interval("xs").is_none());
+    assert!(parse_flush_interval("-1s").is_none());
+    assert!(parse_flush_interval("+5s").is_none());
+}
+
+#[cfg(test)]
+fn test_options() -> Options {
+    Options { block_size: 1024, initial_size: 1024, max_size: 1024, ..Options::default() }
+}
+
+#[test]
+fn test_describe() {
+    let _lock = ENV_LOCK.lock().unwrap();
+    let args = vec!["pipebuffer", "--dry-run", "-s", "1m", "--block-size", "2m", "--pace", "20", "--start-after", "0x0a00",
+                    "--timeout", "1500ms", "--connect", "localhost:9000", "--tee", "a", "--tee", "b", "--affinity", "0,1"];
+    let description = describe(&options(&app().get_matches_from(args)));
+    let lines: Vec<&str> = description.lines().collect();
+    for expected in ["block-size: 1048576", "min-size: 1048576", "max-size: 1048576", "pace: 20ms", "delay: none",
+                     "input: stdin", "output: tcp:localhost:9000", "tee: a, b", "tee-policy: abort", "tee-buffer: none", "affinity: 0,1",
+                     "timeout: 1500ms", "start-after: \"\\n\\0\"", "stop-before: none", "verbose: 0"].iter() {
+        assert!(lines.contains(expected), "missing {}", expected);
+    }
+    assert!(lines.iter().all(|line| line.contains(": ")));
+}
+
+#[test]
+fn test_memory_problem() {
+    let meminfo = "MemTotal:        8052300 kB\nMemFree:          512000 kB\nMemAvailable:    4000000 kB\n";
+    assert!(parse_mem_available(meminfo) == Some(4000000 * 1024));
+    assert!(parse_mem_available("MemTotal: 8052300 kB\n").is_none());
+
+    let gib = 1024 * 1024 * 1024;
+    assert!(memory_problem(256 * 1024 * 1024, 8 * gib).is_none());
+    assert!(memory_problem(6 * gib as usize, 8 * gib).is_none());
+    assert!(memory_problem(100 * gib as usize, 8 * gib).unwrap() == "the buffer of 100.0 GiB is more than 80% of the 8.0 GiB of memory available");
+}
+
+#[test]
+fn test_flush_interval_kept_unless_writing_to_a_terminal() {
+    let interval = Some(FlushInterval::Bytes(4 * 1024 * 1024));
+    let to_stdout = Options { flush_interval: interval, ..test_options() };
+    assert!(terminal_flush_interval(&to_stdout, true).is_none());
+    assert!(terminal_flush_interval(&to_stdout, false) == interval);
+
+    // Whatever stdout is, it doesn't matter when writing elsewhere
+    let to_file = Options { output: Some(PathBuf::from("out")), ..to_stdout.clone() };
+    let to_socket = Options { connect: Some("localhost:9000".to_string()), ..to_stdout.clone() };
+    assert!(terminal_flush_interval(&to_file, true) == interval);
+    assert!(terminal_flush_interval(&to_socket, true) == interval);
+
+    let _lock = ENV_LOCK.lock().unwrap();
+    let opts = options(&app().get_matches_from(vec!["pipebuffer", "--flush-interval", "4mb", "--output", "out"]));
+    assert!(opts.flush_interval == interval);
+
+    // Also with --no-flush, which flushes only at EOF when writing to a file
+    let opts = options(&app().get_matches_from(vec!["pipebuffer", "--no-flush", "--output", "out"]));
+    assert!(terminal_flush_interval(&opts, true) == Some(FlushInterval::Never));
+}
+
+#[test]
+fn test_output_conflicts_with_connect() {
+    assert!(app().get_matches_from_safe(vec!["pipebuffer", "-o", "out", "--append"]).is_ok());
+    assert!(app().get_matches_from_safe(vec!["pipebuffer", "-o", "out", "--connect", "localhost:9"]).is_err());
+}
+
+#[test]
+fn test_parse_affinity() {
+    assert!(parse_affinity("0,1") == Some((0, 1)));
+    assert!(parse_affinity("3, 2") == Some((3, 2)));
+    assert!(parse_affinity("0").is_none());
+    assert!(parse_affinity("0,1,2").is_none());
+    assert!(parse_affinity("a,b").is_none());
+}
+
+#[test]
+fn test_parse_checksum() {
+    assert!(parse_checksum("cbf43926") == Some(0xCBF4_3926));
+    assert!(parse_checksum("0xCBF43926") == Some(0xCBF4_3926));
+    assert!(parse_checksum("1f") == Some(0x1F));
+    for s in ["", "0x", "cbf439260", "xyz", "+1f"].iter() { assert!(parse_checksum(s).is_none()); }
 }
@@ -0,0 +1,199 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! An asynchronous counterpart to the program's reader and writer threads, for use
+//! inside a tokio application: a single future that reads from an `AsyncRead` into a
+//! `RingBuffer` and writes from it to an `AsyncWrite`, so a stream can be buffered
+//! without dedicating two threads to it.  Only built with the `async` feature.
+
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use ringbuffer::RingBuffer;
+
+/// A future that copies everything from `input` to `output` through a ring buffer,
+/// resolving to the number of bytes copied once the input has reached EOF and all of
+/// it has been written and the output flushed.  Created with `buffer()`.
+///
+/// Reading and writing are driven from the same task: whenever the future is polled
+/// it reads while there is room in the buffer and writes while there is anything in
+/// it, so a slow output doesn't hold up the input until the buffer fills, and vice
+/// versa, just as with the program's threads.  Each read and write moves at most one
+/// block, and unlike the program the output is only flushed at the end.
+pub struct Buffered<R, W> {
+    input  : R,
+    output : W,
+    ring   : RingBuffer<u8>,
+    read   : Vec<u8>,
+    write  : Vec<u8>,
+    start  : usize,
+    end    : usize,
+    eof    : bool,
+    written: u64
+}
+
+/// Creates a future that buffers `input` to `output` through a buffer of `size` bytes,
+/// reading and writing in blocks of up to `block_size` bytes.  The input and output
+/// are taken by value, but since `&mut` references to them are also readers and
+/// writers they may be borrowed instead.
+///
+/// # Panics
+/// Will panic if `size` or `block_size` is zero.
+pub fn buffer<R, W>(input: R, output: W, size: usize, block_size: usize) -> Buffered<R, W>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    assert!(size > 0 && block_size > 0, "size and block_size must be greater than zero");
+    Buffered {
+        input, output,
+        ring   : RingBuffer::new(size),
+        read   : vec![0u8; block_size],
+        write  : vec![0u8; block_size],
+        start  : 0,
+        end    : 0,
+        eof    : false,
+        written: 0
+    }
+}
+
+impl<R, W> Future for Buffered<R, W> where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    type Output = io::Result<u64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            let mut progressed = false;
+
+            // Read as much as there is room for, so it can all be put in the buffer
+            let room = this.ring.capacity() - this.ring.len();
+            if !this.eof && room > 0 {
+                let length = cmp::min(room, this.read.len());
+                let mut buf = ReadBuf::new(&mut this.read[0..length]);
+                if let Poll::Ready(result) = Pin::new(&mut this.input).poll_read(cx, &mut buf) {
+                    result?;
+                    let n = buf.filled().len();
+                    if n == 0 { this.eof = true; } else { this.ring.put_all(&this.read[0..n]); }
+                    progressed = true;
+                }
+            }
+
+            // Write what's left of the last block got from the buffer, or else get the next
+            if this.start == this.end {
+                this.start = 0;
+                this.end   = this.ring.get(&mut this.write);
+            }
+            if this.start < this.end {
+                if let Poll::Ready(result) = Pin::new(&mut this.output).poll_write(cx, &this.write[this.start..this.end]) {
+                    let n = result?;
+                    if n == 0 { return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "the output accepted no more data"))); }
+                    this.start   += n;
+                    this.written += n as u64;
+                    progressed = true;
+                }
+            }
+            else if this.eof {
+                return Pin::new(&mut this.output).poll_flush(cx).map(|result| result.map(|_| this.written));
+            }
+
+            // Anything that couldn't proceed has arranged for the task to be woken when it can
+            if !progressed { return Poll::Pending; }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+/// Wraps a reader or writer so that every other poll is `Pending`, to exercise waiting.
+#[cfg(test)]
+struct Hesitant<T> { inner: T, ready: bool }
+
+#[cfg(test)]
+impl<T> Hesitant<T> {
+    fn new(inner: T) -> Hesitant<T> { Hesitant { inner, ready: false } }
+
+    /// Returns `Pending`, having asked to be polled again, on every other call.
+    fn hesitate(&mut self, cx: &mut Context) -> bool {
+        self.ready = !self.ready;
+        if !self.ready { cx.waker().wake_by_ref(); }
+        !self.ready
+    }
+}
+
+#[cfg(test)]
+impl<T: AsyncRead + Unpin> AsyncRead for Hesitant<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.hesitate(cx) { return Poll::Pending; }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+impl<T: AsyncWrite + Unpin> AsyncWrite for Hesitant<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.hesitate(cx) { return Poll::Pending; }
+        let length = cmp::min(buf.len(), 7); // and only ever accept a little at a time
+        Pin::new(&mut this.inner).poll_write(cx, &buf[0..length])
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+fn block_on<F: Future>(future: F) -> F::Output {
+    ::tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+}
+
+#[test]
+fn test_round_trip() {
+    let data = super::test_data(100000);
+    let mut output = Vec::new();
+    let n = block_on(buffer(&data[..], &mut output, 1000, 64)).unwrap();
+    assert!(n == data.len() as u64);
+    assert!(output == data);
+}
+
+#[test]
+fn test_round_trip_with_waiting() {
+    let data = super::test_data(10000);
+    let mut output = Hesitant::new(Vec::new());
+    let n = block_on(buffer(Hesitant::new(&data[..]), &mut output, 100, 30)).unwrap();
+    assert!(n == data.len() as u64);
+    assert!(output.inner == data);
+}
+
+#[test]
+fn test_empty_input() {
+    let mut output = Vec::new();
+    assert!(block_on(buffer(&b""[..], &mut output, 10, 10)).unwrap() == 0);
+    assert!(output.is_empty());
+}
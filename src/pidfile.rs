@@ -0,0 +1,112 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! A module implementing `--pidfile`, which records the process ID and the buffer size
+//! in a file for the duration of the run, so that other tools can find and signal it.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A pidfile, holding the process ID and the buffer size in bytes on a line each,
+/// which is removed when this is dropped.
+pub struct Pidfile {
+    path: PathBuf
+}
+
+impl Pidfile {
+    /// Creates the pidfile at `path` for this process and a buffer of `size` bytes.  A
+    /// file already there is replaced if the process it names is no longer running, or
+    /// it names none, and otherwise is left alone and an `AlreadyExists` error returned.
+    pub fn create(path: &Path, size: usize) -> io::Result<Pidfile> {
+        let contents = format!("{}\n{}\n", std::process::id(), size);
+        let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if let Some(pid) = running_pid(path) {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} belongs to running process {}", path.display(), pid)));
+                }
+                fs::remove_file(path)?;
+                fs::OpenOptions::new().write(true).create_new(true).open(path)?
+            },
+            result => result?
+        };
+        file.write_all(contents.as_bytes())?;
+        Ok(Pidfile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the process ID in the pidfile at `path` if it names a process that is running.
+fn running_pid(path: &Path) -> Option<i32> {
+    let text = fs::read_to_string(path).ok()?;
+    let pid = text.lines().next()?.trim().parse::<i32>().ok().filter(|pid| *pid > 0)?;
+    let alive = unsafe { libc::kill(pid, 0) == 0 } || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    if alive { Some(pid) } else { None }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pipebuffer-test-{}-{}.pid", std::process::id(), name))
+}
+
+#[test]
+fn test_pidfile_created_and_removed() {
+    let path = temp_path("created");
+    {
+        let _pidfile = Pidfile::create(&path, 1048576).unwrap();
+        assert!(fs::read_to_string(&path).unwrap() == format!("{}\n1048576\n", std::process::id()));
+    }
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_pidfile_already_exists() {
+    // One naming a running process, this one, is left alone
+    let path = temp_path("running");
+    fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+    assert!(Pidfile::create(&path, 1024).err().unwrap().kind() == io::ErrorKind::AlreadyExists);
+    assert!(path.exists());
+    fs::remove_file(&path).unwrap();
+
+    // A stale one, naming a process that has exited, or garbage, is replaced
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let stale = child.id();
+    child.wait().unwrap();
+    for contents in [format!("{}\n4096\n", stale), "garbage".to_string()].iter() {
+        fs::write(&path, contents).unwrap();
+        let pidfile = Pidfile::create(&path, 1024).unwrap();
+        assert!(fs::read_to_string(&path).unwrap() == format!("{}\n1024\n", std::process::id()));
+        drop(pidfile);
+    }
+}
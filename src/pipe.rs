@@ -0,0 +1,2457 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! The pipeline the program runs, for use from Rust code: a reader and a writer thread
+//! either side of a `BlockingRingBuffer`, copying a source to a sink.  A `PipeBuffer`
+//! runs it, configured either with its builder methods or wholesale with `Options`,
+//! which is how the program sets it up from its command line.
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{IoSlice,Read,Seek,SeekFrom,Write};
+use std::fs;
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use blocking::BlockingRingBuffer;
+use checksum::Crc32;
+use concat::ConcatReader;
+use direct::DirectFile;
+use progress::MetricsSink;
+use tee::{BufferedTee, MultiWriter, Tee};
+use token::Matcher;
+use adaptive::AdaptiveBlock;
+use hexdump::Hexdump;
+use watchdog::{State, Watchdog};
+use split::SplitFiles;
+use interleave::InterleaveReader;
+use checkpoint::Checkpoint;
+use coalesce::Coalescing;
+#[cfg(target_os = "linux")]
+use {fastcopy, splice};
+use {progress, signals, split, tee, watchdog};
+
+/// The buffer size used unless told otherwise, as for the program.
+pub const DEFAULT_SIZE: usize = 256 * 1024 * 1024;
+
+/// The block size used unless told otherwise, as for the program.
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+// The exit status when --watchdog finds the transfer hung: EX_SOFTWARE, an internal error
+const WATCHDOG_STATUS: i32 = 70;
+
+// The longest --min-read holds back bytes gathered from the input waiting for more
+const MIN_READ_WAIT: Duration = Duration::from_millis(100);
+
+// How often the main thread checks for shutdown while waiting for the writer to finish
+const JOIN_POLL: Duration = Duration::from_millis(50);
+
+// How long the reader waits before reading again from an input in non-blocking mode that
+// had nothing to read
+const WOULD_BLOCK_WAIT: Duration = Duration::from_millis(10);
+
+/// A closure given each chunk read, before it is buffered; see `PipeBuffer::tap()`.
+pub type Tap = Box<dyn FnMut(&[u8]) + Send>;
+
+/// Statistics on a transfer made by `PipeBuffer::run()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    /// The number of bytes copied from the source to the sink.
+    pub bytes           : u64,
+    /// The most bytes that were in the buffer at once.
+    pub high_water_mark : usize,
+    /// The capacity of the buffer at the end of the transfer.
+    pub capacity        : usize,
+    /// The time the reader spent blocked on a full buffer.
+    pub blocked_on_full : Duration,
+    /// The time the writer spent blocked on an empty buffer.
+    pub blocked_on_empty: Duration,
+    /// The number of bytes read but discarded rather than written, with
+    /// `no_drain_on_close`.
+    pub discarded       : u64,
+    /// The time the transfer took.
+    pub elapsed         : Duration
+}
+
+/// Copies everything from a source to a sink through a buffer, reading on one thread
+/// and writing on another, so that a slow sink doesn't hold up the source until the
+/// buffer fills, and vice versa.  Configured with builder methods, or with `Options`
+/// for everything the program can do, and started with `run()`.  The source and sink
+/// default to those the `Options` name, so to stdin and stdout.
+///
+/// ```
+/// use std::io;
+/// use std::io::{Cursor, Write};
+/// use std::sync::{Arc, Mutex};
+/// use pipebuffer::pipe::PipeBuffer;
+///
+/// // The sink is moved to the writer thread, so collect what it is given somewhere shared
+/// struct Shared(Arc<Mutex<Vec<u8>>>);
+/// impl Write for Shared {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+///     fn flush(&mut self) -> io::Result<()> { Ok(()) }
+/// }
+///
+/// let data = b"some data to be buffered".to_vec();
+/// let output = Arc::new(Mutex::new(Vec::new()));
+/// let stats = PipeBuffer::new()
+///     .size(16)
+///     .block_size(4)
+///     .source(Box::new(Cursor::new(data.clone())))
+///     .sink(Box::new(Shared(output.clone())))
+///     .run()
+///     .unwrap();
+/// assert_eq!(stats.bytes, data.len() as u64);
+/// assert_eq!(*output.lock().unwrap(), data);
+/// ```
+pub struct PipeBuffer {
+    opts   : Options,
+    source : Option<Source>,
+    sink   : Option<Box<dyn Output>>,
+    taps   : Vec<Tap>,
+    signals: bool
+}
+
+impl Default for PipeBuffer {
+    fn default() -> PipeBuffer { PipeBuffer::new() }
+}
+
+impl PipeBuffer {
+    /// Constructs a `PipeBuffer` from stdin to stdout with the default sizes, which,
+    /// unlike the program, keeps stderr free of everything but fatal errors.
+    pub fn new() -> PipeBuffer {
+        PipeBuffer::with_options(Options { quiet: true, ..Options::default() })
+    }
+
+    /// Constructs a `PipeBuffer` configured by `opts`, reading from and writing to what
+    /// they name unless given a `source()` or `sink()`.
+    pub fn with_options(opts: Options) -> PipeBuffer {
+        PipeBuffer { opts, source: None, sink: None, taps: Vec::new(), signals: false }
+    }
+
+    /// Sets the size of the buffer in bytes, which it keeps unless it has been let grow.
+    pub fn size(mut self, size: usize) -> PipeBuffer {
+        if self.opts.max_size == self.opts.initial_size { self.opts.max_size = size; }
+        self.opts.initial_size = size;
+        self
+    }
+
+    /// Lets the buffer grow, if the reader repeatedly finds it full, up to `max_size`
+    /// bytes; see `BlockingRingBuffer::with_max_capacity()`.
+    pub fn max_size(mut self, max_size: usize) -> PipeBuffer { self.opts.max_size = max_size; self }
+
+    /// Sets the most bytes read, or written, at once.
+    pub fn block_size(mut self, block_size: usize) -> PipeBuffer { self.opts.block_size = block_size; self }
+
+    /// Sets the minimum interval between the starts of successive writes, if any.
+    pub fn pace(mut self, pace: Option<Duration>) -> PipeBuffer { self.opts.pace = pace; self }
+
+    /// Sets what is read from.  As `Read` defines it, a read that returns no bytes is taken
+    /// to mean EOF, so a source must not do so until it is finished.  A source in
+    /// non-blocking mode instead fails with `WouldBlock` while it has nothing to read,
+    /// and is read again shortly after.
+    pub fn source(mut self, source: Box<dyn Read + Send>) -> PipeBuffer { self.source = Some(source); self }
+
+    /// Sets what is written to.
+    pub fn sink(mut self, sink: Box<dyn Write + Send>) -> PipeBuffer { self.sink = Some(Box::new(sink)); self }
+
+    /// Adds a tap: `f` is called with each chunk read from the source, in order, as it
+    /// is put into the buffer, e.g. to keep metrics on or validate the stream.  It is
+    /// called on the thread reading the source, so however slow it is the writer carries
+    /// on with what is already buffered, and it sees but cannot change the data.  Taps
+    /// are called in the order they were added.
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use pipebuffer::pipe::PipeBuffer;
+    ///
+    /// let data = vec![7u8; 1000];
+    /// let bytes = Arc::new(AtomicUsize::new(0));
+    /// let counted = bytes.clone();
+    /// PipeBuffer::new()
+    ///     .block_size(256)
+    ///     .source(Box::new(Cursor::new(data.clone())))
+    ///     .sink(Box::new(Vec::new()))
+    ///     .tap(move |chunk| { counted.fetch_add(chunk.len(), Ordering::Relaxed); })
+    ///     .run()
+    ///     .unwrap();
+    /// assert_eq!(bytes.load(Ordering::Relaxed), data.len());
+    /// ```
+    pub fn tap<F>(mut self, f: F) -> PipeBuffer where F: FnMut(&[u8]) + Send + 'static {
+        self.taps.push(Box::new(f));
+        self
+    }
+
+    /// Has SIGTERM and SIGINT stop reading and drain the buffer, as the program does,
+    /// rather than end the process at once; see `signals::install()`.  Off by default,
+    /// since it changes how the whole process responds to them.
+    pub fn handle_signals(mut self, handle: bool) -> PipeBuffer { self.signals = handle; self }
+
+    /// Copies the source to the sink until the source reaches EOF, and flushes the sink.
+    ///
+    /// # Errors
+    /// Returns an `InvalidInput` error if the buffer or block size is zero.  Otherwise
+    /// returns the first error reading or writing; on a read error what was read before
+    /// it is still written out first, while on a write error reading stops.  See
+    /// `stop_reason()` for the errors returned when reading is stopped early.
+    pub fn run(self) -> io::Result<Stats> {
+        let PipeBuffer { mut opts, source, sink, taps, signals } = self;
+        if opts.initial_size == 0 || opts.block_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the buffer and block sizes must be greater than zero"));
+        }
+        opts.max_size = cmp::max(opts.initial_size, opts.max_size);
+        fit_block_to_buffer(&mut opts);
+
+        // The kernel can only move data between file descriptors, not a source or sink of the caller's own
+        if source.is_some() || sink.is_some() {
+            opts.splice = false;
+            opts.fast_copy = false;
+        }
+        let counters = Arc::new(IoCounters::default());
+        let (metrics, input, output) = open_endpoints(&opts, &counters, source, sink)?;
+        run(opts, input, output, metrics, counters, taps, signals)
+    }
+}
+
+impl Default for Options {
+    /// The program's defaults: a buffer of `DEFAULT_SIZE` bytes from stdin to stdout, a
+    /// `DEFAULT_BLOCK_SIZE` at a time, flushing after every write, with nothing else
+    /// asked for.
+    fn default() -> Options {
+        Options {
+            block_size: DEFAULT_BLOCK_SIZE, initial_size: DEFAULT_SIZE, max_size: DEFAULT_SIZE, prefault: false, pace: None, delay: None, flush_interval: None, flush_on_idle: None,
+            inputs: Vec::new(), interleave: Vec::new(), output: None, output_prefix: None, split: None, append: false, fsync: false, direct: false, checkpoint: None, resume_from: None, listen: None, connect: None, tees: Vec::new(), tee_policy: tee::Policy::Abort, tee_buffer: None, tee_overflow: tee::Overflow::Wait, unix_listen: None, unix_connect: None, discard: false, prefetch: false, no_drain_on_close: false, adaptive_block: false, splice: false, fast_copy: false, stack_size: None,
+            affinity: None, progress: None, progress_interval: Duration::from_secs(1), color: progress::ColorChoice::Never, expected_size: None, timeout: None, input_timeout: None, watchdog: None, stats: false, count_lines: false, warn_on_full: false, inspect: None, max_write: None, record_size: None, min_read: None, max_bytes: None, start_after: None, include_start_token: false, stop_before: None, header: None, trailer: None, checksum_verify: None, quiet: false, verbosity: 0, metrics_file: None, buffer_stats_interval: None, on_drain: None, pidfile: None
+        }
+    }
+}
+
+/// Settings that control the behaviour of `PipeBuffer::run()`, as the program resolves
+/// them from its command line.  `Default` gives the program's defaults.
+#[derive(Clone)]
+pub struct Options {
+    /// The size of the chunks read from the input and written to the output.
+    pub block_size: usize,
+    /// The capacity the ring buffer is created with.
+    pub initial_size: usize,
+    /// The capacity the ring buffer may grow to if it repeatedly fills.
+    pub max_size: usize,
+    /// Whether to commit the buffer's memory before the transfer starts.
+    pub prefault: bool,
+    /// The minimum interval between the starts of successive writes, if any.
+    pub pace: Option<Duration>,
+    /// How long the writer holds back after the first data arrives before writing any.
+    pub delay: Option<Duration>,
+    /// How often to flush the output, or `None` to flush after every write.
+    pub flush_interval: Option<FlushInterval>,
+    /// How long the input may go quiet before what has been written is flushed anyway.
+    pub flush_on_idle: Option<Duration>,
+    /// Files to read, one after another, instead of stdin.
+    pub inputs: Vec<PathBuf>,
+    /// Files to read instead of stdin a read at a time from each in turn, if any.
+    pub interleave: Vec<PathBuf>,
+    /// The address to accept a connection on and read from, instead of stdin.
+    pub listen: Option<String>,
+    /// A file to write to instead of stdout.
+    pub output: Option<PathBuf>,
+    /// The prefix of the files to write the output to, `split` bytes to a file, instead
+    /// of stdout.
+    pub output_prefix: Option<String>,
+    /// The size of each file written with `output_prefix`.
+    pub split: Option<usize>,
+    /// Whether to append to the `output` file rather than truncating it.
+    pub append: bool,
+    /// Whether to sync the `output` file to disk once everything has been written.
+    pub fsync: bool,
+    /// Whether to write the `output` file with `O_DIRECT`, bypassing the page cache.
+    pub direct: bool,
+    /// A file in which to keep the count of bytes written to the `output` file, if any.
+    pub checkpoint: Option<PathBuf>,
+    /// With `--resume`, the count of bytes already written read from the `checkpoint`
+    /// file, which are skipped in the input and kept in the output.
+    pub resume_from: Option<u64>,
+    /// The address to connect to and write to, instead of stdout.
+    pub connect: Option<String>,
+    /// Files that everything written to the output is also copied to.
+    pub tees: Vec<PathBuf>,
+    /// What to do if writing to one of the `tees` fails.
+    pub tee_policy: tee::Policy,
+    /// The size of a buffer of its own for each of the `tees`, if they are to be written
+    /// independently of the output.
+    pub tee_buffer: Option<usize>,
+    /// What to do when one of the `tees` has no room left in its `tee_buffer`.
+    pub tee_overflow: tee::Overflow,
+    /// The path of a Unix domain socket to accept a connection on and read from.
+    pub unix_listen: Option<PathBuf>,
+    /// The path of a Unix domain socket to connect to and write to.
+    pub unix_connect: Option<PathBuf>,
+    /// Whether to throw the output away rather than write it anywhere.
+    pub discard: bool,
+    /// Whether the writer prefetches the next block while writing the previous one.
+    pub prefetch: bool,
+    /// Whether the writer discards what is left in the buffer once it is closed, rather
+    /// than draining it to the output.
+    pub no_drain_on_close: bool,
+    /// Whether the writer adapts the size of the blocks it writes, up to `block_size`,
+    /// to how readily the output accepts them.
+    pub adaptive_block: bool,
+    /// Whether to splice from stdin to stdout through a pipe when both are pipes.
+    pub splice: bool,
+    /// Whether to have the kernel copy the input to the output when both are regular files.
+    pub fast_copy: bool,
+    /// The stack size for the writer thread, or `None` for the default.
+    pub stack_size: Option<usize>,
+    /// The CPUs to pin the reader and writer threads to respectively, if any.
+    pub affinity: Option<(usize, usize)>,
+    /// How to report progress on stderr during the transfer, if at all.
+    pub progress: Option<progress::Format>,
+    /// How often progress is reported.
+    pub progress_interval: Duration,
+    /// When progress is coloured, already resolved against `NO_COLOR`.
+    pub color: progress::ColorChoice,
+    /// The number of bytes expected to be transferred, if known, for reporting progress.
+    pub expected_size: Option<u64>,
+    /// The longest the input is read for before the transfer is cut short, if any.
+    pub timeout: Option<Duration>,
+    /// The longest to wait for the first byte of the input before giving up, if any.
+    pub input_timeout: Option<Duration>,
+    /// How long data may stop moving, with neither thread waiting on I/O, before the
+    /// transfer is deemed hung and the process exits, if at all.
+    pub watchdog: Option<Duration>,
+    /// Whether to report buffer statistics on stderr at exit.
+    pub stats: bool,
+    /// Whether to count the lines read, and report the count at exit.
+    pub count_lines: bool,
+    /// Whether to report on stderr when the buffer becomes full.
+    pub warn_on_full: bool,
+    /// How many bytes of the input to hexdump on stderr as they are read, if any.
+    pub inspect: Option<usize>,
+    /// The most bytes passed to the output in a single write, if limited.
+    pub max_write: Option<usize>,
+    /// The size of the fixed-size records the stream consists of, if it does, which are
+    /// then only ever written whole, except for a partial record at the end.
+    pub record_size: Option<usize>,
+    /// The fewest bytes gathered from the input before they are put into the buffer, if
+    /// reads are to be coalesced.
+    pub min_read: Option<usize>,
+    /// The most bytes read from the input, if limited, the rest being left unread.
+    pub max_bytes: Option<u64>,
+    /// A token before which the input is discarded, if any.
+    pub start_after: Option<Vec<u8>>,
+    /// Whether the `start_after` token itself is kept rather than discarded.
+    pub include_start_token: bool,
+    /// A token at which reading stops, the token and everything after it being discarded.
+    pub stop_before: Option<Vec<u8>>,
+    /// Bytes written to the output before anything from the buffer, if any.
+    pub header: Option<Vec<u8>>,
+    /// Bytes written to the output once the buffer has been drained, if any.
+    pub trailer: Option<Vec<u8>>,
+    /// The CRC-32 the output must have, if it is to be checked.
+    pub checksum_verify: Option<u32>,
+    /// Whether to keep stderr free of everything but fatal errors.
+    pub quiet: bool,
+    /// How much tracing to write to stderr: none at 0, the main events of the transfer at
+    /// 1, and every read, write and flush at 2.
+    pub verbosity: u64,
+    /// A file or FIFO to write progress and statistics to instead of stderr.
+    pub metrics_file: Option<PathBuf>,
+    /// How often to log a summary of the buffer's fill level to the `metrics_file`, if at all.
+    pub buffer_stats_interval: Option<Duration>,
+    /// A shell command to run once the input has reached EOF and been fully written out.
+    pub on_drain: Option<String>,
+    /// A file to record the process ID and buffer size in while running.
+    pub pidfile: Option<PathBuf>
+}
+
+impl Options {
+    /// True if anything has been asked for that needs the reader and writer threads and
+    /// the ring buffer between them, so that the data can't be moved any other way: by
+    /// the kernel, see `use_splice()` and `use_fast_copy()`, or by `passthrough()`.
+    fn needs_ring_buffer(&self) -> bool {
+        self.pace.is_some() ||
+        self.delay.is_some() ||
+        self.progress.is_some() ||
+        self.stats ||
+        self.count_lines ||
+        self.warn_on_full ||
+        self.inspect.is_some() ||
+        self.no_drain_on_close ||
+        self.max_write.is_some() ||
+        self.adaptive_block ||
+        self.buffer_stats_interval.is_some() ||
+        self.record_size.is_some() ||
+        self.min_read.is_some() ||
+        self.max_bytes.is_some() ||
+        self.start_after.is_some() ||
+        self.stop_before.is_some() ||
+        self.header.is_some() ||
+        self.trailer.is_some() ||
+        self.checksum_verify.is_some() ||
+        self.timeout.is_some() ||
+        self.input_timeout.is_some() ||
+        self.checkpoint.is_some()
+    }
+}
+
+/// Counts of reads and writes that moved less than was asked of them, maintained by
+/// the reader and writer and reported with `--stats`.
+#[derive(Default)]
+pub struct IoCounters {
+    /// Reads that returned fewer bytes than the block size, other than at EOF.
+    pub short_reads   : AtomicU64,
+    /// Writes that accepted only part of a block, so had to be continued.
+    pub partial_writes: AtomicU64,
+    /// Tee destinations that failed and were dropped under `--tee-policy ignore`.
+    pub dropped_tees  : AtomicU64,
+    /// Lines read, with `--count-lines`; see `read_input()`.
+    pub lines         : AtomicU64,
+    /// The block size the writer last used, with `--adaptive-block`.
+    pub block_size    : AtomicU64,
+    /// Bytes read from the input, for `--input-timeout`.
+    pub bytes_read    : AtomicU64,
+    /// Bytes left in the buffer when it was closed, discarded with `--no-drain-on-close`.
+    pub discarded     : AtomicU64,
+    /// What the reader and writer are doing, for `--watchdog`.
+    pub activity      : watchdog::Activity,
+    /// The CRC-32 of what has been written, with `--checksum-verify`.
+    pub checksum      : Mutex<Crc32>,
+    /// Bytes of `--header` and `--trailer` written, which aren't part of the stream.
+    pub framing       : AtomicU64
+}
+
+/// The reason for the `TimedOut` error `run()` returns when `--input-timeout` passes,
+/// given the timeout, so that it can be told apart from `--timeout`.
+#[derive(Debug)]
+pub struct NoInput(pub Duration);
+
+impl fmt::Display for NoInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no input arrived within {:?}", self.0)
+    }
+}
+
+impl Error for NoInput {}
+
+/// Something data to be buffered can be read from.
+pub type Source = Box<dyn Read + Send>;
+
+/// Something buffered data can be written to, which may also be able to make what was
+/// written durable.
+pub trait Output: Write + Send {
+    /// Writes out anything the output has held back until everything was written.
+    fn finish(&mut self) -> io::Result<()> { Ok(()) }
+    /// Makes everything written so far durable, if the output supports it.
+    fn sync(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Output for fs::File {
+    fn sync(&mut self) -> io::Result<()> { self.sync_all() }
+}
+
+impl Output for io::Stdout {}
+impl Output for TcpStream {}
+impl Output for UnixStream {}
+impl Output for io::Sink {}
+impl Output for Box<dyn Write + Send> {}
+
+/// How often the writer flushes its output, either after a number of bytes have
+/// been written since the last flush or after an amount of time has passed, or else
+/// only once at EOF.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushInterval {
+    Bytes(usize),
+    Time(Duration),
+    Never
+}
+
+/// Deals with a block size, necessarily given explicitly, larger than the buffer can
+/// grow to.  If nothing has been asked for that needs the buffer, the input is copied
+/// straight to the output without it, as `run()` does for such a buffer; otherwise
+/// the block size is reduced to the buffer size, so that a whole block can always be
+/// put into the buffer and the reader and writer alternate predictably.
+///
+/// # Return
+/// A warning describing what will be done, or `None` if the block fits the buffer.
+pub fn fit_block_to_buffer(opts: &mut Options) -> Option<String> {
+    if opts.block_size <= opts.max_size { return None; }
+    if use_passthrough(opts) {
+        Some(format!("the block size of {} bytes exceeds the buffer size of {} bytes, so the input will be copied straight to the output without buffering.",
+                     opts.block_size, opts.max_size))
+    }
+    else {
+        let warning = format!("the block size of {} bytes exceeds the buffer size of {} bytes, so it has been reduced to {} bytes.",
+                              opts.block_size, opts.max_size, opts.max_size);
+        opts.block_size = opts.max_size;
+        Some(warning)
+    }
+}
+
+/// Pins the calling thread to the given CPU.
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if cpu >= 8 * std::mem::size_of::<libc::cpu_set_t>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU number out of range"));
+        }
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0 { Ok(()) }
+        else { Err(io::Error::last_os_error()) }
+    }
+}
+/// Pins the calling thread to the given CPU, which is unsupported on this platform.
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "thread affinity is not supported on this platform"))
+}
+
+/// Pins the calling thread, named `name`, to the given CPU; since pinning is only a
+/// performance optimisation a failure is reported as a warning, unless `quiet`, and
+/// otherwise ignored.
+fn pin_or_warn(name: &str, cpu: usize, quiet: bool) {
+    if let Err(e) = pin_to_cpu(cpu) {
+        if quiet { return; }
+        writeln!(&mut io::stderr(), "Warning: could not pin the {} thread to CPU {}: {}", name, cpu, e).unwrap();
+    }
+}
+
+/// Moves data from `input` to `output` through a `BlockingRingBuffer` of between
+/// `initial_size` and `max_size` bytes, filled by `read_input()` on a thread named
+/// `reader` and emptied by `write_output()` on one named `writer`.  Which input and
+/// output are used is up to the caller; see `open_endpoints()`.  Other threads report
+/// progress, sample or log the fill level and watch for a hang, as `opts` asks, writing
+/// to `metrics`.  The buffer is bypassed where it isn't needed; see `use_splice()`,
+/// `use_fast_copy()` and `use_passthrough()`.
+///
+/// Reading may be stopped early; see `stop_reason()`.  Whatever was read is still
+/// written out.  The reader is left behind, still blocked on the input, to be torn down
+/// with the process.  With `signals` SIGTERM and SIGINT are among the reasons to stop.
+/// With `stats` the time each thread spent waiting on the other, the counts in
+/// `counters` and a hint from the fill level are written to `metrics` at the end.
+///
+/// # Return
+/// The `Stats` for the transfer, in which only the bytes and elapsed time are known
+/// without the ring buffer.  Otherwise the first error reading or writing, or the
+/// reason reading was stopped.
+fn run(opts: Options, mut input: Source, mut output: Box<dyn Output>, mut metrics: MetricsSink,
+       counters: Arc<IoCounters>, mut taps: Vec<Tap>, signals: bool) -> io::Result<Stats> {
+    let started = Instant::now();
+    #[cfg(target_os = "linux")]
+    if use_splice(&opts) {
+        match splice::BufferPipe::new(opts.max_size) {
+            Ok(pipe) => {
+                trace!(opts, 1, "Input and output are pipes; splicing between them.");
+                if signals { signals::install()?; }
+                let result = splice::transfer(pipe, libc::STDIN_FILENO, libc::STDOUT_FILENO, opts.block_size,
+                                              &|| signals::received().is_some(), &signals::forced);
+                let result = result.and_then(|bytes| match signals::received() {
+                    Some(signal) => Err(io::Error::new(io::ErrorKind::Interrupted, format!("terminated by signal {}", signal))),
+                    None         => Ok(bytes)
+                });
+                drop(output);
+                return finish_unbuffered(result, &opts, started);
+            },
+            Err(e) => if !opts.quiet {
+                writeln!(&mut io::stderr(), "Warning: could not make a pipe of {} bytes to splice through, so buffering as usual: {}", opts.max_size, e).unwrap();
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if use_fast_copy(&opts) {
+        match fast_copy(&opts) {
+            Ok(None) => if !opts.quiet {
+                writeln!(&mut io::stderr(), "Warning: the kernel can't copy between these files, so buffering as usual.").unwrap();
+            },
+            result => {
+                trace!(opts, 1, "Input and output are regular files; copied one to the other in the kernel.");
+                drop(output);
+                return finish_unbuffered(result.map(|copied| copied.unwrap()), &opts, started);
+            }
+        }
+    }
+
+    if use_passthrough(&opts) {
+        trace!(opts, 1, "Buffer cannot hold a block; copying input straight to output.");
+        let result = passthrough(&mut input, &mut output, opts.block_size).and_then(|bytes| finish_output(&mut *output, &opts).map(|_| bytes));
+        drop(output);
+        return finish_unbuffered(result, &opts, started);
+    }
+
+    if signals { signals::install()?; }
+    let buffer = Arc::new(BlockingRingBuffer::with_max_capacity(opts.initial_size, opts.max_size));
+    if opts.prefault {
+        let started = Instant::now();
+        buffer.prefault(0);
+        trace!(opts, 1, "Prefaulted the buffer in {:.3}s.", started.elapsed().as_secs_f64());
+    }
+    if opts.warn_on_full {
+        let start = Instant::now();
+        buffer.on_full(move || {
+            writeln!(&mut io::stderr(), "[{}] Buffer is full after {:.3}s; the input is outrunning the output.",
+                     timestamp(SystemTime::now()), start.elapsed().as_secs_f64()).unwrap();
+        });
+    }
+
+    // Setup the writer thread, which aborts the buffer if it fails so the reader isn't left waiting,
+    // and hangs up on writer_done when it finishes
+    let (writer_done, writer_finished) = mpsc::channel::<()>();
+    let writer_handle = {
+        let buffer = buffer.clone();
+        let counters = counters.clone();
+        let opts = opts.clone();
+        let mut builder = thread::Builder::new().name("writer".to_string());
+        if let Some(size) = opts.stack_size { builder = builder.stack_size(size); }
+        builder.spawn(move || {
+            let _writer_done = writer_done;
+            if let Some((_, cpu)) = opts.affinity { pin_or_warn("writer", cpu, opts.quiet); }
+            let result = write_output(&buffer, &mut output, &opts, &counters).and_then(|_| finish_output(&mut *output, &opts));
+            if result.is_err() { buffer.abort(); }
+            counters.activity.writer(State::Done);
+            result
+        })?
+    };
+
+    // Setup the progress thread, which runs until it is told the transfer is done
+    let (progress_done, progress_handle) = if let Some(format) = opts.progress {
+        let buffer = buffer.clone();
+        let expected  = opts.expected_size;
+        let color = opts.color;
+        let interval  = opts.progress_interval;
+        let sink = metrics.try_clone()?;
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new().name("progress".to_string())
+            .spawn(move || progress::report(&buffer, expected, format, color, interval, sink, receiver))?;
+        (Some(sender), Some(handle))
+    }
+    else {
+        (None, None)
+    };
+
+    // Setup the thread that samples how full the buffer is, for the hint given with the stats
+    let (sampler_done, sampler_handle) = if opts.stats {
+        let buffer = buffer.clone();
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new().name("sampler".to_string())
+            .spawn(move || progress::sample_fill(&buffer, receiver))?;
+        (Some(sender), Some(handle))
+    }
+    else {
+        (None, None)
+    };
+
+    // Setup the thread that logs how full the buffer is over each interval
+    let (fill_log_done, fill_log_handle) = if let Some(interval) = opts.buffer_stats_interval {
+        let buffer = buffer.clone();
+        let sink = metrics.try_clone()?;
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new().name("fill-log".to_string())
+            .spawn(move || progress::log_fill(&buffer, interval, sink, receiver))?;
+        (Some(sender), Some(handle))
+    }
+    else {
+        (None, None)
+    };
+
+    // Setup the watchdog thread, which exits the process if the reader and writer hang
+    let (watchdog_done, watchdog_handle) = if let Some(interval) = opts.watchdog {
+        let buffer = buffer.clone();
+        let counters = counters.clone();
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new().name("watchdog".to_string())
+            .spawn(move || watch(&buffer, &counters, interval, receiver))?;
+        (Some(sender), Some(handle))
+    }
+    else {
+        (None, None)
+    };
+
+    // Setup the reader thread, which hangs up on reader_done when it finishes
+    let (reader_done, reader_finished) = mpsc::channel::<()>();
+    let reader_handle = {
+        let buffer = buffer.clone();
+        let counters = counters.clone();
+        let opts = opts.clone();
+        thread::Builder::new().name("reader".to_string()).spawn(move || {
+            let _reader_done = reader_done;
+            if let Some((cpu, _)) = opts.affinity { pin_or_warn("reader", cpu, opts.quiet); }
+            let result = read_input(&buffer, &mut input, &opts, &counters, &mut taps);
+            counters.activity.reader(State::Done);
+            result
+        })?
+    };
+
+    // Wait for the reader, unless the timeout passes or a terminating signal arrives first, in which
+    // case the buffer is closed so the writer drains what has been read, and the reader is left
+    // behind since it may be stuck
+    let reading = Instant::now();
+    let mut stopped: Option<io::Error> = None;
+    while let Err(mpsc::RecvTimeoutError::Timeout) = reader_finished.recv_timeout(JOIN_POLL) {
+        stopped = stop_reason(&opts, &counters, reading);
+        if let Some(ref reason) = stopped {
+            trace!(opts, 1, "Stopping reading, {}; closing the buffer.", reason);
+            buffer.close();
+            break;
+        }
+    }
+    let read_result = if stopped.is_some() { Ok(()) } else { reader_handle.join().unwrap() };
+
+    if !opts.quiet { writeln!(&mut io::stderr(), "Attempting to join on the writer.").unwrap(); }
+    let result = read_result.and(join_writer(writer_handle, &writer_finished, &buffer));
+    let result = match (result, stopped) {
+        (Ok(()), Some(reason)) => Err(reason),
+        (result, _)            => result
+    };
+    let result = result.and_then(|()| verify_checksum(&opts, &counters));
+    trace!(opts, 1, "Writer has finished.");
+    drop(progress_done);
+    if let Some(handle) = progress_handle { handle.join().unwrap(); }
+    drop(sampler_done);
+    let fill_samples = sampler_handle.map(|handle| handle.join().unwrap());
+    drop(fill_log_done);
+    if let Some(handle) = fill_log_handle { handle.join().unwrap(); }
+    drop(watchdog_done);
+    if let Some(handle) = watchdog_handle { handle.join().unwrap(); }
+
+    let result = finish_drain(result, &opts);
+    let snapshot = buffer.snapshot();
+    let stats = Stats {
+        bytes           : snapshot.total_got,
+        high_water_mark : snapshot.high_water_mark,
+        capacity        : snapshot.capacity,
+        blocked_on_full : snapshot.blocked_on_full,
+        blocked_on_empty: snapshot.blocked_on_empty,
+        discarded       : counters.discarded.load(Ordering::Relaxed),
+        elapsed         : started.elapsed()
+    };
+
+    if opts.stats {
+        let pct = |part: u64, whole: u64| if whole == 0 { 0.0 } else { 100.0 * part as f64 / whole as f64 };
+        write!(&mut metrics, "High-water mark: {} of {} bytes", stats.high_water_mark, stats.capacity).unwrap();
+        if stats.high_water_mark == stats.capacity { write!(&mut metrics, " (buffer filled; consider a larger --size)").unwrap(); }
+        writeln!(&mut metrics).unwrap();
+        writeln!(&mut metrics, "Reader blocked on full buffer:  {:.3}s", stats.blocked_on_full.as_secs_f64()).unwrap();
+        writeln!(&mut metrics, "Writer blocked on empty buffer: {:.3}s", stats.blocked_on_empty.as_secs_f64()).unwrap();
+        writeln!(&mut metrics, "Puts split by the wrap: {} of {} ({:.1}%)", snapshot.split_puts, snapshot.puts, pct(snapshot.split_puts, snapshot.puts)).unwrap();
+        writeln!(&mut metrics, "Gets split by the wrap: {} of {} ({:.1}%)", snapshot.split_gets, snapshot.gets, pct(snapshot.split_gets, snapshot.gets)).unwrap();
+        writeln!(&mut metrics, "Short reads:    {}", counters.short_reads.load(Ordering::Relaxed)).unwrap();
+        writeln!(&mut metrics, "Partial writes: {}", counters.partial_writes.load(Ordering::Relaxed)).unwrap();
+        if !opts.tees.is_empty() {
+            writeln!(&mut metrics, "Dropped tees:   {} of {}", counters.dropped_tees.load(Ordering::Relaxed), opts.tees.len()).unwrap();
+        }
+        if opts.no_drain_on_close {
+            writeln!(&mut metrics, "Discarded at close: {} bytes", stats.discarded).unwrap();
+        }
+        if opts.adaptive_block {
+            writeln!(&mut metrics, "Adapted block size: {} bytes", counters.block_size.load(Ordering::Relaxed)).unwrap();
+        }
+        if opts.header.is_some() || opts.trailer.is_some() {
+            writeln!(&mut metrics, "Header and trailer: {} bytes", counters.framing.load(Ordering::Relaxed)).unwrap();
+        }
+        if let Some(hint) = fill_samples.and_then(|samples| samples.hint()) {
+            writeln!(&mut metrics, "Hint: {}", hint).unwrap();
+        }
+    }
+    if opts.count_lines {
+        writeln!(&mut metrics, "Lines: {}", counters.lines.load(Ordering::Relaxed)).unwrap();
+    }
+
+    result.map(|()| stats)
+}
+
+/// Checks whether reading, begun at `started`, should stop before the input ends, as it
+/// does once `timeout` has passed, or `input_timeout` has with nothing at all read, or
+/// on SIGTERM or SIGINT.  What was read is then still written out and flushed, so a
+/// normal `systemctl stop` loses nothing already buffered; a second signal abandons
+/// that, see `join_writer()`.
+///
+/// # Return
+/// The error for `run()` to return once the output is drained, or `None` to carry on:
+/// a `TimedOut` error, wrapping `NoInput` for the `input_timeout`, or an `Interrupted`
+/// one, which `main()` turns into a successful exit after SIGTERM.
+fn stop_reason(opts: &Options, counters: &IoCounters, started: Instant) -> Option<io::Error> {
+    let elapsed = started.elapsed();
+    if opts.timeout.is_some_and(|timeout| elapsed >= timeout) {
+        Some(io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {:?}", opts.timeout.unwrap())))
+    }
+    else if opts.input_timeout.is_some_and(|timeout| elapsed >= timeout) && counters.bytes_read.load(Ordering::Relaxed) == 0 {
+        Some(io::Error::new(io::ErrorKind::TimedOut, NoInput(opts.input_timeout.unwrap())))
+    }
+    else {
+        signals::received().map(|signal| io::Error::new(io::ErrorKind::Interrupted, format!("terminated by signal {}", signal)))
+    }
+}
+
+/// The body of the watchdog thread: checks every tenth of `interval`, until a message
+/// is received on, or the sender hangs up, `done`, that data is still moving through
+/// `buffer` or else that the reader or writer is waiting on I/O.  If neither has been
+/// so for `interval` the threads are taken to have hung, and the process exits with
+/// `WATCHDOG_STATUS` after describing the state of the buffer and both threads.
+fn watch(buffer: &BlockingRingBuffer<u8>, counters: &IoCounters, interval: Duration, done: mpsc::Receiver<()>) {
+    let mut watchdog = Watchdog::new(interval, Instant::now());
+    while let Err(mpsc::RecvTimeoutError::Timeout) = done.recv_timeout(interval / 10) {
+        let snapshot = buffer.snapshot();
+        let (reader, writer) = counters.activity.states();
+        if watchdog.check(snapshot.total_put + snapshot.total_got, Instant::now(), reader, writer) {
+            let mut stderr = io::stderr();
+            writeln!(&mut stderr, "Error: no data has moved for {:?} although neither the reader nor the writer is waiting on I/O; giving up on the transfer as hung.", interval).unwrap();
+            writeln!(&mut stderr, "  Buffer: {} of {} bytes ({:.0}%), {} put and {} got in total, {}{}", snapshot.len, snapshot.capacity,
+                     100.0 * snapshot.len as f64 / snapshot.capacity as f64, snapshot.total_put, snapshot.total_got,
+                     if buffer.is_closed() { "closed" } else { "open" }, if buffer.is_aborted() { ", aborted" } else { "" }).unwrap();
+            writeln!(&mut stderr, "  Reader: {}", watchdog::describe(reader, "reading the input")).unwrap();
+            writeln!(&mut stderr, "  Writer: {}", watchdog::describe(writer, "writing the output")).unwrap();
+            std::process::exit(WATCHDOG_STATUS);
+        }
+    }
+}
+
+/// Checks that the CRC-32 of the output, kept in `counters`, is the `checksum_verify`
+/// one, if given, returning an `InvalidData` error if not.
+fn verify_checksum(opts: &Options, counters: &IoCounters) -> io::Result<()> {
+    match opts.checksum_verify {
+        Some(expected) => {
+            let actual = counters.checksum.lock().unwrap().value();
+            if actual == expected {
+                trace!(opts, 1, "The output has the expected CRC-32, {:08x}.", actual);
+                Ok(())
+            }
+            else {
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("the output's CRC-32 is {:08x} rather than the expected {:08x}", actual, expected)))
+            }
+        },
+        None => Ok(())
+    }
+}
+
+/// Waits for the writer thread, which hangs up on `finished` as it exits, and returns its
+/// result.  If shutdown of the buffer is requested while waiting, which a second
+/// terminating signal also does, the writer is given up on, since it may be stuck
+/// writing to an output that is no longer being read, and an error is returned; the
+/// writer is then torn down along with the process.
+fn join_writer(handle: thread::JoinHandle<io::Result<()>>, finished: &mpsc::Receiver<()>,
+               buffer: &BlockingRingBuffer<u8>) -> io::Result<()> {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(JOIN_POLL) {
+        if signals::forced() { buffer.request_shutdown(); }
+        if buffer.is_shutdown_requested() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "shut down before the writer finished"));
+        }
+    }
+    handle.join().unwrap()
+}
+
+/// The body of the reader: reads from `input` into the buffer until EOF, at which point
+/// the buffer is closed, or until the writer gives up.  On a read error the buffer is
+/// also closed, so that what was read before it is still written out.
+///
+/// With `max_bytes` reading stops, as at EOF, once that many bytes have been read, and
+/// no read asks for more than remain, so nothing past the limit is taken from `input`.
+///
+/// With `start_after` nothing is put into the buffer until the token has been read,
+/// even if it is split across reads, and then only what follows it, or with
+/// `include_start_token` the token and what follows it.  With `stop_before` reading
+/// stops at that token, as if the input had ended there, and nothing from the token on
+/// is put into the buffer; since the token may be split across reads, bytes at the end
+/// of a read that could be the start of it are held back until it's known whether
+/// they are.
+///
+/// With `count_lines` the newlines put into the buffer are counted in `counters`, and
+/// at EOF a final line without a trailing newline is counted too, so `a\nb` is two
+/// lines (where `wc -l` would say one).
+///
+/// With `inspect` what is read is hexdumped on stderr, before any of the above.  The
+/// `taps` are given what is put into the buffer, after all of the above.
+///
+/// A read that returns no bytes is always taken as EOF, as `Read` defines it, whatever
+/// the input: files, pipes, stdin and sockets all do so only once the writing end is
+/// closed.  An input left in non-blocking mode, such as stdin inherited with O_NONBLOCK
+/// set, instead fails reads with `WouldBlock` while it has nothing to read; those reads
+/// are retried after `WOULD_BLOCK_WAIT` rather than ending the transfer.
+fn read_input(buffer: &BlockingRingBuffer<u8>, input: &mut dyn Read, opts: &Options, counters: &IoCounters,
+              taps: &mut [Tap]) -> io::Result<()> {
+    let mut bytes = vec![0u8; opts.block_size];
+    let mut unterminated = false;
+    let mut waiting  = opts.start_after.as_ref().map(|token| Matcher::new(token));
+    let mut stopping = opts.stop_before.as_ref().map(|token| Matcher::new(token));
+    let mut inspector = opts.inspect.map(|limit| Hexdump::new(io::stderr(), limit as u64));
+    let mut remaining = opts.max_bytes;
+    loop {
+        let want = remaining.map_or(bytes.len(), |remaining| cmp::min(remaining, bytes.len() as u64) as usize);
+        let n = if want == 0 { 0 } else {
+            match input.read(&mut bytes[0..want]) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if buffer.is_aborted() { return Ok(()); }
+                    thread::sleep(WOULD_BLOCK_WAIT);
+                    continue;
+                },
+                Err(e) => {
+                    trace!(opts, 1, "Reading the input failed; closing the buffer: {}", e);
+                    buffer.close();
+                    return Err(with_context(e, "could not read the input"));
+                }
+            }
+        };
+        if n == 0 { // input stream is closed, or the limit reached
+            trace!(opts, 1, "{}; closing the buffer.", if want == 0 { "Read --max-bytes of the input" } else { "Input is closed" });
+
+            // Bytes held back as possibly the start of the stop token turned out not to be
+            if let Some(ref matcher) = stopping {
+                if !put_input(buffer, &matcher.token()[0..matcher.matched()], opts, counters, taps, &mut unterminated) { return Ok(()); }
+            }
+            if unterminated { counters.lines.fetch_add(1, Ordering::Relaxed); }
+            buffer.close();
+            return Ok(());
+        }
+        trace!(opts, 2, "Read {} bytes.", n);
+        counters.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        if n < want { counters.short_reads.fetch_add(1, Ordering::Relaxed); }
+        if let Some(ref mut remaining) = remaining { *remaining -= n as u64; }
+        if let Some(ref mut dump) = inspector { dump.feed(&bytes[0..n]); }
+
+        // Skip everything up to the start token, if there is one and it hasn't been found yet
+        let mut data = &bytes[0..n];
+        if let Some(mut matcher) = waiting.take() {
+            match matcher.find(data) {
+                None => { waiting = Some(matcher); continue; },
+                Some(end) => {
+                    trace!(opts, 1, "Found the start token; buffering from here on.");
+                    data = &bytes[end..n];
+                    if opts.include_start_token && !put_input(buffer, matcher.token(), opts, counters, taps, &mut unterminated) { return Ok(()); }
+                }
+            }
+        }
+
+        // Put all but what is, or could yet turn out to be, the stop token, treating what was held back
+        // from the last read, which is the start of the token, as if it preceded this read
+        if let Some(ref mut matcher) = stopping {
+            let held  = matcher.matched();
+            let found = matcher.find(data);
+            let end   = match found {
+                Some(end) => held + end - matcher.token().len(),
+                None      => held + data.len() - matcher.matched()
+            };
+            if !put_input(buffer, &matcher.token()[0..cmp::min(held, end)], opts, counters, taps, &mut unterminated) { return Ok(()); }
+            if !put_input(buffer, &data[0..end.saturating_sub(held)], opts, counters, taps, &mut unterminated) { return Ok(()); }
+            if found.is_some() {
+                trace!(opts, 1, "Found the stop token; closing the buffer.");
+                if unterminated { counters.lines.fetch_add(1, Ordering::Relaxed); }
+                buffer.close();
+                return Ok(());
+            }
+            continue;
+        }
+        if !put_input(buffer, data, opts, counters, taps, &mut unterminated) { return Ok(()); }
+    }
+}
+
+/// Puts `data` read from the input into the buffer, after passing it to the `taps`,
+/// counting its lines with `count_lines`, and noting in `unterminated` whether it ends
+/// part way through one.
+///
+/// # Return
+/// False if the writer has given up, so reading should stop, and true otherwise.
+fn put_input(buffer: &BlockingRingBuffer<u8>, data: &[u8], opts: &Options, counters: &IoCounters, taps: &mut [Tap],
+             unterminated: &mut bool) -> bool {
+    if data.is_empty() { return true; }
+    for tap in taps.iter_mut() { tap(data); }
+    if opts.count_lines {
+        let newlines = data.iter().filter(|b| **b == b'\n').count();
+        counters.lines.fetch_add(newlines as u64, Ordering::Relaxed);
+        *unterminated = data[data.len() - 1] != b'\n';
+    }
+    counters.activity.reader(State::Buffer);
+    let put = buffer.put_blocking(data);
+    counters.activity.reader(State::Io);
+    if !put { // the writer has given up
+        trace!(opts, 1, "Buffer was aborted by the writer; stopping reading.");
+        return false;
+    }
+    true
+}
+
+/// True if `splice` was asked for and can be used: the input and output are stdin and
+/// stdout, both are pipes, and nothing has been asked for that needs the ring buffer,
+/// including that it start smaller than `max_size`, in which case `run()` moves the
+/// data with `splice::transfer()`.  A terminating signal then stops splicing as it
+/// stops the ring buffer: no more is read, what is in the pipe is drained to the
+/// output, and a second signal abandons that.
+#[cfg(target_os = "linux")]
+fn use_splice(opts: &Options) -> bool {
+    let stdio = opts.inputs.is_empty() && opts.interleave.is_empty() && opts.listen.is_none() && opts.unix_listen.is_none() &&
+        opts.output.is_none() && opts.output_prefix.is_none() && opts.connect.is_none() && opts.unix_connect.is_none() && !opts.discard && opts.tees.is_empty();
+    opts.splice && stdio &&
+    !opts.needs_ring_buffer() &&
+    opts.initial_size == opts.max_size && // a pipe can't start small and grow as the ring buffer does
+    splice::is_pipe(libc::STDIN_FILENO) && splice::is_pipe(libc::STDOUT_FILENO)
+}
+
+/// True if `fast_copy` was asked for and can be used: the input is a single regular
+/// file, either one `--input` or stdin, the output is a regular file, either `--output`
+/// without `append` or `direct`, or stdout, and nothing has been asked for that needs
+/// the ring buffer, in which case `run()` has the kernel copy the one to the other
+/// with `fast_copy()`.  That holds nothing back, so signals are left to end the process
+/// at once, as they would without `pipebuffer`.
+#[cfg(target_os = "linux")]
+fn use_fast_copy(opts: &Options) -> bool {
+    let input = match opts.inputs.len() {
+        0 => opts.interleave.is_empty() && opts.listen.is_none() && opts.unix_listen.is_none() && fastcopy::is_regular_file(libc::STDIN_FILENO),
+        1 => fs::metadata(&opts.inputs[0]).map(|meta| meta.is_file()).unwrap_or(false),
+        _ => false
+    };
+    let output = match opts.output {
+        Some(ref path) => !opts.append && !opts.direct && fs::metadata(path).map(|meta| meta.is_file()).unwrap_or(false),
+        None           => opts.output_prefix.is_none() && opts.connect.is_none() && opts.unix_connect.is_none() && !opts.discard && fastcopy::is_regular_file(libc::STDOUT_FILENO)
+    };
+    opts.fast_copy && input && output && opts.tees.is_empty() && !opts.needs_ring_buffer()
+}
+
+/// Copies the input file to the output file, each stdin or stdout if not given, within
+/// the kernel; see `fastcopy::transfer()`.  The output file, already created by
+/// `open_output()`, is opened again to copy into, and with `fsync` is synced afterwards.
+///
+/// # Return
+/// The number of bytes copied, or `None` if the kernel can't copy between the files.
+#[cfg(target_os = "linux")]
+fn fast_copy(opts: &Options) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+    let input = match opts.inputs.first() {
+        Some(path) => Some(fs::File::open(path).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?),
+        None       => None
+    };
+    let output = match opts.output {
+        Some(ref path) => Some(fs::OpenOptions::new().write(true).open(path).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?),
+        None           => None
+    };
+    let output_fd = output.as_ref().map_or(libc::STDOUT_FILENO, |file| file.as_raw_fd());
+    let copied = fastcopy::transfer(input.as_ref().map_or(libc::STDIN_FILENO, |file| file.as_raw_fd()), output_fd)?;
+    if copied.is_some() && opts.fsync && unsafe { libc::fsync(output_fd) } != 0 {
+        return Err(with_context(io::Error::last_os_error(), "could not sync the output"));
+    }
+    Ok(copied)
+}
+
+/// True if the buffer is too small to be of use, since it cannot hold even one block,
+/// and nothing has been asked for that needs it, nor a flush interval, in which case
+/// `run()` copies the input straight to the output with `passthrough()` rather than
+/// using a second thread and a ring buffer.  Since no more than a block is then held
+/// back, signals are left to end the process at once.
+fn use_passthrough(opts: &Options) -> bool {
+    opts.max_size < opts.block_size &&
+    !opts.needs_ring_buffer() &&
+    opts.flush_interval.is_none() // unlike the kernel, passthrough() flushes after every write
+}
+
+/// Copies `input` to `output` on the calling thread, a block at a time, flushing after
+/// every write just as the writer thread does by default.
+fn passthrough(input: &mut dyn Read, output: &mut dyn Write, block_size: usize) -> io::Result<u64> {
+    let mut bytes = vec![0u8; block_size];
+    let mut total = 0u64;
+    loop {
+        let n = match input.read(&mut bytes) {
+            Ok(0)  => return output.flush().map(|_| total),
+            Ok(n)  => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        write_fully(output, &bytes[0..n])?;
+        output.flush()?;
+        total += n as u64;
+    }
+}
+
+/// Formats `time` as a UTC time of day, to the millisecond, e.g. `13:02:45.120`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86400;
+    format!("{:02}:{:02}:{:02}.{:03}", secs / 3600, secs / 60 % 60, secs % 60, since_epoch.subsec_millis())
+}
+
+/// Runs the `on_drain` command, if any, provided the transfer succeeded, so not after a
+/// timeout or signal.  By then the writer has flushed and closed the output, so the
+/// command sees every byte, and no stats have yet been reported.  An unsuccessful exit
+/// is returned as an error.
+fn finish_drain(result: io::Result<()>, opts: &Options) -> io::Result<()> {
+    match (result, opts.on_drain.as_ref()) {
+        (Ok(()), Some(command)) => run_on_drain(command, opts.quiet),
+        (result, _)             => result
+    }
+}
+
+/// Finishes a transfer made without the ring buffer, of `result` bytes if successful,
+/// as `finish_drain()` does, returning `Stats` with only the bytes and elapsed time.
+fn finish_unbuffered(result: io::Result<u64>, opts: &Options, started: Instant) -> io::Result<Stats> {
+    let bytes = result?;
+    finish_drain(Ok(()), opts)?;
+    Ok(Stats { bytes, high_water_mark: 0, capacity: 0, blocked_on_full: Duration::default(), blocked_on_empty: Duration::default(),
+               discarded: 0, elapsed: started.elapsed() })
+}
+
+/// Runs the `--on-drain` command through the shell and waits for it, reporting its exit
+/// status on stderr unless `quiet`, and returning an error if it could not be run or
+/// did not succeed.
+fn run_on_drain(command: &str, quiet: bool) -> io::Result<()> {
+    let status = Command::new("sh").arg("-c").arg(command).status()
+        .map_err(|e| with_context(e, &format!("could not run on-drain command '{}'", command)))?;
+    if !quiet { writeln!(&mut io::stderr(), "On-drain command '{}' exited with {}", command, status).unwrap(); }
+    if status.success() { Ok(()) }
+    else { Err(io::Error::other(format!("on-drain command '{}' failed with {}", command, status))) }
+}
+
+/// Opens what `run()` reads from and writes to, as given by `opts`: the sink for progress
+/// and statistics, which is stderr or the `metrics_file` and is opened before anything
+/// else, then the output and the input, unless given a `sink` or `source` to use
+/// instead.  With `min_read` the input's reads are gathered together by a `Coalescing`
+/// reader.
+///
+/// Everything written to the output is also copied to each of the `tees` files.  If
+/// writing to one fails, then with the `Abort` `tee_policy` the run fails as it would
+/// if the output itself had; with `Ignore` a warning is given and that file is dropped,
+/// which is counted in `counters` and so reported with `stats`.  With `tee_buffer` each
+/// tee is written independently from a buffer of its own, and with the `Fail`
+/// `tee_overflow` one that falls a buffer behind is treated as having failed.
+fn open_endpoints(opts: &Options, counters: &Arc<IoCounters>, source: Option<Source>, sink: Option<Box<dyn Output>>)
+                  -> io::Result<(MetricsSink, Source, Box<dyn Output>)> {
+    let metrics = match opts.metrics_file {
+        Some(ref path) => MetricsSink::open(path).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?,
+        None           => MetricsSink::Stderr
+    };
+    let mut output = match sink { Some(sink) => sink, None => open_output(opts)? };
+    if !opts.tees.is_empty() {
+        output = Box::new(MultiWriter::new(output, open_tees(opts)?, opts.tee_policy, opts.quiet, counters.clone()));
+    }
+    let mut input = match source { Some(source) => source, None => open_input(opts)? };
+    if let Some(offset) = opts.resume_from { skip_input(&mut input, offset)?; }
+    if let Some(min) = opts.min_read { input = Box::new(Coalescing::new(input, min, MIN_READ_WAIT)); }
+    Ok((metrics, input, output))
+}
+
+/// Reads and discards the first `n` bytes of `input`, already transferred before a
+/// `--resume`, failing if the input ends before then.
+fn skip_input(input: &mut Source, n: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut input.take(n), &mut io::sink()).map_err(|e| with_context(e, "could not skip the input already transferred"))?;
+    if skipped < n {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("the input ended after {} bytes, before the {} already transferred", skipped, n)));
+    }
+    Ok(())
+}
+
+/// Opens the input to be buffered: the `inputs` files, read in order, or the
+/// `interleave` files, read in turn, if given, or a connection accepted on the
+/// `listen` address or the `unix_listen` socket if given, otherwise stdin, unbuffered
+/// with `max_bytes`.
+fn open_input(opts: &Options) -> io::Result<Source> {
+    if !opts.inputs.is_empty() {
+        Ok(Box::new(ConcatReader::new(opts.inputs.clone())))
+    }
+    else if !opts.interleave.is_empty() {
+        let sources = opts.interleave.iter().map(|path| {
+            let file = fs::File::open(path).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?;
+            Ok(Box::new(file) as Source)
+        }).collect::<io::Result<Vec<_>>>()?;
+        Ok(Box::new(InterleaveReader::new(sources)))
+    }
+    else if let Some(ref addr) = opts.listen {
+        let listener = TcpListener::bind(addr.as_str()).map_err(|e| with_context(e, &format!("could not listen on {}", addr)))?;
+        let (stream, _) = listener.accept().map_err(|e| with_context(e, &format!("could not accept a connection on {}", addr)))?;
+        Ok(Box::new(stream))
+    }
+    else if let Some(ref path) = opts.unix_listen {
+        let context = format!("could not listen on {}", path.display());
+        remove_stale_socket(path).map_err(|e| with_context(e, &context))?;
+        let listener = UnixListener::bind(path).map_err(|e| with_context(e, &context))?;
+
+        // Only one connection is ever accepted, so the socket file can go once it has been
+        let accepted = listener.accept();
+        fs::remove_file(path)?;
+        let (stream, _) = accepted.map_err(|e| with_context(e, &format!("could not accept a connection on {}", path.display())))?;
+        Ok(Box::new(stream))
+    }
+    else if opts.max_bytes.is_some() {
+        Ok(Box::new(RawStdin))
+    }
+    else {
+        Ok(Box::new(io::stdin()))
+    }
+}
+
+/// Stdin read straight from its file descriptor.  `io::stdin()` reads ahead into a
+/// buffer of its own, so would take more of a shared input than `max_bytes` allows.
+struct RawStdin;
+
+impl Read for RawStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 { Err(io::Error::last_os_error()) } else { Ok(n as usize) }
+    }
+}
+
+/// Removes a socket file left at `path` by a process that is no longer listening on
+/// it.  Fails if something is still listening, or the path exists but isn't a socket.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(_) => Ok(()), // nothing there
+        Ok(ref meta) if meta.file_type().is_socket() && UnixStream::connect(path).is_err() => fs::remove_file(path),
+        Ok(_)  => Err(io::Error::new(io::ErrorKind::AlreadyExists, "path already exists"))
+    }
+}
+
+/// Opens the output that buffered data is written to: the `output` file, appended to
+/// if `append` is set, cut back to `resume_from` bytes and written on from there if
+/// that is, or written with `O_DIRECT` if `direct` is, a series of files
+/// starting with `output_prefix`, or a connection to the `connect` address or the
+/// `unix_connect` socket if given, or with `discard` a sink that accepts everything
+/// without writing it anywhere, otherwise stdout.
+fn open_output(opts: &Options) -> io::Result<Box<dyn Output>> {
+    if let (Some(ref path), true) = (&opts.output, opts.direct) {
+        let file = DirectFile::create(path, opts.block_size, opts.quiet).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?;
+        Ok(Box::new(file))
+    }
+    else if let (Some(ref path), Some(offset)) = (&opts.output, opts.resume_from) {
+        let context = format!("could not resume writing {}", path.display());
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(path).map_err(|e| with_context(e, &context))?;
+        let len = file.metadata()?.len();
+        if len < offset {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: it holds only {} of the {} bytes the checkpoint records", context, len, offset)));
+        }
+        file.set_len(offset).and_then(|_| file.seek(SeekFrom::End(0))).map_err(|e| with_context(e, &context))?;
+        Ok(Box::new(file))
+    }
+    else if let Some(ref path) = opts.output {
+        let file = fs::OpenOptions::new().write(true).create(true).append(opts.append).truncate(!opts.append)
+            .open(path).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?;
+        Ok(Box::new(file))
+    }
+    else if let (Some(ref prefix), Some(size)) = (&opts.output_prefix, opts.split) {
+        let files = SplitFiles::create(prefix, size as u64).map_err(|e| with_context(e, &format!("could not open {}", split::path(prefix, 0).display())))?;
+        Ok(Box::new(files))
+    }
+    else if let Some(ref addr) = opts.connect {
+        let stream = TcpStream::connect(addr.as_str()).map_err(|e| with_context(e, &format!("could not connect to {}", addr)))?;
+        Ok(Box::new(stream))
+    }
+    else if let Some(ref path) = opts.unix_connect {
+        let stream = UnixStream::connect(path).map_err(|e| with_context(e, &format!("could not connect to {}", path.display())))?;
+        Ok(Box::new(stream))
+    }
+    else if opts.discard {
+        Ok(Box::new(io::sink()))
+    }
+    else {
+        Ok(Box::new(io::stdout()))
+    }
+}
+
+/// True if `open_output()` will write to stdout, there being no file, split files,
+/// socket or `--discard` to write to instead.
+pub fn writes_to_stdout(opts: &Options) -> bool {
+    opts.output.is_none() && !(opts.output_prefix.is_some() && opts.split.is_some()) &&
+        opts.connect.is_none() && opts.unix_connect.is_none() && !opts.discard
+}
+
+/// Creates, or truncates, each of the `tees` files that the output is copied to, giving
+/// each a `BufferedTee` of its own with `tee_buffer`.
+fn open_tees(opts: &Options) -> io::Result<Vec<Tee>> {
+    opts.tees.iter().map(|path| {
+        let file = fs::File::create(path).map_err(|e| with_context(e, &format!("could not open {}", path.display())))?;
+        let sink = match opts.tee_buffer {
+            Some(size) => Box::new(BufferedTee::new(Box::new(file), size, opts.block_size, opts.tee_overflow)?) as Box<dyn Output>,
+            None       => Box::new(file) as Box<dyn Output>
+        };
+        Ok(Tee { path: path.clone(), sink })
+    }).collect()
+}
+
+/// Completes the output once everything has been written and flushed: anything it has
+/// held back is written out and then, with `fsync`, it is synced, so that the data is
+/// on disk before the process exits.
+fn finish_output(output: &mut dyn Output, opts: &Options) -> io::Result<()> {
+    output.finish().map_err(|e| with_context(e, "could not finish writing the output"))?;
+    if opts.fsync { output.sync().map_err(|e| with_context(e, "could not sync the output"))?; }
+    Ok(())
+}
+
+/// Wraps an error with a message describing what was being attempted.
+fn with_context(e: io::Error, context: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", context, e))
+}
+
+/// The body of the writer thread: repeatedly gets data from the buffer and writes it
+/// to `output` until the buffer is closed and drained, as `write_blocks()` describes.
+/// The data is written straight from the buffer, except with
+/// `record_size`, which needs whole records gathered first, and with `prefetch`, where
+/// the gets are done by `write_prefetched()`.
+fn write_output(buffer: &BlockingRingBuffer<u8>, output: &mut dyn Write, opts: &Options, counters: &IoCounters) -> io::Result<()> {
+    if opts.prefetch { return write_prefetched(buffer, output, opts, counters); }
+    if opts.record_size.is_some() {
+        return write_blocks(buffer, output, opts, counters, Fetch::Staged(&mut |bytes, wanted| buffer.get_blocking(&mut bytes[0..wanted])));
+    }
+    write_blocks(buffer, output, opts, counters, Fetch::Direct)
+}
+
+/// Writes the output using two staging blocks so that copying out of the buffer overlaps
+/// with writing to the output: a `fetcher` thread gets the next block from the buffer
+/// while the previous one is being written, the blocks being passed back and forth over
+/// channels.  This only pays off with a core free for the fetcher; on a single core the
+/// extra hand-offs make it markedly slower than getting and writing in turn.
+fn write_prefetched(buffer: &BlockingRingBuffer<u8>, output: &mut dyn Write, opts: &Options, counters: &IoCounters) -> io::Result<()> {
+    let (full_tx, full_rx)   = mpsc::sync_channel::<(Vec<u8>, usize)>(1);
+    let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+    empty_tx.send(vec![0u8; opts.block_size]).unwrap();
+
+    thread::scope(|scope| {
+        thread::Builder::new().name("fetcher".to_string()).spawn_scoped(scope, move || {
+            for mut bytes in empty_rx {
+                let n = buffer.get_blocking(&mut bytes);
+                if n == 0 || full_tx.send((bytes, n)).is_err() { break; } // drained, or the writer failed
+            }
+        })?;
+
+        // Swap the block just written for the next one fetched, handing the former back to be refilled
+        let result = write_blocks(buffer, output, opts, counters, Fetch::Staged(&mut |bytes, _| match full_rx.recv() {
+            Ok((next, n)) => { let _ = empty_tx.send(mem::replace(bytes, next)); n },
+            Err(_)        => 0
+        }));
+        if result.is_err() { buffer.abort(); } // so the fetcher isn't left waiting on the buffer
+        drop(empty_tx);
+        result
+    })
+}
+
+/// Where `write_blocks()` gets the data it writes.
+enum Fetch<'a> {
+    /// Straight from the buffer, in place, with vectored writes of the data either side
+    /// of the wrap, the data being removed from the buffer once written.
+    Direct,
+    /// Into a staging block, with a function that fills or replaces the block it is
+    /// given, up to the number of bytes wanted, and returns its length, which is then
+    /// written in full.
+    Staged(&'a mut dyn FnMut(&mut Vec<u8>, usize) -> usize)
+}
+
+/// Writes blocks to `output`, fetched as `fetch` says, until the buffer is closed and
+/// drained.  With `adaptive_block` the number of bytes wanted is adapted to the output
+/// by an `AdaptiveBlock`, otherwise it is always the block size; a prefetching `fetch`
+/// always fetches whole blocks, so the two can't be used together.
+///
+/// With `pace` each write starts at least that long after the previous one, but only
+/// while the input is still open; after that what remains goes out as fast as the
+/// output takes it.  With `delay` the first write waits that long once there is data,
+/// while the reader carries on filling the buffer.
+///
+/// The output is flushed after every write, or with a `flush_interval` once that many
+/// bytes have been written or that much time has passed since the last flush, or with
+/// `FlushInterval::Never` not until the end.  With `flush_on_idle` it is also flushed
+/// once the buffer has been empty that long with something unflushed.  It is always
+/// flushed at the end.
+///
+/// With `no_drain_on_close` whatever is left once the input ends is discarded, and
+/// counted in `counters`, though a write already under way is completed.  With
+/// `checkpoint` the bytes written, plus `resume_from`, are saved to that file after a
+/// flush, at most once a second and again at the end, so the output may hold more than
+/// the checkpoint records but never less; `--resume` cuts off the excess.
+fn write_blocks(buffer: &BlockingRingBuffer<u8>, output: &mut dyn Write, opts: &Options, counters: &IoCounters,
+                mut fetch: Fetch) -> io::Result<()> {
+    let mut bytes = vec![0u8; if let Fetch::Staged(_) = fetch { opts.block_size } else { 0 }];
+    let mut adaptive = if opts.adaptive_block { Some(AdaptiveBlock::new(opts.block_size)) } else { None };
+    let mut records = Vec::new();
+    let mut records_written = 0;
+    let mut last_write: Option<Instant> = None;
+    let mut last_flush = Instant::now();
+    let mut unflushed = 0;
+    let mut checkpoint = opts.checkpoint.as_ref().map(|path| Checkpoint::new(path.clone(), opts.resume_from.unwrap_or(0)));
+    let mut written = 0u64;
+    if let Some(ref header) = opts.header { write_framing(output, header, opts, counters)?; }
+    loop {
+        // Sleep off whatever is left of the pacing interval since the last write
+        if let (Some(pace), Some(last)) = (opts.pace, last_write) {
+            let elapsed = last.elapsed();
+            if elapsed < pace && !buffer.is_closed() { thread::sleep(pace - elapsed); }
+        }
+
+        // Fail fast, discarding the rest of the input once it has ended
+        if opts.no_drain_on_close && buffer.is_closed() {
+            let discarded = buffer.discard();
+            counters.discarded.fetch_add(discarded as u64, Ordering::Relaxed);
+            trace!(opts, 1, "Buffer is closed; discarded the {} bytes left in it.", discarded);
+        }
+
+        // Flush what has been written if the input goes quiet, rather than hold it back for more
+        if let Some(idle) = opts.flush_on_idle {
+            counters.activity.writer(State::Buffer);
+            if unflushed > 0 && !buffer.wait_for_items(idle) {
+                counters.activity.writer(State::Io);
+                output.flush()?;
+                trace!(opts, 2, "Flushed the output after {:?} without input.", idle);
+                last_flush = Instant::now();
+                unflushed = 0;
+                if let Some(ref mut checkpoint) = checkpoint {
+                    checkpoint.flushed(written, last_flush).map_err(|e| with_context(e, "could not save the checkpoint"))?;
+                }
+            }
+        }
+
+        // Write the next data to the output: the number of bytes fetched, and of those
+        // how many were written, and whether a write accepted only part of what it was given
+        let wanted = adaptive.as_ref().map_or(opts.block_size, |a| a.size());
+        let mut started = Instant::now();
+        counters.activity.writer(State::Buffer);
+        let wrote = match fetch {
+            Fetch::Direct => {
+                // Hold back the first write for the delay, if any, once there is something to write
+                if let (Some(delay), None) = (opts.delay, last_write) {
+                    while !buffer.wait_for_items(Duration::from_secs(1)) {}
+                    trace!(opts, 1, "Delaying the output by {:?}.", delay);
+                    thread::sleep(delay);
+                }
+
+                let mut partial = false;
+                let n = buffer.consume_blocking(|first, second| {
+                    counters.activity.writer(State::Io);
+                    started = Instant::now();
+                    let n = cmp::min(first.len() + second.len(), cmp::min(wanted, opts.max_write.unwrap_or(wanted)));
+                    partial = write_slices(output, first, second, n)?;
+                    if opts.checksum_verify.is_some() {
+                        let mut checksum = counters.checksum.lock().unwrap();
+                        let split = cmp::min(n, first.len());
+                        checksum.update(&first[0..split]);
+                        checksum.update(&second[0..n-split]);
+                    }
+                    Ok::<_, io::Error>(n)
+                })?;
+                counters.activity.writer(State::Io);
+                if n == 0 { None } else { Some((n, n, partial)) }
+            },
+            Fetch::Staged(ref mut next) => {
+                let n = next(&mut bytes, wanted);
+                counters.activity.writer(State::Io);
+                if n == 0 { None }
+                else {
+                    // With records, write only whole ones, carrying any partial record over to the next write
+                    let data = match opts.record_size {
+                        None       => &bytes[0..n],
+                        Some(size) => {
+                            records.drain(0..records_written);
+                            records.extend_from_slice(&bytes[0..n]);
+                            records_written = records.len() / size * size;
+                            &records[0..records_written]
+                        }
+                    };
+                    if data.is_empty() { continue; }
+
+                    // Hold back the first write for the delay, if any
+                    if let (Some(delay), None) = (opts.delay, last_write) {
+                        trace!(opts, 1, "Delaying the output by {:?}.", delay);
+                        thread::sleep(delay);
+                    }
+
+                    started = Instant::now();
+                    let partial = write_capped(output, data, opts.max_write)?;
+                    if opts.checksum_verify.is_some() { counters.checksum.lock().unwrap().update(data); }
+                    Some((n, data.len(), partial))
+                }
+            }
+        };
+
+        let (fetched, n, partial) = match wrote {
+            Some(wrote) => wrote,
+            None        => { // closed and fully drained
+                trace!(opts, 1, "Buffer is closed and drained; finishing writing.");
+                if records.len() > records_written { // a final partial record
+                    write_capped(output, &records[records_written..], opts.max_write)?;
+                    written += (records.len() - records_written) as u64;
+                    if opts.checksum_verify.is_some() { counters.checksum.lock().unwrap().update(&records[records_written..]); }
+                }
+                if let Some(ref trailer) = opts.trailer { write_framing(output, trailer, opts, counters)?; }
+                break;
+            }
+        };
+
+        last_write = Some(started);
+        if partial { counters.partial_writes.fetch_add(1, Ordering::Relaxed); }
+        written += n as u64;
+        trace!(opts, 2, "Wrote {} bytes.", n);
+        if let Some(ref mut adaptive) = adaptive {
+            adaptive.record(fetched, partial, started.elapsed());
+            counters.block_size.store(adaptive.size() as u64, Ordering::Relaxed);
+            trace!(opts, 2, "Adapted the block size to {} bytes.", adaptive.size());
+        }
+
+        unflushed += n;
+        let flush = match opts.flush_interval {
+            None                              => true,
+            Some(FlushInterval::Bytes(bytes)) => unflushed >= bytes,
+            Some(FlushInterval::Time(time))   => last_flush.elapsed() >= time,
+            Some(FlushInterval::Never)        => false
+        };
+        if flush {
+            output.flush()?;
+            trace!(opts, 2, "Flushed the output.");
+            last_flush = Instant::now();
+            unflushed = 0;
+            if let Some(ref mut checkpoint) = checkpoint {
+                checkpoint.flushed(written, last_flush).map_err(|e| with_context(e, "could not save the checkpoint"))?;
+            }
+        }
+    }
+
+    output.flush()?;
+    if let Some(ref checkpoint) = checkpoint {
+        checkpoint.save(written).map_err(|e| with_context(e, "could not save the checkpoint"))?;
+    }
+    Ok(())
+}
+
+/// Writes the `header` or `trailer` to `output`, outside of any records, counting it in
+/// `counters` apart from the stream and including it in the checksum of the output.
+fn write_framing(output: &mut dyn Write, bytes: &[u8], opts: &Options, counters: &IoCounters) -> io::Result<()> {
+    write_capped(output, bytes, opts.max_write)?;
+    counters.framing.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    if opts.checksum_verify.is_some() { counters.checksum.lock().unwrap().update(bytes); }
+    trace!(opts, 2, "Wrote {} bytes of framing.", bytes.len());
+    Ok(())
+}
+
+/// Writes all of `bytes` to `output`, looping over partial writes.  A write that
+/// accepts nothing means the output can take no more, so it is reported as a
+/// `WriteZero` error rather than being retried forever.
+///
+/// # Return
+/// True if any write accepted only part of what remained, and false otherwise.
+fn write_fully<W: Write + ?Sized>(output: &mut W, bytes: &[u8]) -> io::Result<bool> {
+    let mut start = 0;
+    let mut partial = false;
+    while start < bytes.len() {
+        match output.write(&bytes[start..]) {
+            Ok(0)  => return Err(io::Error::new(io::ErrorKind::WriteZero, "output would not accept any more data")),
+            Ok(n)  => { start += n; partial |= start < bytes.len(); },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(partial)
+}
+
+/// Writes the start of `first`, and then of `second`, up to `limit` bytes in all, to
+/// `output` as `write_fully()` does, but with vectored writes, so that data either side
+/// of the wrap in the buffer goes out in one system call where the output supports it.
+///
+/// # Return
+/// True if any write accepted only part of what remained, and false otherwise.
+fn write_slices<W: Write + ?Sized>(output: &mut W, first: &[u8], second: &[u8], limit: usize) -> io::Result<bool> {
+    let mut first  = &first[0..cmp::min(first.len(), limit)];
+    let mut second = &second[0..cmp::min(second.len(), limit - first.len())];
+    let mut partial = false;
+    while !first.is_empty() || !second.is_empty() {
+        match output.write_vectored(&[IoSlice::new(first), IoSlice::new(second)]) {
+            Ok(0)  => return Err(io::Error::new(io::ErrorKind::WriteZero, "output would not accept any more data")),
+            Ok(n)  => {
+                let from_first = cmp::min(n, first.len());
+                first  = &first[from_first..];
+                second = &second[n-from_first..];
+                partial |= !first.is_empty() || !second.is_empty();
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(partial)
+}
+
+/// Writes all of `bytes` to `output` with `write_fully()`, passing it at most
+/// `max_write` bytes at a time if that is given.
+///
+/// # Return
+/// True if any write accepted only part of what it was passed, and false otherwise.
+fn write_capped<W: Write + ?Sized>(output: &mut W, bytes: &[u8], max_write: Option<usize>) -> io::Result<bool> {
+    let mut partial = false;
+    for chunk in bytes.chunks(max_write.unwrap_or(bytes.len()).max(1)) {
+        partial |= write_fully(output, chunk)?;
+    }
+    Ok(partial)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use {adaptive, checkpoint, coalesce};
+#[cfg(test)]
+use super::{temp_file, test_data, SharedSink};
+
+#[test]
+fn test_run() {
+    let data = test_data(100000);
+    let output = SharedSink::default();
+    let stats = PipeBuffer::new().size(1000).block_size(300)
+        .source(Box::new(io::Cursor::new(data.clone()))).sink(Box::new(output.clone())).run().unwrap();
+    assert!(*output.written.lock().unwrap() == data);
+    assert!(stats.bytes == data.len() as u64 && stats.capacity == 1000);
+    assert!(stats.high_water_mark > 0 && stats.high_water_mark <= 1000);
+    assert!(stats.discarded == 0);
+}
+
+#[test]
+fn test_taps() {
+    let data = test_data(10000);
+    let first  = SharedSink::default();
+    let second = Arc::new(Mutex::new(Vec::new()));
+    let output = SharedSink::default();
+    let (mut copy, sizes) = (first.clone(), second.clone());
+    PipeBuffer::new().size(1000).block_size(300).source(Box::new(io::Cursor::new(data.clone()))).sink(Box::new(output.clone()))
+        .tap(move |chunk| copy.write_all(chunk).unwrap())
+        .tap(move |chunk| sizes.lock().unwrap().push(chunk.len()))
+        .run().unwrap();
+    assert!(*first.written.lock().unwrap() == data && *output.written.lock().unwrap() == data);
+    let second = second.lock().unwrap();
+    assert!(second.iter().sum::<usize>() == data.len() && second.iter().all(|n| *n <= 300));
+}
+
+#[test]
+fn test_run_errors() {
+    let sized = |size, block_size| PipeBuffer::new().size(size).block_size(block_size).source(Box::new(io::empty())).sink(Box::new(io::sink()));
+    assert!(sized(0, 10).run().unwrap_err().kind() == io::ErrorKind::InvalidInput);
+    assert!(sized(10, 0).run().unwrap_err().kind() == io::ErrorKind::InvalidInput);
+
+    // A failing sink stops the transfer rather than leaving the reader blocked on a full buffer
+    let result = PipeBuffer::new().size(10).block_size(5)
+        .source(Box::new(io::repeat(1))).sink(Box::new(SharedSink { fail: true, ..SharedSink::default() })).run();
+    assert!(result.unwrap_err().to_string() == "disk full");
+}
+
+#[cfg(test)]
+fn test_options() -> Options {
+    Options { block_size: 1024, initial_size: 1024, max_size: 1024, ..Options::default() }
+}
+
+#[test]
+fn test_fit_block_to_buffer() {
+    let mut opts = Options { block_size: 1024, initial_size: 1024, max_size: 4096, ..test_options() };
+    assert!(fit_block_to_buffer(&mut opts).is_none() && opts.block_size == 1024);
+
+    // With nothing needing the buffer it is bypassed, so the block size is left alone
+    let mut opts = Options { block_size: 8192, initial_size: 1024, max_size: 4096, ..test_options() };
+    assert!(fit_block_to_buffer(&mut opts).unwrap().contains("without buffering") && opts.block_size == 8192);
+
+    // Otherwise the block size is reduced to the buffer size
+    let mut opts = Options { block_size: 8192, initial_size: 1024, max_size: 4096, stats: true, ..test_options() };
+    assert!(fit_block_to_buffer(&mut opts).unwrap().contains("reduced to 4096 bytes") && opts.block_size == 4096);
+    assert!(!use_passthrough(&opts));
+}
+
+/// A sink that accepts a limited number of bytes and thereafter reports writing zero.
+#[cfg(test)]
+struct FullSink { remaining: usize, written: Vec<u8> }
+
+#[cfg(test)]
+impl Write for FullSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(std::cmp::min(self.remaining, buf.len()), 3);
+        self.written.extend_from_slice(&buf[0..n]);
+        self.remaining -= n;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn test_write_fully_partial_writes() {
+    let mut sink = FullSink { remaining: 100, written: Vec::new() };
+    assert!(write_fully(&mut sink, b"hello world").unwrap()); // written three bytes at a time
+    assert!(sink.written == b"hello world");
+    assert!(!write_fully(&mut sink, b"abc").unwrap());
+}
+
+#[test]
+fn test_write_fully_stops_on_write_zero() {
+    let mut sink = FullSink { remaining: 5, written: Vec::new() };
+    let result = write_fully(&mut sink, b"hello world");
+    assert!(result.unwrap_err().kind() == io::ErrorKind::WriteZero);
+    assert!(sink.written == b"hello");
+}
+
+/// A reader and writer that records the size of the slice passed to each call.
+#[cfg(test)]
+struct ChunkRecorder { remaining: usize, sizes: Vec<usize> }
+
+#[cfg(test)]
+impl Read for ChunkRecorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sizes.push(buf.len());
+        let n = std::cmp::min(self.remaining, buf.len());
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl Write for ChunkRecorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sizes.push(buf.len());
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn test_staging_matches_block_size() {
+    let opts = Options { block_size: 100, initial_size: 1000, max_size: 1000, ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    let mut input = ChunkRecorder { remaining: 1000, sizes: Vec::new() };
+    read_input(&buffer, &mut input, &opts, &IoCounters::default(), &mut []).unwrap();
+    assert!(input.sizes.iter().all(|n| *n == 100));
+
+    let mut output = ChunkRecorder { remaining: 0, sizes: Vec::new() };
+    write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+    assert!(output.sizes == vec![100; 10]);
+}
+
+#[test]
+fn test_io_counters() {
+    let opts = Options { block_size: 100, ..test_options() };
+    let counters = IoCounters::default();
+    let buffer = BlockingRingBuffer::new(1000);
+    read_input(&buffer, &mut ChunkRecorder { remaining: 250, sizes: Vec::new() }, &opts, &counters, &mut []).unwrap();
+    assert!(counters.short_reads.load(Ordering::Relaxed) == 1);
+
+    // The sink takes three bytes at a time, so each of the three blocks is written partially
+    write_output(&buffer, &mut FullSink { remaining: 250, written: Vec::new() }, &opts, &counters).unwrap();
+    assert!(counters.partial_writes.load(Ordering::Relaxed) == 3);
+}
+
+#[test]
+fn test_count_lines() {
+    let opts = Options { block_size: 4, count_lines: true, ..test_options() };
+    for (input, lines) in [(&b""[..], 0), (b"one\n", 1), (b"one\ntwo\n\nfour\n", 4), (b"one\ntwo\nthree", 3)].iter() {
+        let counters = IoCounters::default();
+        let buffer = BlockingRingBuffer::new(1000);
+        read_input(&buffer, &mut &input[..], &opts, &counters, &mut []).unwrap();
+        assert!(counters.lines.load(Ordering::Relaxed) == *lines);
+        assert!(buffer.snapshot().len == input.len());
+    }
+}
+
+#[test]
+fn test_start_after_token_across_reads() {
+    // The token straddles the first two reads, of ten bytes each
+    let input = b"preamble--START--payload\n";
+    for (include, expected) in [(false, &b"--payload\n"[..]), (true, &b"--START--payload\n"[..])].iter() {
+        let opts = Options { block_size: 10, start_after: Some(b"--START".to_vec()), include_start_token: *include, ..test_options() };
+        let buffer = BlockingRingBuffer::new(1000);
+        read_input(&buffer, &mut &input[..], &opts, &IoCounters::default(), &mut []).unwrap();
+        let mut output = FullSink { remaining: 1000, written: Vec::new() };
+        write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+        assert!(output.written == *expected);
+    }
+
+    // Nothing is passed if the token never appears
+    let opts = Options { block_size: 10, start_after: Some(b"--STOP".to_vec()), ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    read_input(&buffer, &mut &input[..], &opts, &IoCounters::default(), &mut []).unwrap();
+    assert!(buffer.snapshot().len == 0);
+}
+
+#[test]
+fn test_region_between_tokens() {
+    // Read four bytes at a time, so both tokens straddle reads, and the first partial match of the
+    // stop token, '<', is held back and then released when the token turns out not to be there
+    let input = b"header<BEGIN>a<b\nc<END>trailer<END>";
+    let opts = Options { block_size: 4, start_after: Some(b"<BEGIN>".to_vec()), stop_before: Some(b"<END>".to_vec()), ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    read_input(&buffer, &mut &input[..], &opts, &IoCounters::default(), &mut []).unwrap();
+    assert!(buffer.is_closed());
+    let mut output = FullSink { remaining: 1000, written: Vec::new() };
+    write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+    assert!(output.written == b"a<b\nc");
+
+    // Without the stop token, the start of it held back at EOF is still passed
+    for input in [&b"abc<EN"[..], b"<EN", b"abc<END"].iter() {
+        let opts = Options { block_size: 2, stop_before: Some(b"<END>".to_vec()), ..test_options() };
+        let buffer = BlockingRingBuffer::new(1000);
+        read_input(&buffer, &mut &input[..], &opts, &IoCounters::default(), &mut []).unwrap();
+        assert!(buffer.snapshot().len == input.len());
+    }
+}
+
+#[test]
+fn test_max_bytes_leaves_the_rest_unread() {
+    // The limit falls part way through the second read, which must stop short of it
+    let input = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let mut source = &input[..];
+    let opts = Options { block_size: 16, max_bytes: Some(20), ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    let counters = IoCounters::default();
+    read_input(&buffer, &mut source, &opts, &counters, &mut []).unwrap();
+    assert!(buffer.is_closed());
+    assert!(buffer.snapshot().len == 20);
+    assert!(source == b"klmnopqrstuvwxyz");
+    assert!(counters.short_reads.load(Ordering::Relaxed) == 0);
+
+    // A shorter input is read to EOF as usual
+    let opts = Options { block_size: 16, max_bytes: Some(100), ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    read_input(&buffer, &mut &input[..], &opts, &IoCounters::default(), &mut []).unwrap();
+    assert!(buffer.snapshot().len == input.len());
+}
+
+#[test]
+fn test_zero_length_read_is_eof_for_each_input() {
+    let data = test_data(5000);
+    let read_all = |input: &mut dyn Read| {
+        let buffer = BlockingRingBuffer::new(100000);
+        read_input(&buffer, input, &Options { block_size: 1000, ..test_options() }, &IoCounters::default(), &mut []).unwrap();
+        assert!(buffer.is_closed());
+        let mut read = vec![0u8; buffer.snapshot().len];
+        assert!(buffer.get_blocking(&mut read) == read.len());
+        read
+    };
+
+    // A file
+    let path = temp_file("eof", &data);
+    assert!(read_all(&mut fs::File::open(&path).unwrap()) == data);
+    fs::remove_file(&path).unwrap();
+
+    // A TCP connection, once the peer has finished sending
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    sender.write_all(&data).unwrap();
+    sender.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(read_all(&mut listener.accept().unwrap().0) == data);
+
+    // A socket in non-blocking mode, fed in pieces with pauses between them in which it has
+    // nothing to read, ends only when the peer closes it
+    let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+    receiver.set_nonblocking(true).unwrap();
+    let feeder = {
+        let data = data.clone();
+        thread::spawn(move || for chunk in data.chunks(1500) {
+            thread::sleep(Duration::from_millis(20));
+            sender.write_all(chunk).unwrap();
+        })
+    };
+    assert!(read_all(&mut receiver) == data);
+    feeder.join().unwrap();
+}
+
+#[test]
+fn test_min_read_coalesces_puts() {
+    let data = test_data(10000);
+    let opts = Options { block_size: 1000, ..test_options() };
+    let trickle = coalesce::Trickle { data: io::Cursor::new(data.clone()), size: 10, pause: Duration::new(0, 0) };
+    let buffer = BlockingRingBuffer::new(100000);
+    read_input(&buffer, &mut Coalescing::new(trickle, 500, MIN_READ_WAIT), &opts, &IoCounters::default(), &mut []).unwrap();
+
+    // A put per 500 bytes rather than per 10
+    assert!(buffer.snapshot().puts == 20);
+    let mut output = FullSink { remaining: 100000, written: Vec::new() };
+    write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+    assert!(output.written == data);
+}
+
+/// A sink that records the size of each write, or part of one, it accepts.
+#[cfg(test)]
+struct WriteRecorder { written: Vec<u8>, sizes: Vec<usize> }
+
+#[cfg(test)]
+impl Write for WriteRecorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), 1000); // so large writes are partial
+        self.written.extend_from_slice(&buf[0..n]);
+        self.sizes.push(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> { self.sizes.push(0); Ok(()) }
+}
+
+#[test]
+fn test_record_size() {
+    // Records of 7 bytes, written from blocks of 100, so rarely a whole number of records
+    let data = test_data(10003);
+    for prefetch in [false, true].iter() {
+        let opts = Options { block_size: 100, record_size: Some(7), prefetch: *prefetch, ..test_options() };
+        let buffer = BlockingRingBuffer::new(data.len());
+        buffer.put_blocking(&data);
+        buffer.close();
+        let mut output = WriteRecorder { written: Vec::new(), sizes: Vec::new() };
+        write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+        assert!(output.written == data);
+
+        // Every flush comes after a whole number of records, bar the final partial one
+        let mut total = 0;
+        for size in output.sizes.iter() {
+            if *size == 0 { assert!(total % 7 == 0 || total == data.len()); }
+            total += *size;
+        }
+    }
+}
+
+#[test]
+fn test_adaptive_block() {
+    // The recorder accepts at most 1000 bytes at a time, so the block size should fall to the minimum
+    let data = test_data(200000);
+    let opts = Options { block_size: 64 * 1024, adaptive_block: true, ..test_options() };
+    let buffer = BlockingRingBuffer::new(data.len());
+    buffer.put_blocking(&data);
+    buffer.close();
+    let mut output = WriteRecorder { written: Vec::new(), sizes: Vec::new() };
+    let counters = IoCounters::default();
+    write_output(&buffer, &mut output, &opts, &counters).unwrap();
+    assert!(output.written == data);
+    assert!(counters.block_size.load(Ordering::Relaxed) == adaptive::MIN_BLOCK as u64);
+    assert!(output.sizes.iter().all(|n| *n <= 1000));
+}
+
+#[test]
+fn test_no_drain_on_close() {
+    let opts = Options { block_size: 100, no_drain_on_close: true, ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    let mut output = WriteRecorder { written: Vec::new(), sizes: Vec::new() };
+    let counters = IoCounters::default();
+
+    // What the writer gets before the buffer is closed is written, what is left at the close isn't
+    buffer.put_blocking(&[1u8; 300]);
+    let mut first = true;
+    write_blocks(&buffer, &mut output, &opts, &counters, Fetch::Staged(&mut |bytes, wanted| {
+        let n = buffer.get_blocking(&mut bytes[0..wanted]);
+        if first { buffer.put_blocking(&[2u8; 400]); buffer.close(); first = false; }
+        n
+    })).unwrap();
+    assert!(output.written == vec![1u8; 100]);
+    assert!(counters.discarded.load(Ordering::Relaxed) == 600);
+}
+
+#[test]
+fn test_max_write() {
+    let data = test_data(10000);
+    let opts = Options { block_size: 1000, max_write: Some(7), ..test_options() };
+    let buffer = BlockingRingBuffer::new(data.len());
+    buffer.put_blocking(&data);
+    buffer.close();
+    let mut output = WriteRecorder { written: Vec::new(), sizes: Vec::new() };
+    let counters = IoCounters::default();
+    write_output(&buffer, &mut output, &opts, &counters).unwrap();
+    assert!(output.written == data);
+    assert!(output.sizes.iter().all(|n| *n <= 7));
+    assert!(counters.partial_writes.load(Ordering::Relaxed) == 0);
+}
+
+/// A `Write` that, unlike the default, writes from every slice of a vectored write, but
+/// accepts at most `limit` bytes per write, recording how many bytes each write was offered.
+#[cfg(test)]
+struct VectoredRecorder { written: Vec<u8>, offered: Vec<usize>, limit: usize }
+
+#[cfg(test)]
+impl Write for VectoredRecorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.write_vectored(&[IoSlice::new(buf)]) }
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.offered.push(bufs.iter().map(|b| b.len()).sum());
+        let mut n = 0;
+        for buf in bufs {
+            let take = cmp::min(buf.len(), self.limit - n);
+            self.written.extend_from_slice(&buf[0..take]);
+            n += take;
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn test_write_output_vectored() {
+    // Data wrapped around the end of the buffer goes out in a single write of both parts,
+    // and what a partial write didn't take is written next, with nothing lost or repeated
+    let buffer = BlockingRingBuffer::new(1000);
+    let mut scratch = vec![0u8; 700];
+    buffer.put_blocking(&scratch);
+    buffer.get_blocking(&mut scratch);
+    let data = test_data(900);
+    buffer.put_blocking(&data);
+    buffer.close();
+
+    let opts = Options { block_size: 1000, ..test_options() };
+    let counters = IoCounters::default();
+    let mut output = VectoredRecorder { written: Vec::new(), offered: Vec::new(), limit: 500 };
+    write_output(&buffer, &mut output, &opts, &counters).unwrap();
+    assert!(output.written == data);
+    assert!(output.offered == vec![900, 400]);
+    assert!(counters.partial_writes.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn test_write_output_prefetched() {
+    let data = test_data(100000);
+    for prefetch in [false, true].iter() {
+        let buffer = BlockingRingBuffer::new(data.len());
+        buffer.put_blocking(&data);
+        buffer.close();
+        let opts = Options { prefetch: *prefetch, block_size: 999, ..test_options() };
+        let mut sink = FullSink { remaining: data.len(), written: Vec::new() };
+        write_output(&buffer, &mut sink, &opts, &IoCounters::default()).unwrap();
+        assert!(sink.written == data);
+    }
+}
+
+#[test]
+fn test_header_and_trailer() {
+    let data = test_data(10003);
+    let mut expected = b"HDR".to_vec();
+    expected.extend_from_slice(&data);
+    expected.extend_from_slice(b"\n\0");
+    let mut crc = Crc32::new();
+    crc.update(&expected);
+
+    // Written whole around the stream, even when only whole records of it are written at a time
+    for record_size in [None, Some(7)].iter() {
+        let opts = Options { block_size: 100, header: Some(b"HDR".to_vec()), trailer: Some(b"\n\0".to_vec()),
+                             record_size: *record_size, checksum_verify: Some(crc.value()), ..test_options() };
+        let buffer = BlockingRingBuffer::new(100000);
+        let counters = IoCounters::default();
+        read_input(&buffer, &mut &data[..], &opts, &counters, &mut []).unwrap();
+        let mut output = FullSink { remaining: 100000, written: Vec::new() };
+        write_output(&buffer, &mut output, &opts, &counters).unwrap();
+        assert!(output.written == expected);
+        assert!(counters.framing.load(Ordering::Relaxed) == 5);
+        assert!(counters.checksum.lock().unwrap().value() == crc.value());
+    }
+
+    // An empty input is still framed
+    let opts = Options { header: Some(b"HDR".to_vec()), trailer: Some(b"END".to_vec()), ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    buffer.close();
+    let mut output = FullSink { remaining: 1000, written: Vec::new() };
+    write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+    assert!(output.written == b"HDREND");
+}
+
+#[test]
+fn test_flush_on_idle() {
+    for idle in [None, Some(Duration::from_millis(50))].iter() {
+        let opts = Options { block_size: 100, flush_interval: Some(FlushInterval::Never), flush_on_idle: *idle, ..test_options() };
+        let buffer = BlockingRingBuffer::new(1000);
+        let sizes = thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let mut output = WriteRecorder { written: Vec::new(), sizes: Vec::new() };
+                write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+                output.sizes
+            });
+
+            // A burst, a lull long enough to count as idle, then another burst
+            buffer.put_blocking(&[1; 250]);
+            thread::sleep(Duration::from_millis(300));
+            buffer.put_blocking(&[2; 250]);
+            buffer.close();
+            writer.join().unwrap()
+        });
+
+        // The first burst is flushed in the lull (a flush is recorded as 0), or otherwise only at EOF
+        let first_flush = sizes.iter().position(|n| *n == 0).unwrap();
+        assert!(sizes[0..first_flush].iter().sum::<usize>() == if idle.is_some() { 250 } else { 500 });
+    }
+}
+
+#[test]
+fn test_write_output_delayed() {
+    let opts = Options { block_size: 100, delay: Some(Duration::from_millis(200)), ..test_options() };
+    let buffer = BlockingRingBuffer::new(1000);
+    let start = Instant::now();
+    let written = thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            let mut sink = FullSink { remaining: 3000, written: Vec::new() };
+            write_output(&buffer, &mut sink, &opts, &IoCounters::default()).unwrap();
+            sink.written
+        });
+
+        // The reader fills the buffer during the delay and then blocks until the writer starts
+        read_input(&buffer, &mut ChunkRecorder { remaining: 3000, sizes: Vec::new() }, &opts, &IoCounters::default(), &mut []).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        writer.join().unwrap()
+    });
+    assert!(written.len() == 3000);
+    assert!(buffer.blocked_on_full() > Duration::from_millis(100));
+}
+
+#[test]
+fn test_write_output_prefetched_failure_aborts() {
+    let buffer = BlockingRingBuffer::new(100);
+    buffer.put_blocking(&[7u8; 100]);
+    let opts = Options { prefetch: true, block_size: 10, ..test_options() };
+    let mut sink = FullSink { remaining: 25, written: Vec::new() };
+    assert!(write_output(&buffer, &mut sink, &opts, &IoCounters::default()).unwrap_err().kind() == io::ErrorKind::WriteZero);
+    assert!(buffer.is_aborted());
+}
+
+/// A sink that never accepts any data, blocking every write until `release` hangs up.
+#[cfg(test)]
+struct WedgedSink { release: mpsc::Receiver<()> }
+
+#[cfg(test)]
+impl Write for WedgedSink {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        let _ = self.release.recv();
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "released"))
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn test_shutdown_with_wedged_writer() {
+    let buffer = Arc::new(BlockingRingBuffer::new(16));
+    let (release, wedge) = mpsc::channel();
+    let (done, finished) = mpsc::channel::<()>();
+    let handle = {
+        let buffer = buffer.clone();
+        thread::spawn(move || {
+            let _done = done;
+            write_output(&buffer, &mut WedgedSink { release: wedge }, &test_options(), &IoCounters::default())
+        })
+    };
+
+    // The writer is stuck in its first write, of what the reader filled the buffer with before closing it
+    assert!(buffer.put_blocking(&[1u8; 16]));
+    buffer.close();
+
+    let shutdown = { let buffer = buffer.clone(); thread::spawn(move || { thread::sleep(Duration::from_millis(100)); buffer.request_shutdown(); }) };
+    assert!(join_writer(handle, &finished, &buffer).unwrap_err().kind() == io::ErrorKind::Interrupted);
+    shutdown.join().unwrap();
+    drop(release);
+}
+
+#[test]
+fn test_passthrough() {
+    let data = test_data(100000);
+    let mut sink = FullSink { remaining: data.len(), written: Vec::new() };
+    passthrough(&mut io::Cursor::new(data.clone()), &mut sink, 1000).unwrap();
+    assert!(sink.written == data);
+
+    assert!(use_passthrough(&Options { max_size: 512, ..test_options() }));
+    assert!(!use_passthrough(&Options { max_size: 512, stats: true, ..test_options() }));
+    assert!(!use_passthrough(&Options { max_size: 512, min_read: Some(256), ..test_options() }));
+    assert!(!use_passthrough(&Options { max_size: 512, flush_interval: Some(FlushInterval::Never), ..test_options() }));
+    assert!(!use_passthrough(&test_options()));
+}
+
+#[test]
+fn test_timestamp() {
+    assert!(timestamp(UNIX_EPOCH) == "00:00:00.000");
+    assert!(timestamp(UNIX_EPOCH + Duration::from_millis(86400 * 1000 * 3 + 47_565_120)) == "13:12:45.120");
+}
+
+#[test]
+fn test_run_on_drain() {
+    assert!(run_on_drain("exit 0", true).is_ok());
+    assert!(run_on_drain("exit 3", true).unwrap_err().to_string().contains("exit status: 3"));
+}
+
+#[test]
+fn test_run_in_memory() {
+    let data = test_data(100000);
+    let opts = Options { block_size: 1000, initial_size: 10000, max_size: 10000, count_lines: true, quiet: true, ..test_options() };
+    let output = SharedSink::default();
+    let counters = Arc::new(IoCounters::default());
+    run(opts, Box::new(io::Cursor::new(data.clone())), Box::new(output.clone()), MetricsSink::Stderr, counters.clone(), Vec::new(), false).unwrap();
+    assert!(*output.written.lock().unwrap() == data);
+    assert!(counters.lines.load(Ordering::Relaxed) == data.iter().filter(|b| **b == b'\n').count() as u64 + 1);
+}
+
+#[test]
+fn test_run_returns_stats() {
+    let data = test_data(100000);
+    let opts = Options { block_size: 1000, initial_size: 10000, max_size: 10000, quiet: true, ..test_options() };
+    let stats = run(opts, Box::new(io::Cursor::new(data.clone())), Box::new(SharedSink::default()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).unwrap();
+    assert!(stats.bytes == data.len() as u64 && stats.discarded == 0);
+    assert!(stats.capacity == 10000 && stats.high_water_mark > 0 && stats.high_water_mark <= 10000);
+
+    // Without the ring buffer only the bytes are counted
+    let opts = Options { block_size: 1000, initial_size: 500, max_size: 500, quiet: true, ..test_options() };
+    let output = SharedSink::default();
+    let stats = run(opts, Box::new(io::Cursor::new(data.clone())), Box::new(output.clone()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).unwrap();
+    assert!(*output.written.lock().unwrap() == data);
+    assert!(stats.bytes == data.len() as u64 && stats.capacity == 0 && stats.high_water_mark == 0);
+}
+
+#[test]
+fn test_run_checksum_verify() {
+    let data = b"123456789".repeat(1000);
+    let mut crc = Crc32::new();
+    crc.update(&data);
+    for (expected, ok) in [(crc.value(), true), (crc.value() ^ 1, false)].iter() {
+        let opts = Options { block_size: 1000, initial_size: 3000, max_size: 3000, record_size: Some(7), checksum_verify: Some(*expected), quiet: true, ..test_options() };
+        let output = SharedSink::default();
+        let result = run(opts, Box::new(io::Cursor::new(data.clone())), Box::new(output.clone()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false);
+        assert!(result.is_ok() == *ok);
+        if !ok { assert!(result.unwrap_err().kind() == io::ErrorKind::InvalidData); }
+        assert!(*output.written.lock().unwrap() == data);
+    }
+}
+
+/// A source that yields `data` only after `delay`.
+#[cfg(test)]
+struct SlowSource { delay: Duration, data: io::Cursor<Vec<u8>> }
+
+#[cfg(test)]
+impl Read for SlowSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(mem::take(&mut self.delay));
+        self.data.read(buf)
+    }
+}
+
+#[test]
+fn test_run_input_timeout() {
+    let data = b"late but welcome".to_vec();
+    let opts = Options { input_timeout: Some(Duration::from_millis(100)), quiet: true, ..test_options() };
+    let output = SharedSink::default();
+    let input = SlowSource { delay: Duration::from_millis(1000), data: io::Cursor::new(data.clone()) };
+    let e = run(opts.clone(), Box::new(input), Box::new(output.clone()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).unwrap_err();
+    assert!(e.kind() == io::ErrorKind::TimedOut && e.get_ref().unwrap().is::<NoInput>());
+    assert!(e.to_string() == "no input arrived within 100ms");
+    assert!(output.written.lock().unwrap().is_empty());
+
+    // Input that starts in time is read to the end, however long that takes
+    let output = SharedSink::default();
+    let input = SlowSource { delay: Duration::from_millis(20), data: io::Cursor::new(data.clone()) };
+    run(opts, Box::new(input), Box::new(output.clone()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).unwrap();
+    assert!(*output.written.lock().unwrap() == data);
+}
+
+#[test]
+fn test_run_discard() {
+    let data = test_data(100000);
+    let opts = Options { block_size: 1000, initial_size: 10000, max_size: 10000, discard: true, quiet: true, ..test_options() };
+    let output = open_output(&opts).unwrap();
+    let stats = run(opts, Box::new(io::Cursor::new(data.clone())), output, MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).unwrap();
+    assert!(stats.bytes == data.len() as u64);
+}
+
+#[test]
+fn test_resume_after_interruption() {
+    let dir = env::temp_dir();
+    let input_path  = dir.join(format!("pipebuffer-test-{}-resume-input", std::process::id()));
+    let output_path = dir.join(format!("pipebuffer-test-{}-resume-output", std::process::id()));
+    let checkpoint_path = dir.join(format!("pipebuffer-test-{}-resume-checkpoint", std::process::id()));
+    let data = test_data(100000);
+    fs::write(&input_path, &data).unwrap();
+    let opts = Options { block_size: 1000, initial_size: 10000, max_size: 10000, output: Some(output_path.clone()),
+                         checkpoint: Some(checkpoint_path.clone()), quiet: true, ..test_options() };
+
+    // The first run fails part way, and then a few bytes it wrote after the last checkpoint are left behind
+    let input = FailingSource { data: io::Cursor::new(data[0..40000].to_vec()) };
+    assert!(run(opts.clone(), Box::new(input), open_output(&opts).unwrap(), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).is_err());
+    assert!(checkpoint::load(&checkpoint_path).unwrap() == 40000);
+    fs::OpenOptions::new().append(true).open(&output_path).unwrap().write_all(&data[40000..40500]).unwrap();
+
+    // Resuming skips what was written and cuts off what wasn't recorded, leaving the whole input
+    let opts = Options { inputs: vec![input_path.clone()], resume_from: Some(checkpoint::load(&checkpoint_path).unwrap()), ..opts };
+    let counters = Arc::new(IoCounters::default());
+    let (metrics, input, output) = open_endpoints(&opts, &counters, None, None).unwrap();
+    let stats = run(opts, input, output, metrics, counters, Vec::new(), false).unwrap();
+    assert!(stats.bytes == 60000);
+    assert!(fs::read(&output_path).unwrap() == data);
+    assert!(checkpoint::load(&checkpoint_path).unwrap() == 100000);
+    for path in [input_path, output_path, checkpoint_path].iter() { fs::remove_file(path).unwrap(); }
+}
+
+#[test]
+fn test_run_inspect_leaves_output_unchanged() {
+    let data = test_data(100000);
+    let opts = Options { block_size: 1000, initial_size: 10000, max_size: 10000, inspect: Some(64), ..test_options() };
+    let output = SharedSink::default();
+    run(opts, Box::new(io::Cursor::new(data.clone())), Box::new(output.clone()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false).unwrap();
+    assert!(*output.written.lock().unwrap() == data);
+}
+
+/// A source that yields `data` and then fails.
+#[cfg(test)]
+struct FailingSource { data: io::Cursor<Vec<u8>> }
+
+#[cfg(test)]
+impl Read for FailingSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.data.read(buf)? {
+            0 => Err(io::Error::new(io::ErrorKind::InvalidData, "bad sector")),
+            n => Ok(n)
+        }
+    }
+}
+
+#[test]
+fn test_run_writes_out_what_was_read_before_a_read_error() {
+    let data = test_data(25000);
+    let opts = Options { block_size: 1000, initial_size: 100000, max_size: 100000, quiet: true, ..test_options() };
+    let output = SharedSink::default();
+    let input = FailingSource { data: io::Cursor::new(data.clone()) };
+    let result = run(opts, Box::new(input), Box::new(output.clone()), MetricsSink::Stderr, Arc::new(IoCounters::default()), Vec::new(), false);
+    let e = result.unwrap_err();
+    assert!(e.kind() == io::ErrorKind::InvalidData);
+    assert!(e.to_string() == "could not read the input: bad sector");
+    assert!(*output.written.lock().unwrap() == data);
+}
+
+/// An output that records how many times it has been flushed and whether it has been synced.
+#[cfg(test)]
+#[derive(Default)]
+struct SyncRecorder { flushes: usize, synced: bool }
+
+#[cfg(test)]
+impl Write for SyncRecorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+    fn flush(&mut self) -> io::Result<()> { self.flushes += 1; Ok(()) }
+}
+
+#[cfg(test)]
+impl Output for SyncRecorder {
+    fn sync(&mut self) -> io::Result<()> { self.synced = true; Ok(()) }
+}
+
+#[test]
+fn test_no_flush_flushes_only_at_eof() {
+    for (interval, flushes) in [(None, 11), (Some(FlushInterval::Bytes(300)), 4), (Some(FlushInterval::Never), 1)].iter() {
+        let opts = Options { block_size: 100, flush_interval: *interval, ..test_options() };
+        let buffer = BlockingRingBuffer::new(1000);
+        buffer.put_blocking(&[0u8; 1000]);
+        buffer.close();
+        let mut output = SyncRecorder::default();
+        write_output(&buffer, &mut output, &opts, &IoCounters::default()).unwrap();
+        assert!(output.flushes == *flushes);
+    }
+}
+
+#[test]
+fn test_finish_output_syncs() {
+    let mut output = SyncRecorder::default();
+    finish_output(&mut output, &test_options()).unwrap();
+    assert!(!output.synced);
+    finish_output(&mut output, &Options { fsync: true, ..test_options() }).unwrap();
+    assert!(output.synced);
+
+    // And a real file can be synced
+    let path = env::temp_dir().join(format!("pipebuffer-test-{}-fsync", std::process::id()));
+    let opts = Options { output: Some(path.clone()), fsync: true, ..test_options() };
+    let mut file = open_output(&opts).unwrap();
+    write_fully(&mut file, b"durable").unwrap();
+    assert!(finish_output(&mut *file, &opts).is_ok());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_open_output_appends() {
+    let path = env::temp_dir().join(format!("pipebuffer-test-{}-append", std::process::id()));
+    let opts = Options { output: Some(path.clone()), ..test_options() };
+    write_fully(&mut open_output(&opts).unwrap(), b"overwritten").unwrap();
+    write_fully(&mut open_output(&opts).unwrap(), b"first ").unwrap();
+
+    let opts = Options { append: true, ..opts };
+    write_fully(&mut open_output(&opts).unwrap(), b"second").unwrap();
+    assert!(fs::read(&path).unwrap() == b"first second");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_fast_copy() {
+    let data = test_data(300007);
+    let input  = temp_file("fast-input", &data);
+    let output = env::temp_dir().join(format!("pipebuffer-test-{}-fast-output", std::process::id()));
+
+    let opts = Options { inputs: vec![input.clone()], output: Some(output.clone()), fast_copy: true, ..test_options() };
+    drop(open_output(&opts).unwrap());
+    assert!(use_fast_copy(&opts));
+    assert!(fast_copy(&opts).unwrap() == Some(data.len() as u64));
+    assert!(fs::read(&output).unwrap() == data);
+
+    // Not without being asked, nor when anything needs to see the data or the output is appended to
+    assert!(!use_fast_copy(&Options { fast_copy: false, ..opts.clone() }));
+    assert!(!use_fast_copy(&Options { stats: true, ..opts.clone() }));
+    assert!(!use_fast_copy(&Options { append: true, ..opts.clone() }));
+    assert!(!use_fast_copy(&Options { inputs: vec![input.clone(), input.clone()], ..opts.clone() }));
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_open_output_connects() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let opts = Options { connect: Some(listener.local_addr().unwrap().to_string()), ..test_options() };
+    {
+        let mut output = open_output(&opts).unwrap();
+        write_fully(&mut output, b"over the wire").unwrap();
+    }
+
+    let mut received = Vec::new();
+    listener.accept().unwrap().0.read_to_end(&mut received).unwrap();
+    assert!(received == b"over the wire");
+}
+
+#[test]
+fn test_open_output_reports_failed_connection() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap(); // closed again immediately
+    let opts = Options { connect: Some(addr.to_string()), ..test_options() };
+    let err = open_output(&opts).err().unwrap();
+    assert!(err.to_string().starts_with(&format!("could not connect to {}", addr)));
+}
+
+#[test]
+fn test_unix_socket_round_trip() {
+    let path = env::temp_dir().join(format!("pipebuffer-test-{}.sock", std::process::id()));
+    fs::write(&path, b"").unwrap(); // not a socket, so must be refused
+    let opts = Options { unix_listen: Some(path.clone()), unix_connect: Some(path.clone()), ..test_options() };
+    assert!(open_input(&opts).err().unwrap().kind() == io::ErrorKind::AlreadyExists);
+    fs::remove_file(&path).unwrap();
+
+    let reader = {
+        let opts = opts.clone();
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            open_input(&opts).unwrap().read_to_end(&mut received).unwrap();
+            received
+        })
+    };
+    while !path.exists() { thread::sleep(Duration::from_millis(1)); }
+    write_fully(&mut open_output(&opts).unwrap(), b"over the socket").unwrap();
+
+    assert!(reader.join().unwrap() == b"over the socket");
+    assert!(!path.exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_pin_to_cpu() {
+    thread::spawn(|| {
+        assert!(pin_to_cpu(0).is_ok());
+        assert!(pin_to_cpu(usize::MAX).is_err());
+    }).join().unwrap();
+}
@@ -0,0 +1,639 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A module that reports the progress of a transfer on stderr, either as a bar that is
+//! redrawn in place when stderr is a terminal, or as a plain line per update when not,
+//! or alternatively as one JSON object per update for consumption by other programs.
+//! Output goes to stderr unless a separate metrics file is given.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use blocking::BlockingRingBuffer;
+
+/// The shortest interval progress may be reported at, so as not to spend the time
+/// reporting that should be spent transferring.
+pub const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often the fill level of the buffer is sampled for the hint given with --stats
+const SAMPLE_MILLIS: u64 = 20;
+
+// The buffer counts as full when at least this percentage full, and as empty when at most this
+// percentage full
+const FULL_PCT : u64 = 90;
+const EMPTY_PCT: u64 = 10;
+
+// The percentage of samples that must find the buffer full, or empty, for a hint to be given
+const HINT_PCT: u64 = 75;
+
+// The fewest samples, i.e. a run of a fifth of a second, worth giving a hint for
+const MIN_SAMPLES: u64 = 10;
+
+// The width assumed for the terminal if it cannot be determined
+const DEFAULT_WIDTH: usize = 80;
+
+// ANSI escapes used to colour the filled part of the bar
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// How progress updates are rendered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A bar on a terminal, otherwise a line of text, per update.
+    Human,
+    /// A line of text per update, even on a terminal, for logs that are captured a
+    /// line at a time.
+    Plain,
+    /// A newline-delimited JSON object per update.
+    Json
+}
+
+impl Format {
+    /// Parses the value given to `--progress`, where no value means `Human`.
+    pub fn parse(s: Option<&str>) -> Option<Format> {
+        match s {
+            None | Some("bar") => Some(Format::Human),
+            Some("plain")      => Some(Format::Plain),
+            Some("json")       => Some(Format::Json),
+            _                  => None
+        }
+    }
+}
+
+/// When styled output, i.e. the coloured progress bar, is coloured, as given to `--color`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    /// Only on a terminal, and only if `NO_COLOR` is not set.
+    Auto,
+    /// Always, even when not on a terminal or if `NO_COLOR` is set.
+    Always,
+    /// Never.
+    Never
+}
+
+impl ColorChoice {
+    /// Parses the value given to `--color`.
+    pub fn parse(s: &str) -> Option<ColorChoice> {
+        match s {
+            "auto"   => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never"  => Some(ColorChoice::Never),
+            _        => None
+        }
+    }
+
+    /// Resolves the choice against the environment: with `Auto`, colour is never used if
+    /// `no_color`, i.e. `NO_COLOR` is set to anything non-empty.
+    pub fn with_no_color(self, no_color: bool) -> ColorChoice {
+        match self {
+            ColorChoice::Auto if no_color => ColorChoice::Never,
+            choice                        => choice
+        }
+    }
+
+    /// True if output is to be coloured, given whether it's written to a terminal.
+    pub fn enabled(self, tty: bool) -> bool {
+        match self {
+            ColorChoice::Auto   => tty,
+            ColorChoice::Always => true,
+            ColorChoice::Never  => false
+        }
+    }
+}
+
+/// Where progress and statistics are written: stderr, or a file or FIFO given with
+/// `--metrics-file`.  Metrics are secondary to the transfer itself, so writes to a
+/// file are best-effort: any that would block, or fail, are silently dropped.
+pub enum MetricsSink {
+    Stderr,
+    File(File)
+}
+
+impl MetricsSink {
+    /// Opens the file or FIFO at `path` for writing metrics, creating or truncating it
+    /// as needed.  The file is opened non-blocking, and also for reading so that
+    /// opening a FIFO that has no reader yet neither blocks nor fails.
+    pub fn open(path: &Path) -> io::Result<MetricsSink> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .custom_flags(libc::O_NONBLOCK).open(path)?;
+        Ok(MetricsSink::File(file))
+    }
+
+    /// Returns another handle on the same sink, for use from another thread.
+    pub fn try_clone(&self) -> io::Result<MetricsSink> {
+        match *self {
+            MetricsSink::Stderr      => Ok(MetricsSink::Stderr),
+            MetricsSink::File(ref f) => f.try_clone().map(MetricsSink::File)
+        }
+    }
+
+    /// True if the sink is a terminal, on which a progress bar can be redrawn in place.
+    pub fn is_terminal(&self) -> bool {
+        match *self {
+            MetricsSink::Stderr  => io::stderr().is_terminal(),
+            MetricsSink::File(_) => false
+        }
+    }
+}
+
+impl Write for MetricsSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MetricsSink::Stderr          => io::stderr().write(buf),
+            MetricsSink::File(ref mut f) => { let _ = f.write_all(buf); Ok(buf.len()) }
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MetricsSink::Stderr  => io::stderr().flush(),
+            MetricsSink::File(_) => Ok(())
+        }
+    }
+}
+
+/// How each progress update is drawn, chosen by `Renderer::select()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Renderer {
+    /// A JSON object per line.
+    Json,
+    /// A plain line of text per update.
+    Line,
+    /// A bar redrawn in place with `\r`, optionally coloured.
+    Bar { color: bool }
+}
+
+impl Renderer {
+    /// Selects the renderer for `format` given whether the sink is a terminal and when
+    /// to colour it: a bar only on a terminal, and coloured as `color` decides.
+    pub fn select(format: Format, tty: bool, color: ColorChoice) -> Renderer {
+        match format {
+            Format::Json         => Renderer::Json,
+            Format::Human if tty => Renderer::Bar { color: color.enabled(tty) },
+            Format::Human        => Renderer::Line,
+            Format::Plain        => Renderer::Line
+        }
+    }
+}
+
+/// The state of a transfer at the time a progress update is made.
+pub struct Progress {
+    /// The bytes written to the output so far.
+    pub transferred: u64,
+    /// The total number of bytes expected, if known.
+    pub expected   : Option<u64>,
+    /// The recent rate of transfer, in bytes per second.
+    pub rate       : f64,
+    /// The percentage of the buffer that is currently full.
+    pub fill_pct   : f64,
+    /// The time since the transfer started.
+    pub elapsed    : Duration
+}
+
+/// Reports progress on `sink`, sampling `buffer` every `interval` until a message is
+/// received on, or the sender hangs up, `done`, at which point a final update is always
+/// made, however soon after the previous one.
+///
+/// Each update is built from a single `snapshot()` of the buffer, taken without holding
+/// up the reader or writer, so the figures in it are from the same moment to within a
+/// copy or two.  Colour is used as `color`, already resolved
+/// against `NO_COLOR`, decides for the sink.
+pub fn report(buffer: &BlockingRingBuffer<u8>, expected: Option<u64>, format: Format, color: ColorChoice,
+              interval: Duration, mut sink: MetricsSink, done: Receiver<()>) {
+    let renderer = Renderer::select(format, sink.is_terminal(), color);
+    let started = Instant::now();
+    let mut last_time = started;
+    let mut last_bytes = 0;
+
+    loop {
+        let timeout  = done.recv_timeout(interval);
+        let finished = !matches!(timeout, Err(RecvTimeoutError::Timeout));
+
+        let snapshot = buffer.snapshot();
+        let now = Instant::now();
+        let seconds = (now - last_time).as_secs_f64();
+        let progress = Progress {
+            transferred: snapshot.total_got,
+            expected,
+            rate       : if seconds > 0.0 { (snapshot.total_got - last_bytes) as f64 / seconds } else { 0.0 },
+            fill_pct   : 100.0 * snapshot.len as f64 / snapshot.capacity as f64,
+            elapsed    : now - started
+        };
+        last_time  = now;
+        last_bytes = snapshot.total_got;
+
+        match renderer {
+            Renderer::Json          => writeln!(&mut sink, "{}", render_json(&progress)).unwrap(),
+            Renderer::Line          => writeln!(&mut sink, "{}", render_line(&progress)).unwrap(),
+            Renderer::Bar { color } => write!(&mut sink, "\r{}", render_bar(&progress, terminal_width(), color)).unwrap()
+        }
+
+        if finished {
+            if let Renderer::Bar { .. } = renderer { writeln!(&mut sink).unwrap(); }
+            break;
+        }
+    }
+}
+
+/// Counts of how often the buffer was found full or empty when its fill level was
+/// sampled, from which `hint()` suggests which end of the pipeline is the bottleneck.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FillSamples {
+    pub samples: u64,
+    pub full   : u64,
+    pub empty  : u64
+}
+
+impl FillSamples {
+    /// Records a sample of a buffer holding `len` of `capacity` items.  The buffer is
+    /// taken to be full from `FULL_PCT` percent and empty up to `EMPTY_PCT` percent,
+    /// since data moves in blocks and so the buffer is seldom exactly either.
+    pub fn record(&mut self, len: usize, capacity: usize) {
+        let (len, capacity) = (len as u64, capacity as u64);
+        self.samples += 1;
+        if len * 100 >= capacity * FULL_PCT  { self.full  += 1; }
+        if len * 100 <= capacity * EMPTY_PCT { self.empty += 1; }
+    }
+
+    /// Returns a one-line hint if the buffer was found full, or empty, in at least
+    /// `HINT_PCT` percent of at least `MIN_SAMPLES` samples, and otherwise `None`.
+    pub fn hint(&self) -> Option<String> {
+        if self.samples < MIN_SAMPLES { return None; }
+        let full_pct  = self.full  * 100 / self.samples;
+        let empty_pct = self.empty * 100 / self.samples;
+        if full_pct >= HINT_PCT {
+            Some(format!("buffer was full {}% of the time; the consumer is the bottleneck.", full_pct))
+        }
+        else if empty_pct >= HINT_PCT {
+            Some(format!("buffer was empty {}% of the time; increase producer speed or reduce --size.", empty_pct))
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// Samples the fill level of `buffer` every `SAMPLE_MILLIS` until a message is received
+/// on, or the sender hangs up, `done`, and returns the samples taken.
+pub fn sample_fill(buffer: &BlockingRingBuffer<u8>, done: Receiver<()>) -> FillSamples {
+    let mut samples = FillSamples::default();
+    while let Err(RecvTimeoutError::Timeout) = done.recv_timeout(Duration::from_millis(SAMPLE_MILLIS)) {
+        let snapshot = buffer.snapshot();
+        samples.record(snapshot.len, snapshot.capacity);
+    }
+    samples
+}
+
+/// The fill level of the buffer over one interval of `log_fill()`, as percentages of
+/// its capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FillWindow {
+    pub samples: u64,
+    pub min_pct: f64,
+    pub max_pct: f64,
+    pub sum_pct: f64
+}
+
+impl FillWindow {
+    /// Records a sample of a buffer holding `len` of `capacity` items.
+    pub fn record(&mut self, len: usize, capacity: usize) {
+        let pct = 100.0 * len as f64 / capacity as f64;
+        if self.samples == 0 || pct < self.min_pct { self.min_pct = pct; }
+        if self.samples == 0 || pct > self.max_pct { self.max_pct = pct; }
+        self.sum_pct += pct;
+        self.samples += 1;
+    }
+
+    /// Returns the average of the samples recorded, or zero if there are none.
+    pub fn avg_pct(&self) -> f64 {
+        if self.samples == 0 { 0.0 } else { self.sum_pct / self.samples as f64 }
+    }
+}
+
+/// Logs the fill level of `buffer` to `sink` as a JSON object per `interval`, giving the
+/// minimum, maximum and average of samples taken every `SAMPLE_MILLIS`, and the
+/// percentage of the interval the producer spent blocked on a full buffer and the
+/// consumer on an empty one.  Runs until a message is received on, or the sender hangs
+/// up, `done`, at which point the interval so far is logged.  Each sample is a single
+/// `snapshot()`, so holds the lock only briefly.
+pub fn log_fill(buffer: &BlockingRingBuffer<u8>, interval: Duration, mut sink: MetricsSink, done: Receiver<()>) {
+    let started = Instant::now();
+    let mut window_start = started;
+    let mut window = FillWindow::default();
+    let mut last = buffer.snapshot();
+    loop {
+        let timeout  = done.recv_timeout(Duration::from_millis(SAMPLE_MILLIS));
+        let finished = !matches!(timeout, Err(RecvTimeoutError::Timeout));
+
+        let snapshot = buffer.snapshot();
+        window.record(snapshot.len, snapshot.capacity);
+        let now = Instant::now();
+        if finished || now - window_start >= interval {
+            let span = (now - window_start).as_secs_f64();
+            let blocked_pct = |blocked: Duration| if span > 0.0 { (100.0 * blocked.as_secs_f64() / span).min(100.0) } else { 0.0 };
+            writeln!(&mut sink, "{}", render_fill_window(&window, now - started,
+                     blocked_pct(snapshot.blocked_on_full.saturating_sub(last.blocked_on_full)),
+                     blocked_pct(snapshot.blocked_on_empty.saturating_sub(last.blocked_on_empty)))).unwrap();
+            window = FillWindow::default();
+            window_start = now;
+            last = snapshot;
+        }
+        if finished { break; }
+    }
+}
+
+/// Renders a `FillWindow` ending `elapsed` into the transfer as a single-line JSON
+/// object, along with the percentages of it the producer and consumer spent blocked.
+pub fn render_fill_window(w: &FillWindow, elapsed: Duration, producer_blocked_pct: f64, consumer_blocked_pct: f64) -> String {
+    format!("{{\"elapsed_secs\":{:.3},\"fill_min_pct\":{:.1},\"fill_max_pct\":{:.1},\"fill_avg_pct\":{:.1},\
+             \"producer_blocked_pct\":{:.1},\"consumer_blocked_pct\":{:.1}}}",
+            elapsed.as_secs_f64(), w.min_pct, w.max_pct, w.avg_pct(), producer_blocked_pct, consumer_blocked_pct)
+}
+
+/// Renders progress as a single line of text, without a bar.
+pub fn render_line(p: &Progress) -> String {
+    let mut line = format!("{} {}/s buffer {:.0}% elapsed {}",
+                           format_bytes(p.transferred), format_bytes(p.rate as u64), p.fill_pct,
+                           format_duration(p.elapsed));
+    if let Some(expected) = p.expected {
+        line.push_str(&format!(" {:.0}% of {} ETA {}", percent(p.transferred, expected),
+                               format_bytes(expected), format_eta(p, expected)));
+    }
+    line
+}
+
+/// Renders progress as a line sized to fill `width` columns, including a bar showing
+/// the fraction of the expected total transferred if that is known.  With `color` the
+/// filled part of the bar is coloured, the escapes not counting towards the width.
+pub fn render_bar(p: &Progress, width: usize, color: bool) -> String {
+    let text = format!("{:>10} {:>10}/s buf {:>3.0}%", format_bytes(p.transferred), format_bytes(p.rate as u64), p.fill_pct);
+    match p.expected {
+        None           => format!("{:<width$}", format!("{} {}", text, format_duration(p.elapsed)), width = width.saturating_sub(1)),
+        Some(expected) => {
+            let pct    = percent(p.transferred, expected);
+            let suffix = format!(" {:>3.0}% ETA {}", pct, format_eta(p, expected));
+            let bar_width = width.saturating_sub(text.len() + suffix.len() + 4);
+            let filled = cmp::min(bar_width, (bar_width as f64 * pct / 100.0) as usize);
+            let (on, off) = if color { (GREEN, RESET) } else { ("", "") };
+            format!("{} [{}{}{}{}]{}", text, on, "=".repeat(filled), off, " ".repeat(bar_width - filled), suffix)
+        }
+    }
+}
+
+/// Renders progress as a single-line JSON object.
+pub fn render_json(p: &Progress) -> String {
+    format!("{{\"bytes_total\":{},\"rate_bps\":{:.1},\"buffer_fill_pct\":{:.1},\"elapsed_secs\":{:.3}}}",
+            p.transferred, p.rate, p.fill_pct, p.elapsed.as_secs_f64())
+}
+
+/// Returns the percentage of `expected` that `transferred` represents, capped at 100.
+fn percent(transferred: u64, expected: u64) -> f64 {
+    if expected == 0 { 100.0 } else { (100.0 * transferred as f64 / expected as f64).min(100.0) }
+}
+
+/// Formats the estimated time to transfer the remainder of `expected` at the current rate.
+fn format_eta(p: &Progress, expected: u64) -> String {
+    let remaining = expected.saturating_sub(p.transferred);
+    if remaining == 0    { format_duration(Duration::new(0, 0)) }
+    else if p.rate < 1.0 { "--:--:--".to_string() }
+    else                 { format_duration(Duration::from_secs((remaining as f64 / p.rate).ceil() as u64)) }
+}
+
+/// Formats a number of bytes using binary units, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, units[unit]) }
+}
+
+/// Formats a duration as `h:mm:ss`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+/// Returns the width of the terminal attached to stderr, or a default if unknown.
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { ::std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col > 0 { size.ws_col as usize } else { DEFAULT_WIDTH }
+}
+
+#[test]
+fn test_format_bytes() {
+    assert!(format_bytes(0) == "0 B");
+    assert!(format_bytes(1023) == "1023 B");
+    assert!(format_bytes(1024) == "1.0 KiB");
+    assert!(format_bytes(1536 * 1024) == "1.5 MiB");
+}
+
+#[test]
+fn test_format_duration() {
+    assert!(format_duration(Duration::from_secs(5)) == "0:00:05");
+    assert!(format_duration(Duration::from_secs(3725)) == "1:02:05");
+}
+
+#[test]
+fn test_render_with_expected_size() {
+    let p = Progress {
+        transferred: 512 * 1024, expected: Some(1024 * 1024), rate: 128.0 * 1024.0,
+        fill_pct: 50.0, elapsed: Duration::from_secs(4)
+    };
+    assert!(render_line(&p) == "512.0 KiB 128.0 KiB/s buffer 50% elapsed 0:00:04 50% of 1.0 MiB ETA 0:00:04");
+
+    let bar = render_bar(&p, 80, false);
+    assert!(bar.len() == 79);
+    assert!(bar.contains("[=") && bar.ends_with(" 50% ETA 0:00:04"));
+
+    let colored = render_bar(&p, 80, true);
+    assert!(colored.len() == 79 + GREEN.len() + RESET.len());
+    assert!(colored.contains(GREEN) && colored.contains(RESET));
+}
+
+#[test]
+fn test_render_json() {
+    let p = Progress {
+        transferred: 2048, expected: None, rate: 1024.0, fill_pct: 12.5, elapsed: Duration::from_millis(2500)
+    };
+    assert!(render_json(&p) == r#"{"bytes_total":2048,"rate_bps":1024.0,"buffer_fill_pct":12.5,"elapsed_secs":2.500}"#);
+}
+
+#[test]
+fn test_select_renderer() {
+    assert!(Renderer::select(Format::Human, true,  ColorChoice::Auto)  == Renderer::Bar { color: true });
+    assert!(Renderer::select(Format::Human, true,  ColorChoice::Never) == Renderer::Bar { color: false });
+    assert!(Renderer::select(Format::Human, false, ColorChoice::Always) == Renderer::Line);
+    assert!(Renderer::select(Format::Plain, true,  ColorChoice::Auto)  == Renderer::Line);
+    assert!(Renderer::select(Format::Json,  true,  ColorChoice::Auto)  == Renderer::Json);
+}
+
+#[test]
+fn test_color_choice() {
+    assert!(ColorChoice::parse("auto") == Some(ColorChoice::Auto));
+    assert!(ColorChoice::parse("always") == Some(ColorChoice::Always));
+    assert!(ColorChoice::parse("never") == Some(ColorChoice::Never));
+    assert!(ColorChoice::parse("yes").is_none());
+
+    // (choice, NO_COLOR set, on a terminal) => coloured
+    let cases = [
+        (ColorChoice::Auto,   false, true,  true),
+        (ColorChoice::Auto,   false, false, false),
+        (ColorChoice::Auto,   true,  true,  false),
+        (ColorChoice::Auto,   true,  false, false),
+        (ColorChoice::Always, false, true,  true),
+        (ColorChoice::Always, false, false, true),
+        (ColorChoice::Always, true,  true,  true),
+        (ColorChoice::Always, true,  false, true),
+        (ColorChoice::Never,  false, true,  false),
+        (ColorChoice::Never,  false, false, false),
+        (ColorChoice::Never,  true,  true,  false),
+        (ColorChoice::Never,  true,  false, false)
+    ];
+    for &(choice, no_color, tty, expected) in cases.iter() {
+        assert!(choice.with_no_color(no_color).enabled(tty) == expected);
+    }
+}
+
+#[test]
+fn test_parse_format() {
+    assert!(Format::parse(None) == Some(Format::Human));
+    assert!(Format::parse(Some("bar")) == Some(Format::Human));
+    assert!(Format::parse(Some("plain")) == Some(Format::Plain));
+    assert!(Format::parse(Some("json")) == Some(Format::Json));
+    assert!(Format::parse(Some("xml")).is_none());
+}
+
+#[test]
+fn test_metrics_sink_fifo_without_reader() {
+    let path = ::std::env::temp_dir().join(format!("pipebuffer-test-{}.fifo", ::std::process::id()));
+    let c_path = ::std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    assert!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } == 0);
+
+    // Opening must not block, and writing far more than a pipe holds must not either
+    let mut sink = MetricsSink::open(&path).unwrap();
+    let line = [b'x'; 1024];
+    for _ in 0..1024 { sink.write_all(&line).unwrap(); }
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_fill_hint() {
+    let sampled = |full: usize, empty: usize, between: usize| {
+        let mut samples = FillSamples::default();
+        for _ in 0..full    { samples.record(95, 100); }
+        for _ in 0..empty   { samples.record(5, 100); }
+        for _ in 0..between { samples.record(50, 100); }
+        samples
+    };
+    assert!(sampled(92, 0, 8).hint().unwrap() == "buffer was full 92% of the time; the consumer is the bottleneck.");
+    assert!(sampled(0, 80, 20).hint().unwrap() == "buffer was empty 80% of the time; increase producer speed or reduce --size.");
+    assert!(sampled(50, 50, 0).hint().is_none());
+    assert!(sampled(60, 0, 40).hint().is_none());
+    assert!(sampled(5, 0, 0).hint().is_none());
+
+    let mut samples = FillSamples::default();
+    samples.record(100, 100);
+    samples.record(0, 100);
+    assert!(samples == FillSamples { samples: 2, full: 1, empty: 1 });
+}
+
+#[test]
+fn test_report_interval() {
+    let path = ::std::env::temp_dir().join(format!("pipebuffer-test-{}-progress", ::std::process::id()));
+    let buffer = BlockingRingBuffer::new(1000);
+    buffer.put_blocking(&[0u8; 500]);
+    let (sender, receiver) = ::std::sync::mpsc::channel();
+    let sink = MetricsSink::open(&path).unwrap();
+    let reporter = ::std::thread::spawn(move || {
+        report(&buffer, None, Format::Human, ColorChoice::Never, Duration::from_millis(40), sink, receiver)
+    });
+    ::std::thread::sleep(Duration::from_millis(150));
+    drop(sender);
+    reporter.join().unwrap();
+
+    // Three or so updates while running, and always a final one
+    let lines = ::std::fs::read_to_string(&path).unwrap().lines().count();
+    assert!((3..=5).contains(&lines));
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_report_plain() {
+    let path = ::std::env::temp_dir().join(format!("pipebuffer-test-{}-progress-plain", ::std::process::id()));
+    let buffer = BlockingRingBuffer::new(1000);
+    buffer.put_blocking(&[0u8; 500]);
+    let (sender, receiver) = ::std::sync::mpsc::channel();
+    let sink = MetricsSink::open(&path).unwrap();
+    let reporter = ::std::thread::spawn(move || {
+        report(&buffer, Some(1000), Format::Plain, ColorChoice::Always, Duration::from_millis(40), sink, receiver)
+    });
+    ::std::thread::sleep(Duration::from_millis(100));
+    drop(sender);
+    reporter.join().unwrap();
+
+    // A complete line per update, with no carriage returns or escapes
+    let text = ::std::fs::read_to_string(&path).unwrap();
+    assert!(text.lines().count() >= 2 && text.ends_with('\n'));
+    assert!(!text.contains('\r') && !text.contains('\x1b'));
+    assert!(text.lines().all(|line| line.starts_with("0 B 0 B/s buffer 50%")));
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_fill_window() {
+    let mut window = FillWindow::default();
+    assert!(window.avg_pct() == 0.0);
+    for len in [30, 10, 80, 40].iter() { window.record(*len, 200); }
+    assert!(window.min_pct == 5.0 && window.max_pct == 40.0 && window.avg_pct() == 20.0);
+    assert!(render_fill_window(&window, Duration::from_millis(1500), 12.5, 0.0) ==
+            r#"{"elapsed_secs":1.500,"fill_min_pct":5.0,"fill_max_pct":40.0,"fill_avg_pct":20.0,"producer_blocked_pct":12.5,"consumer_blocked_pct":0.0}"#);
+}
+
+#[test]
+fn test_log_fill() {
+    let buffer: BlockingRingBuffer<u8> = BlockingRingBuffer::new(100);
+    buffer.put_blocking(&[0u8; 50]);
+    let path = ::std::env::temp_dir().join(format!("pipebuffer-test-{}-fill-log", ::std::process::id()));
+    let sink = MetricsSink::open(&path).unwrap();
+    let (sender, receiver) = ::std::sync::mpsc::channel();
+    ::std::thread::scope(|scope| {
+        scope.spawn(|| log_fill(&buffer, Duration::from_millis(100), sink, receiver));
+        ::std::thread::sleep(Duration::from_millis(250));
+        drop(sender);
+    });
+
+    let log = ::std::fs::read_to_string(&path).unwrap();
+    ::std::fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert!(lines.len() >= 2 && lines.len() <= 4);
+    assert!(lines.iter().all(|line| line.contains(r#""fill_min_pct":50.0,"fill_max_pct":50.0,"fill_avg_pct":50.0,"producer_blocked_pct":0.0,"consumer_blocked_pct":0.0"#)));
+}
@@ -24,6 +24,9 @@
 
 use std::cmp;
 use std::clone::Clone;
+use std::fmt;
+use std::io;
+use std::io::Read;
 
 /// Implementation of a non-blocking, fixed size ring-buffer.
 /// Allocates enough space on the heap to store `size` items.  Provides
@@ -38,6 +41,13 @@ use std::clone::Clone;
 /// After calls to `close()`, further attempts to put into the buffer will
 /// cause panics, but `gets()` continue to be allowed in order to let the 
 /// buffer be drained.
+///
+/// Space is reserved for all `size` items up front, but slots are only initialized as
+/// items are first put into them: the backing `Vec` grows by appending until the first
+/// time the buffer wraps, after which every slot holds an item and puts overwrite by
+/// cloning.  No slot is ever read, cloned over or dropped before it has been written,
+/// so this is sound for any `T`, and for types like `u8` neither path costs more than
+/// a copy, without the buffer having to be zero-filled first.
 pub struct RingBuffer<T: Clone> {
     capacity          : usize,
     buffer            : Vec<T>,
@@ -45,25 +55,23 @@ pub struct RingBuffer<T: Clone> {
     available_to_write: usize,
     read_pos          : usize,
     available_to_read : usize,
+    high_water_mark   : usize,
     closed            : bool
 }
 
 impl<T: Clone> RingBuffer<T> {
     /// Constructs a new RingBuffer with capacity `size`.
     pub fn new (size: usize) -> RingBuffer<T> {
-        let mut buf = RingBuffer {
+        RingBuffer {
             capacity           : size,
             buffer             : Vec::with_capacity(size),
             write_pos          : 0,
             available_to_write : size,
             read_pos           : 0,
             available_to_read  : 0,
+            high_water_mark    : 0,
             closed             : false
-        };
-        
-        // Push 'size' up to capacity so we can use slices without having to fill first.
-        unsafe { buf.buffer.set_len(size); }
-        buf
+        }
     }
     
     /// Attempts to `put` items from the slice into the buffer. The only guarantees
@@ -79,25 +87,98 @@ impl<T: Clone> RingBuffer<T> {
     ///
     /// # Return
     /// The number of items, `>= 0`, that were put into the buffer.
-
+    ///
     /// # Panics
     /// Will panic if invoked on a closed buffer.
     pub fn put(&mut self, input: &[T]) -> usize {
         if self.closed { panic!("Cannot write to closed buffer."); }
         if self.available_to_write == 0 { return 0; }
         
-        let distance_to_end = self.capacity - self.write_pos;
-        let available       = cmp::min(distance_to_end, self.available_to_write);
-        let length          = cmp::min(available, input.len());
-        let target_slice = &mut self.buffer[self.write_pos..self.write_pos+length];
-        let source_slice = &input[0..length];
-        target_slice.clone_from_slice(source_slice);
+        let length = cmp::min(self.writable_contiguous(), input.len());
+
+        // Overwrite slots that already hold an item, then append into any never yet written
+        let end         = self.write_pos + length;
+        let initialized = cmp::max(self.write_pos, cmp::min(end, self.buffer.len()));
+        self.buffer[self.write_pos..initialized].clone_from_slice(&input[0..initialized-self.write_pos]);
+        self.buffer.extend_from_slice(&input[initialized-self.write_pos..length]);
         self.available_to_write -= length;
         self.available_to_read  += length;
         self.write_pos           = (self.write_pos + length) % self.capacity;        
+        self.high_water_mark     = cmp::max(self.high_water_mark, self.available_to_read);
         length
     }
     
+    /// Puts as many items from the slice into the buffer as it will take, calling `put`
+    /// repeatedly so that items continue from the start of the buffer when the end is
+    /// reached.  Unlike `put`, this only puts fewer than all of `input` if the buffer
+    /// fills up.
+    ///
+    /// # Return
+    /// The number of items, `>= 0`, that were put into the buffer.
+    ///
+    /// # Panics
+    /// Will panic if invoked on a closed buffer.
+    pub fn put_all(&mut self, input: &[T]) -> usize {
+        let mut start = 0;
+        while start < input.len() {
+            let n = self.put(&input[start..]);
+            if n == 0 { break; }
+            start += n;
+        }
+        start
+    }
+
+    /// Puts a single item into the buffer, moving rather than cloning it.
+    ///
+    /// # Return
+    /// True if the item was put, or false if the buffer is full.
+    ///
+    /// # Panics
+    /// Will panic if invoked on a closed buffer.
+    pub fn put_one(&mut self, item: T) -> bool {
+        if self.closed { panic!("Cannot write to closed buffer."); }
+        if self.available_to_write == 0 { return false; }
+
+        if self.write_pos < self.buffer.len() { self.buffer[self.write_pos] = item; } else { self.buffer.push(item); }
+        self.available_to_write -= 1;
+        self.available_to_read  += 1;
+        self.write_pos           = (self.write_pos + 1) % self.capacity;
+        self.high_water_mark     = cmp::max(self.high_water_mark, self.available_to_read);
+        true
+    }
+
+    /// Puts items pulled from `iter` into the buffer until it is full or the iterator is
+    /// exhausted, continuing from the start of the buffer when the end is reached.  No
+    /// item is pulled that there isn't room for, so any left in `iter` can still be put
+    /// once the buffer has been drained.
+    ///
+    /// # Return
+    /// The number of items, `>= 0`, that were put into the buffer.
+    ///
+    /// # Panics
+    /// Will panic if invoked on a closed buffer.
+    pub fn put_from_iter<I: Iterator<Item=T>>(&mut self, iter: &mut I) -> usize {
+        if self.closed { panic!("Cannot write to closed buffer."); }
+        let mut count = 0;
+        while !self.is_full() {
+            match iter.next() {
+                Some(item) => { self.put_one(item); count += 1; },
+                None       => break
+            }
+        }
+        count
+    }
+
+    /// Gets a single item from the buffer, or `None` if it is empty.
+    pub fn get_one(&mut self) -> Option<T> {
+        if self.available_to_read == 0 { return None; }
+        let item = self.buffer[self.read_pos].clone();
+        self.read_pos            = (self.read_pos + 1) % self.capacity;
+        self.available_to_read  -= 1;
+        self.available_to_write += 1;
+        Some(item)
+    }
+
     /// Attempts to `get` items from the buffer and put them into the slice.
     /// The only guarantees made by this method are:
     ///
@@ -110,9 +191,9 @@ impl<T: Clone> RingBuffer<T> {
     /// # Return
     /// The number of items, `>= 0`, that were fetched from the buffer.
     pub fn get(&mut self, output: &mut [T]) -> usize {
-        let distance_to_end = self.capacity - self.read_pos;
-        let available       = cmp::min(distance_to_end, self.available_to_read);
-        let length          = cmp::min(available, output.len());
+        if self.available_to_read == 0 { return 0; }
+
+        let length = cmp::min(self.readable_contiguous(), output.len());
         let source_slice = & self.buffer[self.read_pos..self.read_pos+length];
         let target_slice = &mut output[0..length];
         target_slice.clone_from_slice(source_slice);
@@ -122,6 +203,111 @@ impl<T: Clone> RingBuffer<T> {
         length
     }
     
+    /// Gets exactly `output.len()` items from the buffer, filling `output`, if that many
+    /// are available, or else gets nothing and leaves `output` untouched.  Unlike `get`,
+    /// it fetches across the end of the buffer in one call, so suits consumers that
+    /// need whole fixed-size frames.
+    ///
+    /// # Return
+    /// True if `output` was filled, or false if fewer than `output.len()` items were
+    /// available.
+    pub fn get_exact(&mut self, output: &mut [T]) -> bool {
+        if output.len() > self.available_to_read { return false; }
+        let n = self.get(output);
+        if n < output.len() { self.get(&mut output[n..]); }
+        true
+    }
+
+    /// Discards up to `n` items from the front of the buffer without copying them,
+    /// which is cheaper than `get`-ing them into a buffer that is thrown away.  Unlike
+    /// `get`, it will skip across the end of the buffer in one call.
+    ///
+    /// # Return
+    /// The number of items, `<= n`, that were discarded.
+    pub fn skip(&mut self, n: usize) -> usize {
+        let length = cmp::min(n, self.available_to_read);
+        if length == 0 { return 0; }
+
+        self.available_to_read  -= length;
+        self.available_to_write += length;
+        self.read_pos = (self.read_pos + length) % self.capacity;
+        length
+    }
+
+    /// Returns an iterator that removes items from the buffer in the order they were
+    /// put, stopping when the buffer is empty.  Items not consumed from the iterator
+    /// are left in the buffer.
+    pub fn drain<'a>(&'a mut self) -> Drain<'a, T> {
+        Drain { buffer: self }
+    }
+
+    /// Grows the buffer to hold `new_capacity` items, as with `resize()`.
+    ///
+    /// # Panics
+    /// Will panic if `new_capacity` is smaller than the current capacity.
+    pub fn grow(&mut self, new_capacity: usize) {
+        if new_capacity < self.capacity { panic!("Cannot grow buffer to a smaller capacity."); }
+        self.resize(new_capacity);
+    }
+
+    /// Changes the buffer to hold `new_capacity` items.  The buffered items are copied,
+    /// in the order they will be read, to the start of a newly allocated buffer, so
+    /// the buffer no longer wraps after resizing.  A buffer cannot be shrunk to fewer
+    /// items than it holds, so a smaller `new_capacity` is clamped to `len()`.
+    ///
+    /// # Return
+    /// The new capacity of the buffer.
+    pub fn resize(&mut self, new_capacity: usize) -> usize {
+        let length       = self.available_to_read;
+        let new_capacity = cmp::max(new_capacity, length);
+        let first  = cmp::min(self.capacity - self.read_pos, length);
+        let mut buffer = Vec::with_capacity(new_capacity);
+        buffer.extend_from_slice(&self.buffer[self.read_pos..self.read_pos+first]);
+        buffer.extend_from_slice(&self.buffer[0..length-first]);
+
+        self.buffer             = buffer;
+        self.capacity           = new_capacity;
+        self.read_pos           = 0;
+        self.write_pos          = if length == new_capacity { 0 } else { length };
+        self.available_to_write = new_capacity - length;
+        new_capacity
+    }
+
+    /// Writes `value` to every slot of the buffer that has never held an item, so that
+    /// the memory behind it is committed now rather than page by page as the buffer
+    /// first fills.  The buffered items, and what `get()` returns, are unaffected.
+    /// Storage added later by `resize()` is again committed lazily.
+    pub fn prefault(&mut self, value: T) {
+        let capacity = self.capacity;
+        self.buffer.resize(capacity, value);
+    }
+
+    /// Returns the number of items the buffer can hold.
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    /// Returns the number of items currently available to `get()`.
+    pub fn len(&self) -> usize { self.available_to_read }
+
+    /// Returns the number of items a single `get()` can fetch: those available up to
+    /// the end of the buffer, where reading wraps around to the start.
+    pub fn readable_contiguous(&self) -> usize { cmp::min(self.capacity - self.read_pos, self.available_to_read) }
+
+    /// Returns the items available to `get()` in place, in the order they would be got,
+    /// as two slices: those up to the end of the buffer, and those that wrap around to
+    /// its start, which is empty unless they wrap.  Together with `skip()` this lets the
+    /// items be written out, e.g. with `write_vectored()`, without being copied first.
+    pub fn readable_slices(&self) -> (&[T], &[T]) {
+        let first = self.readable_contiguous();
+        (&self.buffer[self.read_pos..self.read_pos+first], &self.buffer[0..self.available_to_read-first])
+    }
+
+    /// Returns the number of items a single `put()` can take: the free slots up to the
+    /// end of the buffer, where writing wraps around to the start.
+    pub fn writable_contiguous(&self) -> usize { cmp::min(self.capacity - self.write_pos, self.available_to_write) }
+
+    /// Returns the largest number of items that have been in the buffer at once.
+    pub fn high_water_mark(&self) -> usize { self.high_water_mark }
+
     /// Returns true if the buffer is currently empty, implying calls to `get()` will 
     /// yield zero items.
     pub fn is_empty(&self) -> bool { self.available_to_read == 0 }
@@ -130,11 +316,146 @@ impl<T: Clone> RingBuffer<T> {
     /// consume zero items.
     pub fn is_full(&self) -> bool { self.available_to_write == 0 }
     
-    /// Closes the buffer such that future calls to `put()` will panic.
-    pub fn close(&mut self) -> () { self.closed = true; }
+    /// Closes the buffer such that future calls to `put()` will panic.  Closing an
+    /// already closed buffer has no further effect; only `clear()` reopens it.
+    pub fn close(&mut self) { self.closed = true; }
+
+    /// Empties and reopens the buffer, discarding any items not yet got and resetting
+    /// the high water mark, so that its allocation can be reused for another stream.
+    /// The capacity is unchanged.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.write_pos          = 0;
+        self.available_to_write = self.capacity;
+        self.read_pos           = 0;
+        self.available_to_read  = 0;
+        self.high_water_mark    = 0;
+        self.closed             = false;
+    }
     
     /// Returns true if the buffer is closed, and false otherwise.
     pub fn is_closed(&self) -> bool { self.closed }
+
+    /// Returns a copy of the items available to `get()`, in the order they would be
+    /// got, leaving the buffer unchanged.
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    /// Returns an iterator over the items available to `get()`, in the order they would
+    /// be got, leaving the buffer unchanged: the borrowing counterpart of `to_vec()` and
+    /// `drain()`, which allocates nothing.  Where the items wrap around the end of the
+    /// buffer the iterator runs on from the start of it.
+    pub fn iter(&self) -> impl Iterator<Item=&T> {
+        let first = self.readable_contiguous();
+        self.buffer[self.read_pos..self.read_pos+first].iter()
+            .chain(self.buffer[0..self.available_to_read-first].iter())
+    }
+}
+
+// The most items shown by the Debug output of a buffer
+const DEBUG_ITEMS: usize = 16;
+
+/// Shows the capacity and length of the buffer, and the items available to `get()` in
+/// order, up to `DEBUG_ITEMS` of them so as not to print the whole of a large buffer.
+impl<T: Clone + fmt::Debug> fmt::Debug for RingBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Items<'a, T: Clone + 'a>(&'a RingBuffer<T>);
+        impl<'a, T: Clone + fmt::Debug> fmt::Debug for Items<'a, T> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let mut list = f.debug_list();
+                list.entries(self.0.iter().take(DEBUG_ITEMS));
+                if self.0.len() > DEBUG_ITEMS { list.entry(&format_args!("... {} more", self.0.len() - DEBUG_ITEMS)); }
+                list.finish()
+            }
+        }
+
+        f.debug_struct("RingBuffer")
+            .field("capacity", &self.capacity)
+            .field("len", &self.available_to_read)
+            .field("closed", &self.closed)
+            .field("items", &Items(self))
+            .finish()
+    }
+}
+
+/// Buffers are equal if they have the same capacity and hold the same items in the
+/// same order, regardless of where in the buffer those items happen to be stored.
+impl<T: Clone + PartialEq> PartialEq for RingBuffer<T> {
+    fn eq(&self, other: &RingBuffer<T>) -> bool {
+        self.capacity == other.capacity && self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Clone + Default> RingBuffer<T> {
+    /// Returns the free space from the write position up to the end of the buffer, or
+    /// up to the items yet to be read if they come first, to be filled in place, e.g. by
+    /// reading into it, with no staging copy.  When the free space wraps around the end
+    /// of the buffer only the first part is returned; the rest is available once that
+    /// has been committed.  Slots that have never held an item are filled with the
+    /// default value first.  Nothing is put into the buffer until `commit_write()`.
+    ///
+    /// # Panics
+    /// Will panic if invoked on a closed buffer.
+    pub fn writable_slice(&mut self) -> &mut [T] {
+        if self.closed { panic!("Cannot write to closed buffer."); }
+        let end = self.write_pos + self.writable_contiguous();
+        if self.buffer.len() < end { self.buffer.resize(end, T::default()); }
+        &mut self.buffer[self.write_pos..end]
+    }
+
+    /// Puts the first `n` items of the slice last returned by `writable_slice()` into
+    /// the buffer, as if they had been `put()`.
+    ///
+    /// # Panics
+    /// Will panic if `n` is longer than that slice.
+    pub fn commit_write(&mut self, n: usize) {
+        if n > self.writable_contiguous() || self.write_pos + n > self.buffer.len() { panic!("Cannot commit more than the writable slice."); }
+        if n == 0 { return; }
+        self.available_to_write -= n;
+        self.available_to_read  += n;
+        self.write_pos           = (self.write_pos + n) % self.capacity;
+        self.high_water_mark     = cmp::max(self.high_water_mark, self.available_to_read);
+    }
+}
+
+impl RingBuffer<u8> {
+    /// Constructs a new RingBuffer with capacity `size`, primed with as many bytes read
+    /// from `src` as it will hold.  Reading stops when the buffer is full or `src`
+    /// reaches EOF, so any space left over remains available to `put()` into.
+    ///
+    /// # Errors
+    /// Returns the first error from `src` other than `Interrupted`, which is retried.
+    pub fn filled_from<R: Read>(size: usize, src: &mut R) -> io::Result<RingBuffer<u8>> {
+        let mut buf = RingBuffer::new(size);
+        while !buf.is_full() {
+            match src.read(buf.writable_slice()) {
+                Ok(0)  => break,
+                Ok(n)  => buf.commit_write(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(buf)
+    }
+}
+
+/// An iterator that removes and yields the items in a `RingBuffer`, created by
+/// `RingBuffer::drain()`.
+pub struct Drain<'a, T: Clone + 'a> {
+    buffer: &'a mut RingBuffer<T>
+}
+
+impl<'a, T: Clone> Iterator for Drain<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.buffer.get_one()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.buffer.available_to_read, Some(self.buffer.available_to_read))
+    }
 }
 
 #[test]
@@ -166,5 +487,515 @@ fn test_read_on_empty_buffer() {
     let mut xs: [u8; 10] = [7; 10];
     let n = buffer.get(&mut xs);
     assert!(n == 0);
-    for i in 0..10 { assert!(xs[i] == 7); }
-}
\ No newline at end of file
+    for x in xs.iter() { assert!(*x == 7); }
+}
+
+#[test]
+fn test_grow_wrapped_buffer() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(10);
+    let mut ys: [u8; 10] = [0; 10];
+    buffer.put(&[0,1,2,3,4,5,6,7]);
+    buffer.get(&mut ys[0..6]);
+    buffer.put(&[8,9]);
+    assert!(buffer.put(&[10,11,12]) == 3); // wraps around to the start of the buffer
+
+    buffer.grow(20);
+    assert!(buffer.capacity() == 20);
+    assert!(buffer.put(&[13,14,15,16,17,18,19,20,21,22,23,24,25]) == 13);
+    assert!(buffer.is_full());
+
+    let mut zs: [u8; 20] = [0; 20];
+    assert!(buffer.get(&mut zs) == 20);
+    for (i, z) in zs.iter().enumerate() { assert!(*z as usize == i + 6); }
+}
+
+#[test]
+fn test_grow_full_buffer() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(4);
+    buffer.put(&[1,2,3,4]);
+    buffer.grow(8);
+    assert!(!buffer.is_full());
+    assert!(buffer.put(&[5,6,7,8,9]) == 4);
+
+    let mut ys: [u8; 8] = [0; 8];
+    assert!(buffer.get(&mut ys) == 8);
+    assert!(ys == [1,2,3,4,5,6,7,8]);
+}
+
+#[test]
+fn test_resize_unwrapped_buffer() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    buffer.put(&[1,2,3,4]);
+    assert!(buffer.resize(16) == 16);
+    assert!(buffer.put(&[5,6,7,8,9,10,11,12,13,14,15,16]) == 12 && buffer.is_full());
+    assert!(buffer.drain().collect::<Vec<u8>>() == (1..17).collect::<Vec<u8>>());
+
+    buffer.put(&[1,2,3]);
+    assert!(buffer.resize(4) == 4);
+    assert!(buffer.put(&[4,5]) == 1);
+    assert!(buffer.drain().collect::<Vec<u8>>() == vec![1,2,3,4]);
+}
+
+#[test]
+fn test_resize_wrapped_buffer() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    buffer.put(&[0,1,2,3,4,5]);
+    buffer.skip(4);
+    buffer.put_all(&[6,7,8,9,10]); // wraps around to the start of the buffer
+    assert!(buffer.resize(12) == 12);
+    assert!(buffer.put(&[11,12,13,14,15]) == 5 && buffer.is_full());
+    assert!(buffer.drain().collect::<Vec<u8>>() == (4..16).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_resize_clamps_to_len() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    buffer.put(&[0,1,2,3,4,5,6]);
+    buffer.skip(5);
+    buffer.put_all(&[7,8,9]); // wraps around to the start of the buffer
+    assert!(buffer.resize(2) == 5 && buffer.is_full());
+    assert!(buffer.drain().collect::<Vec<u8>>() == vec![5,6,7,8,9]);
+    assert!(buffer.resize(0) == 0 && buffer.put(&[1]) == 0);
+}
+
+#[test]
+fn test_high_water_mark() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(10);
+    let mut ys: [u8; 10] = [0; 10];
+    assert!(buffer.high_water_mark() == 0);
+    buffer.put(&[0,1,2,3]);
+    buffer.put(&[4,5]);
+    buffer.get(&mut ys);
+    assert!(buffer.is_empty());
+    assert!(buffer.high_water_mark() == 6);
+    buffer.put(&[0,1,2]);
+    assert!(buffer.high_water_mark() == 6);
+}
+
+#[test]
+fn test_drain_across_wrap() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    let mut ys: [u8; 5] = [0; 5];
+    buffer.put(&[0,1,2,3,4]);
+    buffer.get(&mut ys);
+    buffer.put(&[5,6,7]);
+    buffer.put(&[8,9,10]); // wraps around to the start of the buffer
+
+    let drained: Vec<u8> = buffer.drain().collect();
+    assert!(drained == vec![5,6,7,8,9,10]);
+    assert!(buffer.is_empty());
+    assert!(buffer.put(&[0; 8]) == 5 && buffer.put(&[0; 3]) == 3);
+}
+
+#[test]
+fn test_partial_drain() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    buffer.put(&[1,2,3,4]);
+    let first: Vec<u8> = buffer.drain().take(3).collect();
+    assert!(first == vec![1,2,3]);
+    assert!(buffer.len() == 1);
+    assert!(buffer.drain().next() == Some(4));
+    assert!(buffer.drain().next().is_none());
+}
+
+#[test]
+fn test_put_one_and_get_one() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(3);
+    assert!(buffer.get_one().is_none());
+    assert!(buffer.put_one(1) && buffer.put_one(2) && buffer.put_one(3));
+    assert!(!buffer.put_one(4));
+    assert!(buffer.get_one() == Some(1));
+    assert!(buffer.put_one(4)); // wraps around to the start of the buffer
+    assert!(buffer.get_one() == Some(2) && buffer.get_one() == Some(3) && buffer.get_one() == Some(4));
+    assert!(buffer.get_one().is_none());
+    assert!(buffer.high_water_mark() == 3);
+}
+
+#[test]
+fn test_put_one_non_copy_items() {
+    let mut buffer : RingBuffer<String> = RingBuffer::new(2);
+    assert!(buffer.put_one("a".to_string()) && buffer.put_one("b".to_string()));
+    assert!(!buffer.put_one("c".to_string()));
+    assert!(buffer.get_one() == Some("a".to_string()));
+    assert!(buffer.put_one("c".to_string()));
+    assert!(buffer.drain().collect::<Vec<String>>() == vec!["b", "c"]);
+}
+
+#[test]
+fn test_put_from_iter() {
+    let mut buffer : RingBuffer<u32> = RingBuffer::new(5);
+    let mut items = 0..12;
+    assert!(buffer.put_from_iter(&mut items) == 5);
+    assert!(buffer.is_full() && buffer.to_vec() == vec![0,1,2,3,4]);
+
+    // Nothing is pulled from the iterator once the buffer is full
+    assert!(buffer.put_from_iter(&mut items) == 0);
+    assert!(items.next() == Some(5));
+
+    // Continues from the start of the buffer, stopping when the iterator runs out
+    assert!(buffer.skip(3) == 3);
+    assert!(buffer.put_from_iter(&mut items) == 3);
+    assert!(buffer.to_vec() == vec![3,4,6,7,8]);
+    assert!(buffer.skip(5) == 5);
+    assert!(buffer.put_from_iter(&mut items.by_ref().map(|n| n * 10)) == 3);
+    assert!(buffer.to_vec() == vec![90,100,110]);
+    assert!(buffer.put_from_iter(&mut items) == 0);
+}
+
+#[test]
+#[should_panic]
+fn test_put_from_iter_closed() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(5);
+    buffer.close();
+    buffer.put_from_iter(&mut (0..3));
+}
+
+#[test]
+fn test_to_vec() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(5);
+    let mut ys: [u8; 3] = [0; 3];
+    assert!(buffer.to_vec().is_empty());
+    buffer.put(&[1,2,3,4]);
+    assert!(buffer.to_vec() == vec![1,2,3,4]);
+    buffer.get(&mut ys);
+    buffer.put_all(&[5,6,7]); // wraps around to the start of the buffer
+    assert!(buffer.to_vec() == vec![4,5,6,7]);
+    assert!(buffer.len() == 4);
+}
+
+#[test]
+fn test_iter_across_wrap() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(6);
+    let mut ys: [u8; 4] = [0; 4];
+    assert!(buffer.iter().next().is_none());
+    buffer.put(&[1,2,3,4,5]);
+    buffer.get(&mut ys);
+    buffer.put_all(&[6,7,8,9]); // wraps around to the start of the buffer
+    assert!(buffer.readable_contiguous() == 2);
+
+    let items: Vec<&u8> = buffer.iter().collect();
+    assert!(items == vec![&5,&6,&7,&8,&9]);
+    assert!(buffer.len() == 5 && buffer.get_one() == Some(5)); // nothing was consumed
+}
+
+#[test]
+fn test_readable_slices() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(6);
+    assert!(buffer.readable_slices() == (&[][..], &[][..]));
+    buffer.put(&[1,2,3,4,5]);
+    assert!(buffer.readable_slices() == (&[1,2,3,4,5][..], &[][..]));
+
+    // Across the wrap, then consumed in part with skip()
+    buffer.skip(3);
+    buffer.put_all(&[6,7,8]);
+    assert!(buffer.readable_slices() == (&[4,5,6][..], &[7,8][..]));
+    buffer.skip(4);
+    assert!(buffer.readable_slices() == (&[8][..], &[][..]));
+
+    let empty : RingBuffer<u8> = RingBuffer::new(0);
+    assert!(empty.readable_slices() == (&[][..], &[][..]));
+}
+
+#[test]
+fn test_equality_and_debug() {
+    let mut a : RingBuffer<u8> = RingBuffer::new(4);
+    let mut b : RingBuffer<u8> = RingBuffer::new(4);
+    a.put(&[9,9,1,2]);
+    a.skip(2);
+    b.put(&[1,2]);
+    assert!(a == b); // same items though stored at different positions
+    b.put(&[3]);
+    assert!(a != b);
+    assert!(a != RingBuffer::new(8));
+
+    assert!(format!("{:?}", a) == "RingBuffer { capacity: 4, len: 2, closed: false, items: [1, 2] }");
+    let mut big : RingBuffer<u8> = RingBuffer::new(1000);
+    big.put(&[7; 100]);
+    assert!(format!("{:?}", big).ends_with("7, 7, ... 84 more] }"));
+}
+
+#[test]
+fn test_skip_within_region() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    let mut ys: [u8; 8] = [0; 8];
+    buffer.put(&[1,2,3,4,5]);
+    assert!(buffer.skip(2) == 2);
+    assert!(buffer.len() == 3);
+    assert!(buffer.get(&mut ys) == 3 && ys[0..3] == [3,4,5]);
+    assert!(buffer.skip(1) == 0);
+}
+
+#[test]
+fn test_skip_more_than_available() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    buffer.put(&[1,2,3]);
+    assert!(buffer.skip(10) == 3);
+    assert!(buffer.is_empty());
+    assert!(buffer.put_all(&[0; 8]) == 8);
+    assert!(RingBuffer::<u8>::new(0).skip(1) == 0);
+}
+
+#[test]
+fn test_skip_across_wrap() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(8);
+    buffer.put(&[0,1,2,3,4,5]);
+    assert!(buffer.skip(5) == 5);
+    buffer.put_all(&[6,7,8,9,10]); // wraps around to the start of the buffer
+    assert!(buffer.skip(4) == 4);
+
+    let rest: Vec<u8> = buffer.drain().collect();
+    assert!(rest == vec![9,10]);
+    assert!(buffer.put(&[0; 8]) == 5 && buffer.put(&[0; 3]) == 3);
+}
+
+#[test]
+fn test_put_all_across_wrap() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(10);
+    let mut ys: [u8; 10] = [0; 10];
+    buffer.put(&[0,1,2,3,4,5,6]);
+    buffer.get(&mut ys[0..7]);
+
+    // A single put would stop at the end of the ring, but put_all continues from the start
+    assert!(buffer.put_all(&[10,11,12,13,14,15]) == 6);
+    assert!(buffer.len() == 6);
+
+    // Only four more fit, so the rest are left
+    assert!(buffer.put_all(&[16,17,18,19,20,21]) == 4);
+    assert!(buffer.is_full());
+    assert!(buffer.put_all(&[22]) == 0);
+
+    let drained: Vec<u8> = buffer.drain().collect();
+    assert!(drained == vec![10,11,12,13,14,15,16,17,18,19]);
+}
+
+#[test]
+fn test_get_on_empty_zero_capacity_buffer() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(0);
+    let mut ys: [u8; 4] = [0; 4];
+    assert!(buffer.put(&[1, 2]) == 0);
+    assert!(buffer.get(&mut ys) == 0);
+}
+
+#[test]
+fn test_commit_write_on_zero_capacity_buffer() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(0);
+    assert!(buffer.writable_slice().is_empty());
+    buffer.commit_write(0);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_partial_put_and_get_at_wrap() {
+    let mut buffer : RingBuffer<u8> = RingBuffer::new(10);
+    let mut ys: [u8; 10] = [0; 10];
+    buffer.put(&[0,1,2,3,4,5,6]);
+    buffer.get(&mut ys[0..7]);
+
+    // Only the three slots before the end of the ring can be written in one put
+    assert!(buffer.put(&[10,11,12,13,14,15]) == 3);
+    assert!(buffer.put(&[13,14,15]) == 3);
+
+    // And likewise only the three items before the end can be read in one get
+    assert!(buffer.get(&mut ys) == 3);
+    assert!(ys[0..3] == [10,11,12]);
+    assert!(buffer.get(&mut ys) == 3);
+    assert!(ys[0..3] == [13,14,15]);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_fifo_across_many_wraps() {
+    // A capacity that shares no factors with the chunk sizes, so that operations
+    // start and end at every offset and regularly straddle the wrap point
+    let mut buffer : RingBuffer<u32> = RingBuffer::new(17);
+    let total = 10000;
+    let mut next_put: u32 = 0;
+    let mut next_get: u32 = 0;
+    let mut ys: [u32; 19] = [0; 19];
+    let mut i = 0;
+
+    while next_get < total {
+        // Put a chunk of between 1 and 23 items, looping until it is all in or the buffer is full
+        let chunk: Vec<u32> = (next_put..cmp::min(total, next_put + (i % 23) as u32 + 1)).collect();
+        let mut start = 0;
+        while start < chunk.len() {
+            let n = buffer.put(&chunk[start..]);
+            if n == 0 { assert!(buffer.is_full()); break; }
+            start += n;
+        }
+        next_put += start as u32;
+
+        // Get between 1 and 19 items, looping until satisfied or the buffer is empty
+        let wanted = i % 19 + 1;
+        let mut got = 0;
+        while got < wanted {
+            let n = buffer.get(&mut ys[got..wanted]);
+            if n == 0 { assert!(buffer.is_empty()); break; }
+            got += n;
+        }
+        for y in ys[0..got].iter() {
+            assert!(*y == next_get);
+            next_get += 1;
+        }
+        i += 1;
+    }
+    assert!(buffer.is_empty());
+}
+
+/// An item that is neither `Copy` nor `Default`, and counts its live instances.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+struct Counted { value: String, live: ::std::rc::Rc<()> }
+
+#[test]
+fn test_non_copy_items() {
+    let live = ::std::rc::Rc::new(());
+    let item = |i: usize| Counted { value: i.to_string(), live: live.clone() };
+    {
+        let mut buffer : RingBuffer<Counted> = RingBuffer::new(5);
+        let mut ys: Vec<Counted> = (100..103).map(item).collect();
+        assert!(buffer.put_all(&(0..4).map(item).collect::<Vec<_>>()) == 4);
+        assert!(buffer.get(&mut ys) == 3);
+        assert!(ys.iter().map(|y| y.value.as_str()).collect::<Vec<_>>() == vec!["0", "1", "2"]);
+
+        // Wrap around, overwriting slots already written, then grow
+        assert!(buffer.put_all(&(4..8).map(item).collect::<Vec<_>>()) == 4);
+        buffer.grow(8);
+        assert!(buffer.put_all(&(8..12).map(item).collect::<Vec<_>>()) == 3);
+        let drained: Vec<String> = buffer.drain().map(|c| c.value).collect();
+        assert!(drained == vec!["3", "4", "5", "6", "7", "8", "9", "10"]);
+    }
+    // Every clone made by the buffer has been dropped exactly once
+    assert!(::std::rc::Rc::strong_count(&live) == 1);
+}
+
+#[test]
+fn test_filled_from_over_full_source() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut buffer = RingBuffer::filled_from(10, &mut io::Cursor::new(data)).unwrap();
+    assert!(buffer.is_full());
+    let mut ys: [u8; 10] = [0; 10];
+    assert!(buffer.get(&mut ys) == 10);
+    assert!(ys == [0,1,2,3,4,5,6,7,8,9]);
+}
+
+#[test]
+fn test_filled_from_short_source() {
+    let mut buffer = RingBuffer::filled_from(10, &mut io::Cursor::new(vec![1u8, 2, 3])).unwrap();
+    assert!(buffer.len() == 3);
+    assert!(buffer.put_all(&[4, 5, 6, 7, 8, 9, 10, 11]) == 7);
+    let drained: Vec<u8> = buffer.drain().collect();
+    assert!(drained == vec![1,2,3,4,5,6,7,8,9,10]);
+}
+
+#[test]
+fn test_clear_reopens() {
+    let mut buffer: RingBuffer<u8> = RingBuffer::new(8);
+    let mut bytes = [0u8; 8];
+    buffer.put(&[1, 2, 3, 4, 5, 6]);
+    buffer.get(&mut bytes[0..4]);
+    buffer.close();
+    buffer.close();
+    assert!(buffer.is_closed());
+
+    buffer.clear();
+    assert!(!buffer.is_closed());
+    assert!(buffer.is_empty() && buffer.capacity() == 8 && buffer.high_water_mark() == 0);
+    assert!(buffer.put(&[7, 8, 9, 10, 11, 12, 13, 14, 15]) == 8);
+    assert!(buffer.get(&mut bytes) == 8);
+    assert!(bytes == [7, 8, 9, 10, 11, 12, 13, 14]);
+}
+
+#[test]
+fn test_get_exact() {
+    let mut buffer: RingBuffer<u8> = RingBuffer::new(8);
+    let mut frame = [0u8; 4];
+
+    // Fewer available than asked for leaves both the buffer and the output untouched
+    buffer.put(&[1, 2, 3]);
+    assert!(!buffer.get_exact(&mut frame));
+    assert!(frame == [0, 0, 0, 0] && buffer.len() == 3);
+
+    // Exactly as many as asked for
+    buffer.put(&[4]);
+    assert!(buffer.get_exact(&mut frame));
+    assert!(frame == [1, 2, 3, 4] && buffer.is_empty());
+
+    // More than asked for, across the end of the ring
+    buffer.put(&[0, 0]);
+    buffer.skip(2);
+    buffer.put_all(&[5, 6, 7, 8, 9, 10]);
+    assert!(buffer.get_exact(&mut frame));
+    assert!(frame == [5, 6, 7, 8] && buffer.len() == 2);
+}
+
+#[test]
+fn test_prefault() {
+    let mut buffer: RingBuffer<u8> = RingBuffer::new(8);
+    let mut bytes = [0u8; 8];
+    buffer.put(&[1, 2, 3]);
+    buffer.prefault(9);
+    assert!(buffer.len() == 3);
+    assert!(buffer.put_all(&[4, 5, 6, 7, 8]) == 5);
+    assert!(buffer.get(&mut bytes) == 8);
+    assert!(bytes == [1, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[test]
+fn test_writable_slice_matches_put() {
+    let mut put: RingBuffer<u8> = RingBuffer::new(8);
+    let mut filled: RingBuffer<u8> = RingBuffer::new(8);
+    let mut bytes = [0u8; 8];
+    for buffer in [&mut put, &mut filled].iter_mut() {
+        buffer.put(&[0, 0, 0, 0, 0]);
+        buffer.get(&mut bytes[0..5]);
+    }
+
+    // The free space wraps, so only the part up to the end of the buffer is writable at first
+    put.put_all(&[1, 2, 3, 4, 5, 6]);
+    {
+        let slice = filled.writable_slice();
+        assert!(slice.len() == 3);
+        slice.copy_from_slice(&[1, 2, 3]);
+    }
+    filled.commit_write(3);
+    {
+        let slice = filled.writable_slice();
+        assert!(slice.len() == 5);
+        slice[0..3].copy_from_slice(&[4, 5, 6]);
+    }
+    filled.commit_write(3);
+    assert!(filled == put);
+    assert!(filled.high_water_mark() == put.high_water_mark());
+    assert!(filled.writable_slice().len() == 2);
+}
+
+#[test]
+#[should_panic]
+fn test_commit_write_beyond_slice() {
+    let mut buffer: RingBuffer<u8> = RingBuffer::new(8);
+    buffer.writable_slice();
+    buffer.commit_write(9);
+}
+
+#[test]
+fn test_contiguous_near_wrap() {
+    let mut buffer: RingBuffer<u8> = RingBuffer::new(10);
+    assert!(buffer.readable_contiguous() == 0 && buffer.writable_contiguous() == 10);
+
+    // Read and write positions both at 7, so three slots to the end and the rest wrapped
+    buffer.put(&[0; 7]);
+    buffer.get(&mut [0; 7]);
+    assert!(buffer.writable_contiguous() == 3);
+    buffer.put_all(&[1; 5]);
+    assert!(buffer.len() == 5 && buffer.readable_contiguous() == 3);
+    assert!(buffer.writable_contiguous() == 5);
+
+    // Reading up to the end leaves the wrapped items contiguous from the start
+    buffer.get(&mut [0; 3]);
+    assert!(buffer.readable_contiguous() == 2);
+    assert!(buffer.writable_contiguous() == 8);
+    buffer.put_all(&[2; 8]);
+    assert!(buffer.is_full() && buffer.writable_contiguous() == 0);
+    assert!(buffer.readable_contiguous() == 10);
+}
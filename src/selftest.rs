@@ -0,0 +1,157 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A module implementing `--selftest`, which passes a pseudo-random stream through the
+//! same `PipeBuffer` used for a real transfer and checks it arrives intact.
+
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use pipebuffer::checksum::Crc32;
+use pipebuffer::pipe::{Options, PipeBuffer};
+use pipebuffer::progress::format_bytes;
+
+// The number of bytes passed through the buffer
+pub const SELFTEST_BYTES: u64 = 64 * 1024 * 1024;
+
+// The buffer is an odd size, not a multiple of the block size, so that reads and writes
+// straddle the end of the ring at every offset
+const SELFTEST_BUFFER: usize = 64 * 1024 + 1;
+const SELFTEST_BLOCK : usize = 4 * 1024;
+
+/// A small, fast pseudo-random number generator (Marsaglia's xorshift64), which is
+/// plenty for generating test data and choosing chunk sizes.
+pub struct XorShift {
+    state: u64
+}
+
+impl XorShift {
+    /// Constructs a generator from a seed; a zero seed is replaced, as xorshift would
+    /// otherwise only ever produce zeros.
+    pub fn new(seed: u64) -> XorShift {
+        XorShift { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+
+    /// Returns the next number in the sequence.
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    /// Returns a number between 1 and `max` inclusive.
+    pub fn between_one_and(&mut self, max: usize) -> usize {
+        1 + (self.next_u64() % max as u64) as usize
+    }
+
+    /// Fills `bytes` with pseudo-random bytes.
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let x = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&x[0..chunk.len()]);
+        }
+    }
+}
+
+/// A reader producing `remaining` pseudo-random bytes, in reads of random length, and
+/// keeping a checksum of what it produced.
+struct RandomSource { rng: XorShift, remaining: u64, crc: Arc<Mutex<Crc32>> }
+
+impl Read for RandomSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.remaining == 0 { return Ok(0); }
+        let n = cmp::min(self.rng.between_one_and(buf.len()) as u64, self.remaining) as usize;
+        self.rng.fill(&mut buf[0..n]);
+        self.crc.lock().unwrap().update(&buf[0..n]);
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// A writer that accepts a random part of each write, keeping a checksum and count of
+/// the bytes it accepted.
+struct ChecksumSink { rng: XorShift, bytes: Arc<AtomicU64>, crc: Arc<Mutex<Crc32>> }
+
+impl Write for ChecksumSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        let n = self.rng.between_one_and(buf.len());
+        self.crc.lock().unwrap().update(&buf[0..n]);
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// Passes `total` pseudo-random bytes from a reader thread, through a buffer, to a
+/// writer thread using a `PipeBuffer` configured by `opts`, with short reads and partial
+/// writes throughout, then reports the outcome on stdout.
+///
+/// # Return
+/// True if every byte arrived intact and in order, false otherwise.
+pub fn selftest(opts: &Options, total: u64) -> io::Result<bool> {
+    let opts = Options { block_size: SELFTEST_BLOCK, initial_size: SELFTEST_BUFFER, max_size: SELFTEST_BUFFER,
+                         pace: None, flush_interval: None, timeout: None, tees: Vec::new(), on_drain: None, ..opts.clone() };
+    let expected = Arc::new(Mutex::new(Crc32::new()));
+    let received = Arc::new(Mutex::new(Crc32::new()));
+    let bytes    = Arc::new(AtomicU64::new(0));
+    let source = RandomSource { rng: XorShift::new(0x5EED), remaining: total, crc: expected.clone() };
+    let sink = ChecksumSink { rng: XorShift::new(0xD1CE), bytes: bytes.clone(), crc: received.clone() };
+    let started = Instant::now();
+    let stats = PipeBuffer::with_options(opts).source(Box::new(source)).sink(Box::new(sink)).run()?;
+    let elapsed = started.elapsed();
+
+    let (bytes, expected, received) = (bytes.load(Ordering::Relaxed), expected.lock().unwrap().value(), received.lock().unwrap().value());
+    let passed = bytes == total && received == expected;
+    println!("Self-test {}: {} in {:.3}s ({}/s) through a {} byte buffer",
+             if passed { "passed" } else { "FAILED" }, format_bytes(bytes), elapsed.as_secs_f64(),
+             format_bytes((bytes as f64 / elapsed.as_secs_f64().max(1e-9)) as u64), SELFTEST_BUFFER);
+    println!("Checksum: expected {:08x}, received {:08x} over {} of {} bytes", expected, received, bytes, total);
+    println!("Reader blocked on full buffer:  {:.3}s", stats.blocked_on_full.as_secs_f64());
+    println!("Writer blocked on empty buffer: {:.3}s", stats.blocked_on_empty.as_secs_f64());
+    Ok(passed)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_xorshift_between_one_and() {
+    let mut rng = XorShift::new(0);
+    for _ in 0..1000 {
+        let n = rng.between_one_and(7);
+        assert!((1..=7).contains(&n));
+    }
+}
+
+#[test]
+fn test_selftest_passes() {
+    assert!(selftest(&super::test_options(), 1024 * 1024).unwrap());
+}
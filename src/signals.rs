@@ -0,0 +1,72 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A module that catches SIGTERM and SIGINT so that a transfer can be stopped
+//! gracefully, by draining what has already been buffered, rather than abruptly.
+//!
+//! The handler only records the signal in atomics, which is all that is safe to do in
+//! a signal handler; the main thread polls `received()` and `forced()` and acts on them.
+
+use std::io;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+// The first terminating signal received, or zero if none has been
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+// The number of terminating signals received
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Records the signal; the first is kept, and later ones only counted.
+extern "C" fn handle(signal: libc::c_int) {
+    let _ = RECEIVED.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst);
+    COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Installs the handler for SIGTERM and SIGINT.  Interrupted system calls are restarted,
+/// so the handler is invisible to the rest of the program except through this module.
+pub fn install() -> io::Result<()> {
+    for signal in [libc::SIGTERM, libc::SIGINT].iter() {
+        unsafe {
+            let mut action: libc::sigaction = ::std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*signal, &action, ::std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the first terminating signal received, if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0      => None,
+        signal => Some(signal)
+    }
+}
+
+/// True once a second terminating signal has been received, meaning the user wants to
+/// stop now rather than wait for the buffer to drain.
+pub fn forced() -> bool { COUNT.load(Ordering::SeqCst) >= 2 }
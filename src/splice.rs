@@ -0,0 +1,237 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! A zero-copy alternative to the ring buffer for when both the input and the output
+//! are pipes, using Linux's `splice(2)`: data is moved from the input into a second,
+//! enlarged, pipe that serves as the buffer, and from there to the output, all within
+//! the kernel, so it is never copied into or out of userspace.
+
+use std::convert::TryFrom;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// How often the calling thread, and the reader waiting on the input, check whether the transfer should stop
+const STOP_POLL: Duration = Duration::from_millis(50);
+
+/// A pipe, sized to hold the whole buffer, that input is spliced into and output
+/// spliced out of.
+pub struct BufferPipe {
+    read : OwnedFd,
+    write: OwnedFd
+}
+
+impl BufferPipe {
+    /// Creates a pipe holding at least `size` bytes.  Fails if the kernel won't make a
+    /// pipe that large, e.g. beyond `/proc/sys/fs/pipe-max-size` for an unprivileged
+    /// user, in which case the ring buffer must be used instead.
+    pub fn new(size: usize) -> io::Result<BufferPipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 { return Err(io::Error::last_os_error()); }
+        let pipe = unsafe { BufferPipe { read: OwnedFd::from_raw_fd(fds[0]), write: OwnedFd::from_raw_fd(fds[1]) } };
+
+        let size = libc::c_int::try_from(size).map_err(|_| io::Error::other("buffer is too large for a pipe"))?;
+        if unsafe { libc::fcntl(pipe.write.as_raw_fd(), libc::F_SETPIPE_SZ, size) } < 0 { return Err(io::Error::last_os_error()); }
+        Ok(pipe)
+    }
+}
+
+/// True if `fd` refers to a pipe (or FIFO).
+pub fn is_pipe(fd: RawFd) -> bool {
+    let mut stat: libc::stat = unsafe { ::std::mem::zeroed() };
+    unsafe { libc::fstat(fd, &mut stat) == 0 && (stat.st_mode & libc::S_IFMT) == libc::S_IFIFO }
+}
+
+/// Moves everything from the `input` pipe to the `output` pipe through `pipe`, at most
+/// `block_size` bytes at a time: a reader thread splices the input into the buffer
+/// pipe, blocking while it is full, and a writer thread splices from the buffer pipe to
+/// the output, until the input reaches EOF and the buffer pipe is drained.
+///
+/// Meanwhile the calling thread polls `stop` and `force`.  Once `stop` returns true the
+/// reader stops reading the input, closing the buffer pipe as if the input had ended,
+/// so the writer still passes on everything the reader took from the input before the
+/// transfer ends.  Once `force` returns true the transfer ends at once, abandoning
+/// whatever is left in the buffer pipe, and the threads, which may be blocked on the
+/// output, are left behind to be torn down with the process.
+///
+/// # Return
+/// The number of bytes transferred, or an `Interrupted` error if `force` ended it.
+pub fn transfer(pipe: BufferPipe, input: RawFd, output: RawFd, block_size: usize,
+                stop: &dyn Fn() -> bool, force: &dyn Fn() -> bool) -> io::Result<u64> {
+    let BufferPipe { read, write } = pipe;
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let reader = {
+        let stopping = stopping.clone();
+        thread::Builder::new().name("reader".to_string()).spawn(move || {
+            // The write end is closed on return, which the writer sees as EOF
+            splice_input(input, write.as_raw_fd(), block_size, &stopping)
+        })?
+    };
+    let (done, finished) = mpsc::channel::<()>();
+    let writer = thread::Builder::new().name("writer".to_string()).spawn(move || {
+        let _done = done;
+        // The read end is closed on return, so a reader blocked on a full pipe fails rather than waiting forever
+        splice_all(read.as_raw_fd(), output, block_size)
+    })?;
+
+    while let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(STOP_POLL) {
+        if force() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "shut down before the buffer pipe was drained"));
+        }
+        if stop() { stopping.store(true, Ordering::SeqCst); }
+    }
+
+    let written = writer.join().unwrap();
+    let read = reader.join().unwrap();
+    match (read, written) {
+        (_, Err(e))       => Err(e),
+        (Err(e), _)       => Err(e),
+        (Ok(_), Ok(n))    => Ok(n)
+    }
+}
+
+/// Splices from the `input` pipe to the buffer pipe `to`, `block_size` bytes at a time,
+/// until `input` reaches EOF or `stopping` is set.  The splices don't block, waiting
+/// instead with `poll()` for room in the buffer pipe and then for input, so `stopping`
+/// is seen within `STOP_POLL` even while no input arrives.
+fn splice_input(input: RawFd, to: RawFd, block_size: usize, stopping: &AtomicBool) -> io::Result<u64> {
+    let mut total = 0;
+    while !stopping.load(Ordering::SeqCst) {
+        let n = unsafe { libc::splice(input, ptr::null_mut(), to, ptr::null_mut(), block_size, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => {},
+                io::ErrorKind::WouldBlock => { if wait_for(to, libc::POLLOUT)? { wait_for(input, libc::POLLIN)?; } },
+                _                          => return Err(e)
+            }
+            continue;
+        }
+        if n == 0 { break; }
+        total += n as u64;
+    }
+    Ok(total)
+}
+
+/// Waits up to `STOP_POLL` for `fd` to be ready for the poll `events`, returning true
+/// if it is, or has hung up or failed, which the next splice will then report.
+fn wait_for(fd: RawFd, events: libc::c_short) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd, events, revents: 0 };
+    match unsafe { libc::poll(&mut pollfd, 1, STOP_POLL.as_millis() as libc::c_int) } {
+        n if n >= 0 => Ok(n > 0),
+        _           => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) }
+        }
+    }
+}
+
+/// Splices from `from` to `to`, `block_size` bytes at a time, until `from` reaches EOF.
+fn splice_all(from: RawFd, to: RawFd, block_size: usize) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let n = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), block_size, libc::SPLICE_F_MOVE) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { continue; }
+            return Err(e);
+        }
+        if n == 0 { return Ok(total); }
+        total += n as u64;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+/// Creates a pipe, returning its read and write ends as files.
+#[cfg(test)]
+fn pipe() -> (::std::fs::File, ::std::fs::File) {
+    let mut fds = [0; 2];
+    assert!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == 0);
+    unsafe { (::std::fs::File::from_raw_fd(fds[0]), ::std::fs::File::from_raw_fd(fds[1])) }
+}
+
+#[test]
+fn test_splice_transfer() {
+    use std::io::{Read, Write};
+    let data = super::test_data(1000000);
+    let (input, mut feed) = pipe();
+    let (mut drain, output) = pipe();
+    assert!(is_pipe(input.as_raw_fd()) && is_pipe(output.as_raw_fd()));
+    assert!(!is_pipe(::std::fs::File::open("/dev/null").unwrap().as_raw_fd()));
+
+    let (transferred, received) = thread::scope(|scope| {
+        scope.spawn(|| { feed.write_all(&data).unwrap(); drop(feed); });
+        let receiver = scope.spawn(move || { let mut received = Vec::new(); drain.read_to_end(&mut received).unwrap(); received });
+        let transferred = transfer(BufferPipe::new(256 * 1024).unwrap(), input.as_raw_fd(), output.as_raw_fd(), 64 * 1024, &|| false, &|| false).unwrap();
+        drop(output);
+        (transferred, receiver.join().unwrap())
+    });
+    assert!(transferred == data.len() as u64);
+    assert!(received == data);
+}
+
+#[test]
+fn test_splice_transfer_drains_when_stopped() {
+    use std::io::{Read, Write};
+    let data = super::test_data(200000);
+    let (mut input, mut feed) = pipe();
+    let (mut drain, output) = pipe();
+    let stop = AtomicBool::new(false);
+
+    // The input is held open, and nothing is read from the output until the transfer has been
+    // asked to stop, so most of what was read is still in the buffer pipe by then
+    let (transferred, received) = thread::scope(|scope| {
+        feed.write_all(&data[0..1000]).unwrap();
+        let feeder = scope.spawn(|| feed.write_all(&data[1000..]).unwrap());
+        let receiver = scope.spawn(|| {
+            thread::sleep(Duration::from_millis(200));
+            stop.store(true, Ordering::SeqCst);
+            let mut received = Vec::new();
+            drain.read_to_end(&mut received).unwrap();
+            received
+        });
+        let transferred = transfer(BufferPipe::new(256 * 1024).unwrap(), input.as_raw_fd(), output.as_raw_fd(), 64 * 1024,
+                                   &|| stop.load(Ordering::SeqCst), &|| false).unwrap();
+        drop(output);
+        feeder.join().unwrap();
+        (transferred, receiver.join().unwrap())
+    });
+    assert!(transferred == data.len() as u64);
+    assert!(received == data);
+
+    // Input arriving after the transfer has stopped is left unread
+    feed.write_all(b"late").unwrap();
+    drop(feed);
+    let mut late = Vec::new();
+    input.read_to_end(&mut late).unwrap();
+    assert!(late == b"late");
+}
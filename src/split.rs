@@ -0,0 +1,120 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Writing the output to a series of files of a fixed size, for `--split`, as for
+//! archiving a stream in chunks.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use pipe::Output;
+
+/// Writes to the files `{prefix}000`, `{prefix}001` and so on, each holding `size` bytes
+/// except the last, which holds whatever remains.  A write that would take a file past
+/// `size` is split between it and the next, which is only created once there is
+/// something to write to it, so a stream that is an exact multiple of `size` leaves no
+/// empty file at the end.  The first file is created up front, so an empty stream
+/// leaves a single empty file.
+pub struct SplitFiles {
+    prefix : String,
+    size   : u64,
+    index  : usize,
+    written: u64,
+    file   : File
+}
+
+impl SplitFiles {
+    /// Creates the first file of the series, truncating it if it exists.
+    pub fn create(prefix: &str, size: u64) -> io::Result<SplitFiles> {
+        let file = File::create(path(prefix, 0))?;
+        Ok(SplitFiles { prefix: prefix.to_string(), size, index: 0, written: 0, file })
+    }
+
+    /// Returns the path of the file currently being written.
+    pub fn current(&self) -> PathBuf { path(&self.prefix, self.index) }
+}
+
+/// Returns the path of the file numbered `index` in the series.
+pub fn path(prefix: &str, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}{:03}", prefix, index))
+}
+
+impl Write for SplitFiles {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut start = 0;
+        while start < buf.len() {
+            if self.written == self.size {
+                self.file.flush()?;
+                self.index  += 1;
+                self.file    = File::create(self.current())?;
+                self.written = 0;
+            }
+            let n = cmp::min((self.size - self.written) as usize, buf.len() - start);
+            self.file.write_all(&buf[start..start + n])?;
+            self.written += n as u64;
+            start += n;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.file.flush() }
+}
+
+impl Output for SplitFiles {}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_split_files() {
+    let dir = ::std::env::temp_dir().join(format!("pipebuffer-test-{}-split", ::std::process::id()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let prefix = format!("{}/out_", dir.display());
+    let data = super::test_data(2500);
+
+    // Chunks of 300 don't line up with files of 1000, so some writes straddle two files
+    {
+        let mut files = SplitFiles::create(&prefix, 1000).unwrap();
+        for chunk in data.chunks(300) { files.write_all(chunk).unwrap(); }
+        files.flush().unwrap();
+    }
+    let read = |i: usize| ::std::fs::read(path(&prefix, i)).unwrap();
+    assert!(read(0) == data[0..1000]);
+    assert!(read(1) == data[1000..2000]);
+    assert!(read(2) == data[2000..2500]);
+    assert!(!path(&prefix, 3).exists());
+
+    // An exact multiple of the size leaves no empty file behind
+    ::std::fs::remove_dir_all(&dir).unwrap();
+    ::std::fs::create_dir_all(&dir).unwrap();
+    {
+        let mut files = SplitFiles::create(&prefix, 1000).unwrap();
+        files.write_all(&data[0..2000]).unwrap();
+    }
+    assert!(read(1) == data[1000..2000]);
+    assert!(!path(&prefix, 2).exists());
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,358 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! Copying the output to further destinations, given with `--tee`, each of which may
+//! either abort the run or simply be dropped if writing to it fails.  With `--tee-buffer`
+//! each destination is written from a buffer of its own by a thread of its own, so that
+//! a slow one doesn't hold back the output.
+
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use blocking::BlockingRingBuffer;
+use pipe::{IoCounters, Output};
+
+/// What to do when writing to a tee destination fails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// Fail the write, and so the run, just as if the primary output had failed.
+    Abort,
+    /// Warn, stop writing to that destination, and carry on with the rest.
+    Ignore
+}
+
+impl Policy {
+    /// Parses the value given to `--tee-policy`.
+    pub fn parse(s: &str) -> Option<Policy> {
+        match s {
+            "abort"  => Some(Policy::Abort),
+            "ignore" => Some(Policy::Ignore),
+            _        => None
+        }
+    }
+}
+
+/// What to do when a tee destination's own buffer has no room for what is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Wait for the destination to catch up, holding back the output meanwhile.
+    Wait,
+    /// Treat it as a failure to write to the destination, handled as per the `Policy`.
+    Fail
+}
+
+impl Overflow {
+    /// Parses the value given to `--tee-overflow`.
+    pub fn parse(s: &str) -> Option<Overflow> {
+        match s {
+            "wait" => Some(Overflow::Wait),
+            "fail" => Some(Overflow::Fail),
+            _      => None
+        }
+    }
+}
+
+/// A destination that output is copied to, named by its path for reporting failures.
+pub struct Tee {
+    pub path: PathBuf,
+    pub sink: Box<dyn Output>
+}
+
+/// Writes to a primary output and copies whatever it accepts to each of a number of tee
+/// destinations.  A failure on the primary output is always returned; a failure on a
+/// tee is handled according to the `Policy`, destinations dropped under `Ignore` being
+/// counted in `IoCounters::dropped_tees`.
+pub struct MultiWriter {
+    primary : Box<dyn Output>,
+    tees    : Vec<Tee>,
+    policy  : Policy,
+    quiet   : bool,
+    counters: Arc<IoCounters>
+}
+
+impl MultiWriter {
+    /// Creates a writer over `primary` and `tees`; with `quiet` dropped tees are not
+    /// warned about on stderr.
+    pub fn new(primary: Box<dyn Output>, tees: Vec<Tee>, policy: Policy, quiet: bool, counters: Arc<IoCounters>) -> MultiWriter {
+        MultiWriter { primary, tees, policy, quiet, counters }
+    }
+
+    /// Applies `op` to every tee, aborting or dropping those it fails on as per the policy.
+    fn each_tee<F>(&mut self, what: &str, mut op: F) -> io::Result<()> where F: FnMut(&mut dyn Output) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.tees.len() {
+            match op(&mut *self.tees[i].sink) {
+                Ok(()) => i += 1,
+                Err(e) => {
+                    let path = self.tees[i].path.display().to_string();
+                    if self.policy == Policy::Abort {
+                        return Err(io::Error::new(e.kind(), format!("could not {} tee {}: {}", what, path, e)));
+                    }
+                    if !self.quiet {
+                        writeln!(&mut io::stderr(), "Warning: could not {} tee {}, so no longer copying to it: {}", what, path, e).unwrap();
+                    }
+                    self.counters.dropped_tees.fetch_add(1, Ordering::Relaxed);
+                    self.tees.remove(i);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for MultiWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.primary.write(buf)?;
+        self.each_tee("write to", |sink| sink.write_all(&buf[0..n]))?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.primary.flush()?;
+        self.each_tee("flush", |sink| sink.flush())
+    }
+}
+
+impl Output for MultiWriter {
+    fn finish(&mut self) -> io::Result<()> {
+        self.primary.finish()?;
+        self.each_tee("finish", |sink| sink.finish())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.primary.sync()?;
+        self.each_tee("sync", |sink| sink.sync())
+    }
+}
+
+/// A tee destination written from a buffer of its own by a thread of its own, so that
+/// writing to it only has to wait on the destination when the buffer is full, or with
+/// the `Fail` overflow not at all.  A failure writing to the destination is returned by
+/// the next write, or by `finish()`, which waits for everything buffered to be written.
+pub struct BufferedTee {
+    buffer  : Arc<BlockingRingBuffer<u8>>,
+    overflow: Overflow,
+    writer  : Option<thread::JoinHandle<io::Result<Box<dyn Output>>>>,
+    finished: Option<Box<dyn Output>>
+}
+
+impl BufferedTee {
+    /// Starts a thread writing to `sink`, in writes of up to `block_size` bytes, from a
+    /// buffer of `size` bytes.
+    pub fn new(mut sink: Box<dyn Output>, size: usize, block_size: usize, overflow: Overflow) -> io::Result<BufferedTee> {
+        let buffer = Arc::new(BlockingRingBuffer::new(size));
+        let writer = {
+            let buffer = buffer.clone();
+            thread::Builder::new().name("tee".to_string()).spawn(move || {
+                match drain(&buffer, &mut *sink, block_size) {
+                    Ok(())  => Ok(sink),
+                    Err(e)  => { buffer.abort(); Err(e) }
+                }
+            })?
+        };
+        Ok(BufferedTee { buffer, overflow, writer: Some(writer), finished: None })
+    }
+
+    /// Waits for the writing thread to stop, returning the error that stopped it if it
+    /// failed, or an error if it has already been waited for.
+    fn join(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(writer) => { self.finished = Some(writer.join().unwrap()?); Ok(()) },
+            None         => Err(io::Error::new(io::ErrorKind::BrokenPipe, "already finished"))
+        }
+    }
+}
+
+impl Write for BufferedTee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.overflow == Overflow::Fail && !self.buffer.is_aborted() {
+            // Only this thread puts into the buffer, so the room can only grow meanwhile
+            let snapshot = self.buffer.snapshot();
+            let room = snapshot.capacity - snapshot.len;
+            if buf.len() > room {
+                self.buffer.abort();
+                return Err(io::Error::other("its buffer is full"));
+            }
+        }
+        if self.buffer.put_blocking(buf) { return Ok(buf.len()); }
+        self.join()?;
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "already finished"))
+    }
+
+    /// Does nothing: what is buffered is flushed once it has all been written.
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Output for BufferedTee {
+    fn finish(&mut self) -> io::Result<()> {
+        self.buffer.close();
+        self.join()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.writer.is_some() { self.finish()?; }
+        match self.finished {
+            Some(ref mut sink) => sink.sync(),
+            None               => Ok(())
+        }
+    }
+}
+
+/// Writes from `buffer` to `sink` until it is closed and drained, or aborted, then
+/// flushes and finishes `sink`.
+fn drain(buffer: &BlockingRingBuffer<u8>, sink: &mut dyn Output, block_size: usize) -> io::Result<()> {
+    let mut bytes = vec![0u8; block_size];
+    loop {
+        let n = buffer.get_blocking(&mut bytes);
+        if n == 0 { break; }
+        sink.write_all(&bytes[0..n])?;
+    }
+    sink.flush()?;
+    sink.finish()
+}
+
+impl Drop for BufferedTee {
+    /// Stops the writing thread if it is still running, e.g. when the tee was dropped
+    /// after a failure elsewhere, rather than leaving it blocked on the buffer.
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            self.buffer.abort();
+            let _ = writer.join();
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+use super::SharedSink as Shared;
+
+#[cfg(test)]
+fn multi_writer(policy: Policy) -> (MultiWriter, Shared, Shared, Arc<IoCounters>) {
+    let (primary, good) = (Shared::default(), Shared::default());
+    let bad = Shared { fail: true, ..Shared::default() };
+    let tees = vec![Tee { path: PathBuf::from("bad"),  sink: Box::new(bad) },
+                    Tee { path: PathBuf::from("good"), sink: Box::new(good.clone()) }];
+    let counters = Arc::new(IoCounters::default());
+    (MultiWriter::new(Box::new(primary.clone()), tees, policy, true, counters.clone()), primary, good, counters)
+}
+
+#[test]
+fn test_ignore_policy_drops_failed_tee() {
+    let (mut writer, primary, good, counters) = multi_writer(Policy::Ignore);
+    writer.write_all(b"hello ").unwrap();
+    writer.write_all(b"world").unwrap();
+    writer.flush().unwrap();
+    assert!(*primary.written.lock().unwrap() == b"hello world");
+    assert!(*good.written.lock().unwrap() == b"hello world");
+    assert!(counters.dropped_tees.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn test_abort_policy_fails_write() {
+    let (mut writer, _, _, counters) = multi_writer(Policy::Abort);
+    let err = writer.write_all(b"hello").unwrap_err();
+    assert!(err.to_string() == "could not write to tee bad: disk full");
+    assert!(counters.dropped_tees.load(Ordering::Relaxed) == 0);
+}
+
+/// A destination that takes `delay` over each write.
+#[cfg(test)]
+struct SlowSink { sink: Shared, delay: std::time::Duration }
+
+#[cfg(test)]
+impl Write for SlowSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { thread::sleep(self.delay); self.sink.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+impl Output for SlowSink {}
+
+#[test]
+fn test_buffered_tee_does_not_hold_back_output() {
+    use std::time::{Duration, Instant};
+    let data = super::test_data(50000);
+    let (primary, slow) = (Shared::default(), Shared::default());
+    let sink = SlowSink { sink: slow.clone(), delay: Duration::from_millis(10) };
+    let tee = BufferedTee::new(Box::new(sink), 100000, 1000, Overflow::Wait).unwrap();
+    let counters = Arc::new(IoCounters::default());
+    let mut writer = MultiWriter::new(Box::new(primary.clone()), vec![Tee { path: PathBuf::from("slow"), sink: Box::new(tee) }],
+                                      Policy::Abort, true, counters);
+
+    // Fifty writes the tee takes half a second over are all on the output long before then
+    let started = Instant::now();
+    for chunk in data.chunks(1000) { writer.write_all(chunk).unwrap(); }
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert!(*primary.written.lock().unwrap() == data);
+
+    writer.finish().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(50 * 10));
+    assert!(*slow.written.lock().unwrap() == data);
+}
+
+#[test]
+fn test_buffered_tee_overflow() {
+    let data = super::test_data(50000);
+    let primary = Shared::default();
+    let sink = SlowSink { sink: Shared::default(), delay: std::time::Duration::from_millis(10) };
+    let tee = BufferedTee::new(Box::new(sink), 5000, 1000, Overflow::Fail).unwrap();
+    let counters = Arc::new(IoCounters::default());
+    let mut writer = MultiWriter::new(Box::new(primary.clone()), vec![Tee { path: PathBuf::from("slow"), sink: Box::new(tee) }],
+                                      Policy::Ignore, true, counters.clone());
+    for chunk in data.chunks(1000) { writer.write_all(chunk).unwrap(); }
+    writer.finish().unwrap();
+    assert!(*primary.written.lock().unwrap() == data);
+    assert!(counters.dropped_tees.load(Ordering::Relaxed) == 1);
+
+    // Under the abort policy the overflow fails the run
+    let sink = SlowSink { sink: Shared::default(), delay: std::time::Duration::from_millis(10) };
+    let tee = BufferedTee::new(Box::new(sink), 5000, 1000, Overflow::Fail).unwrap();
+    let mut writer = MultiWriter::new(Box::new(Shared::default()), vec![Tee { path: PathBuf::from("slow"), sink: Box::new(tee) }],
+                                      Policy::Abort, true, Arc::new(IoCounters::default()));
+    let err = data.chunks(1000).map(|chunk| writer.write_all(chunk)).find(|r| r.is_err()).unwrap().unwrap_err();
+    assert!(err.to_string() == "could not write to tee slow: its buffer is full");
+}
+
+#[test]
+fn test_buffered_tee_reports_failure() {
+    let sink = Shared { fail: true, ..Shared::default() };
+    let mut tee = BufferedTee::new(Box::new(sink), 5000, 1000, Overflow::Wait).unwrap();
+    // The failure surfaces on a later write, or at the latest at the finish
+    let result = (0..100).map(|_| tee.write_all(b"hello")).find(|r| r.is_err()).unwrap_or_else(|| tee.finish());
+    assert!(result.unwrap_err().to_string() == "disk full");
+}
+
+#[test]
+fn test_primary_failure_always_aborts() {
+    let counters = Arc::new(IoCounters::default());
+    let primary = Shared { fail: true, ..Shared::default() };
+    let mut writer = MultiWriter::new(Box::new(primary), Vec::new(), Policy::Ignore, true, counters);
+    assert!(writer.write_all(b"hello").is_err());
+}
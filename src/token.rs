@@ -0,0 +1,129 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+
+//! Finding a marker token in a stream that arrives in arbitrary chunks, for
+//! `--start-after` and `--stop-before`, where a token may be split across two or more
+//! reads.
+
+/// Finds the first occurrence of a token in a stream fed to it a chunk at a time,
+/// remembering across chunks how much of the token the end of the previous chunk
+/// matched.  Uses Knuth-Morris-Pratt, so each byte is examined only once and a partial
+/// match that fails still finds a match overlapping it, e.g. `aab` in `aaab`.
+pub struct Matcher {
+    token  : Vec<u8>,
+    // For each prefix of the token, the length of the longest proper prefix that is also a suffix of it
+    failure: Vec<usize>,
+    matched: usize
+}
+
+impl Matcher {
+    /// Creates a matcher for `token`, which must not be empty.
+    pub fn new(token: &[u8]) -> Matcher {
+        assert!(!token.is_empty(), "Cannot match an empty token.");
+        let mut failure = vec![0; token.len()];
+        let mut k = 0;
+        for i in 1..token.len() {
+            while k > 0 && token[i] != token[k] { k = failure[k - 1]; }
+            if token[i] == token[k] { k += 1; }
+            failure[i] = k;
+        }
+        Matcher { token: token.to_vec(), failure, matched: 0 }
+    }
+
+    /// Returns the token being matched.
+    pub fn token(&self) -> &[u8] { &self.token }
+
+    /// Returns how many bytes at the end of the stream so far match the start of the
+    /// token, i.e. which may turn out to be part of it.
+    pub fn matched(&self) -> usize { self.matched }
+
+    /// Scans the next chunk of the stream for the token.
+    ///
+    /// # Return
+    /// The offset in `bytes` just past the end of the token if it ends within this
+    /// chunk, or `None` if it doesn't (yet).  After a match the matcher starts afresh.
+    pub fn find(&mut self, bytes: &[u8]) -> Option<usize> {
+        for (i, b) in bytes.iter().enumerate() {
+            while self.matched > 0 && *b != self.token[self.matched] { self.matched = self.failure[self.matched - 1]; }
+            if *b == self.token[self.matched] { self.matched += 1; }
+            if self.matched == self.token.len() {
+                self.matched = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+/// Parses a token given on the command line: hex digits if prefixed with `0x`, e.g.
+/// `0x0d0a`, and otherwise the bytes of the string itself.  Returns `None` if the token
+/// would be empty or the hex is invalid.
+pub fn parse_token(s: &str) -> Option<Vec<u8>> {
+    let bytes = match s.strip_prefix("0x") {
+        Some(hex) if hex.len() % 2 == 0 => {
+            (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect::<Option<Vec<u8>>>()?
+        },
+        Some(_) => return None,
+        None    => s.as_bytes().to_vec()
+    };
+    if bytes.is_empty() { None } else { Some(bytes) }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_find_within_chunk() {
+    let mut matcher = Matcher::new(b"START");
+    assert!(matcher.find(b"..START..") == Some(7));
+    assert!(matcher.find(b"START") == Some(5));
+    assert!(matcher.find(b"STAR").is_none());
+}
+
+#[test]
+fn test_find_across_chunks() {
+    let mut matcher = Matcher::new(b"START");
+    assert!(matcher.find(b"...ST").is_none() && matcher.matched() == 2);
+    assert!(matcher.find(b"A").is_none() && matcher.matched() == 3);
+    assert!(matcher.find(b"RT...") == Some(2));
+
+    // A partial match abandoned part way still finds an overlapping match
+    let mut matcher = Matcher::new(b"aab");
+    assert!(matcher.find(b"xa").is_none());
+    assert!(matcher.find(b"aab") == Some(3));
+    let mut matcher = Matcher::new(b"abab");
+    assert!(matcher.find(b"aba").is_none());
+    assert!(matcher.find(b"bab") == Some(1));
+}
+
+#[test]
+fn test_parse_token() {
+    assert!(parse_token("START") == Some(b"START".to_vec()));
+    assert!(parse_token("0x0d0A") == Some(vec![0x0d, 0x0a]));
+    assert!(parse_token("0x0d0").is_none());
+    assert!(parse_token("0xzz").is_none());
+    assert!(parse_token("0x").is_none());
+    assert!(parse_token("").is_none());
+}
@@ -0,0 +1,147 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! A watchdog, for `--watchdog`, that detects the reader and writer having hung: no data
+//! moving through the buffer for a while although neither thread is waiting on its end
+//! of the pipeline, which can only mean both are stuck in the buffer itself.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// What the reader or writer thread is doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Reading the input, writing the output or pacing writes: waiting on the world
+    /// outside the buffer, however long that takes.
+    Io,
+    /// Putting into, or getting from, the buffer.
+    Buffer,
+    /// Finished.
+    Done
+}
+
+impl State {
+    fn from_usize(n: usize) -> State {
+        match n {
+            0 => State::Io,
+            1 => State::Buffer,
+            _ => State::Done
+        }
+    }
+}
+
+/// The states of the reader and writer threads, as they set them, for the watchdog to
+/// check.  Both start out doing I/O.
+#[derive(Default)]
+pub struct Activity {
+    reader: AtomicUsize,
+    writer: AtomicUsize
+}
+
+impl Activity {
+    /// Records that the reader is now in `state`.
+    pub fn reader(&self, state: State) { self.reader.store(state as usize, Ordering::Relaxed); }
+
+    /// Records that the writer is now in `state`.
+    pub fn writer(&self, state: State) { self.writer.store(state as usize, Ordering::Relaxed); }
+
+    /// Returns the states of the reader and writer.
+    pub fn states(&self) -> (State, State) {
+        (State::from_usize(self.reader.load(Ordering::Relaxed)), State::from_usize(self.writer.load(Ordering::Relaxed)))
+    }
+}
+
+/// Tracks how long it has been since data last moved through the buffer.
+pub struct Watchdog {
+    interval: Duration,
+    progress: u64,
+    since   : Instant
+}
+
+impl Watchdog {
+    /// Constructs a watchdog that considers the threads hung after `interval` without
+    /// progress, starting from `now`.
+    pub fn new(interval: Duration, now: Instant) -> Watchdog {
+        Watchdog { interval, progress: 0, since: now }
+    }
+
+    /// Checks on the threads at `now`, given the total of items put into and got from
+    /// the buffer, which rises whenever data moves, and the states of the threads.
+    ///
+    /// # Return
+    /// True if there has been no progress for the interval while neither thread was
+    /// doing I/O, and at least one was not yet done, i.e. the threads have hung.
+    pub fn check(&mut self, progress: u64, now: Instant, reader: State, writer: State) -> bool {
+        if progress != self.progress {
+            self.progress = progress;
+            self.since    = now;
+            return false;
+        }
+        let waiting_on_io = reader == State::Io || writer == State::Io;
+        let finished      = reader == State::Done && writer == State::Done;
+        now - self.since >= self.interval && !waiting_on_io && !finished
+    }
+}
+
+/// Describes a thread's state for the diagnostics.
+pub fn describe(state: State, io: &str) -> String {
+    match state {
+        State::Io     => io.to_string(),
+        State::Buffer => "waiting on the buffer".to_string(),
+        State::Done   => "finished".to_string()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_activity() {
+    let activity = Activity::default();
+    assert!(activity.states() == (State::Io, State::Io));
+    activity.reader(State::Done);
+    activity.writer(State::Buffer);
+    assert!(activity.states() == (State::Done, State::Buffer));
+}
+
+#[test]
+fn test_watchdog() {
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    let mut watchdog = Watchdog::new(Duration::from_millis(100), start);
+
+    // Progress keeps resetting the clock
+    assert!(!watchdog.check(10, at(90), State::Buffer, State::Buffer));
+    assert!(!watchdog.check(20, at(180), State::Buffer, State::Buffer));
+    assert!(!watchdog.check(20, at(270), State::Buffer, State::Buffer));
+
+    // A stall while either thread waits on I/O is legitimate, as is one once both are done
+    assert!(!watchdog.check(20, at(300), State::Io, State::Buffer));
+    assert!(!watchdog.check(20, at(300), State::Buffer, State::Io));
+    assert!(!watchdog.check(20, at(300), State::Done, State::Done));
+
+    // But not while both are in the buffer, or one is done and the other in the buffer
+    assert!(watchdog.check(20, at(300), State::Buffer, State::Buffer));
+    assert!(watchdog.check(20, at(300), State::Done, State::Buffer));
+}
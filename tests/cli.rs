@@ -0,0 +1,151 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Tests that run the `pipebuffer` binary end to end.
+
+extern crate libc;
+
+use std::io::{Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Runs the binary with `args`, feeding it `input` on stdin, and waits for it to exit.
+fn pipebuffer(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pipebuffer"))
+        .args(args)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_quiet_writes_nothing_to_stderr() {
+    let output = pipebuffer(&["--quiet", "--stats", "--progress"], b"some data");
+    assert!(output.status.success());
+    assert!(output.stdout == b"some data");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_not_quiet_writes_to_stderr() {
+    let output = pipebuffer(&["--stats"], b"some data");
+    assert!(output.status.success());
+    assert!(!output.stderr.is_empty());
+}
+
+#[test]
+fn test_verbose_levels() {
+    let traces = |args: &[&str]| String::from_utf8(pipebuffer(args, b"some data").stderr).unwrap();
+    assert!(!traces(&[]).contains("Input is closed"));
+    assert!(traces(&["-v"]).contains("Input is closed"));
+    assert!(!traces(&["-v"]).contains("Wrote 9 bytes"));
+    assert!(traces(&["-vv"]).contains("Wrote 9 bytes"));
+    assert!(traces(&["-vv", "--quiet"]).is_empty());
+}
+
+#[test]
+fn test_timeout_drains_what_was_read() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pipebuffer"))
+        .args(["--quiet", "--timeout", "300ms"])
+        .stdin(Stdio::piped()).stdout(Stdio::piped())
+        .spawn().unwrap();
+
+    // The input is held open, so only the timeout ends the transfer
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"early").unwrap();
+    let status = child.wait().unwrap();
+    let mut received = Vec::new();
+    child.stdout.take().unwrap().read_to_end(&mut received).unwrap();
+    drop(stdin);
+
+    assert!(status.code() == Some(124));
+    assert!(received == b"early");
+}
+
+/// Runs the binary with `args` writing to a pipe whose read end is already closed, and
+/// returns its exit status.
+fn exit_status_on_closed_output(args: &[&str]) -> Option<i32> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pipebuffer"))
+        .args(args)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn().unwrap();
+    drop(child.stdout.take());
+    child.stdin.take().unwrap().write_all(b"nobody is listening").unwrap();
+    child.wait().unwrap().code()
+}
+
+#[test]
+fn test_sigpipe_status() {
+    assert!(exit_status_on_closed_output(&[]) == Some(0));
+    assert!(exit_status_on_closed_output(&["--sigpipe-status", "exit"]) == Some(0));
+    assert!(exit_status_on_closed_output(&["--sigpipe-status", "signal"]) == Some(141));
+}
+
+#[test]
+fn test_sigterm_drains_what_was_read() {
+    // More than the output pipe holds, so some is still in the buffer when the signal arrives,
+    // whether that is the ring buffer or, splicing, a pipe
+    let data: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    for args in [&["--quiet"][..], &["--quiet", "--splice", "--size", "1m"]].iter() {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_pipebuffer"))
+            .args(*args)
+            .stdin(Stdio::piped()).stdout(Stdio::piped())
+            .spawn().unwrap();
+
+        // The input is held open, so only the signal ends the transfer, and the output is only
+        // read once the signal has been sent
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&data).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM); }
+        let mut received = Vec::new();
+        child.stdout.take().unwrap().read_to_end(&mut received).unwrap();
+        let status = child.wait().unwrap();
+        drop(stdin);
+
+        assert!(status.code() == Some(0));
+        assert!(received == data);
+    }
+}
+
+#[test]
+fn test_sigint_drains_but_reports_the_interrupt() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pipebuffer"))
+        .arg("--quiet")
+        .stdin(Stdio::piped()).stdout(Stdio::piped())
+        .spawn().unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"buffered").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT); }
+    let mut received = Vec::new();
+    child.stdout.take().unwrap().read_to_end(&mut received).unwrap();
+    let status = child.wait().unwrap();
+    drop(stdin);
+
+    assert!(status.code() == Some(128 + libc::SIGINT));
+    assert!(received == b"buffered");
+}